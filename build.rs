@@ -0,0 +1,7 @@
+// Pass on the cfgs portaudio-sys detected for additions of newer PortAudio releases
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(portaudio_version_info)");
+    if std::env::var_os("DEP_PORTAUDIO_PORTAUDIO_VERSION_INFO").is_some() {
+        println!("cargo:rustc-cfg=portaudio_version_info");
+    }
+}
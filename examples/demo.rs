@@ -44,16 +44,14 @@ fn demo() -> portaudio::PaResult
         std::thread::sleep(std::time::Duration::from_secs(SECONDS as u64));
     });
 
-    match stream.write(&*buffer)
+    if let Err(e) = stream.write(&buffer)
     {
-        Err(e) => { println!("write 1: Err({:?})", e); },
-        Ok(()) => {},
+        println!("write 1: Err({:?})", e);
     }
 
-    match stream.write(&*input)
+    if let Err(e) = stream.write(&input)
     {
-        Err(e) => { println!("write 2: Err({:?})", e); },
-        Ok(()) => {},
+        println!("write 2: Err({:?})", e);
     }
 
     let _ = waiter.join();
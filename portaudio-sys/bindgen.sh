@@ -0,0 +1,20 @@
+#!/bin/sh
+# Generate the declarations of lib.rs from the PortAudio headers
+#
+# Usage: ./bindgen.sh [include dir] > generated.rs
+#
+# The output is a reference to update lib.rs with, not a replacement: in lib.rs the functions
+# are left out for the mock and dynamic features, the host API extensions live in their own
+# modules, and the additions of newer releases are behind the cfgs that build.rs detects.
+set -e
+
+INCLUDE_DIR=${1:-/usr/include}
+cd "$(dirname "$0")"
+
+bindgen wrapper.h \
+    --allowlist-function 'Pa(Alsa|Jack)?_.*' \
+    --allowlist-type 'Pa.*' \
+    --allowlist-var 'pa.*' \
+    --ctypes-prefix '::libc' \
+    --no-doc-comments \
+    -- -I"$INCLUDE_DIR"
@@ -1,14 +1,169 @@
 extern crate pkg_config;
+#[cfg(all(not(windows), feature = "bundled"))]
+extern crate cc;
+
+use std::path::PathBuf;
+
+/// Functions added in newer PortAudio releases: the header declaring them, and the cfg that is
+/// set when they are found. The cfgs are passed on to dependents as DEP_PORTAUDIO_<CFG>.
+const ADDITIONS: &[(&str, &str, &str)] = &[
+    ("portaudio.h", "Pa_GetVersionInfo", "portaudio_version_info"),
+    ("pa_linux_alsa.h", "PaAlsa_SetLibraryPathName", "portaudio_alsa_library_path"),
+];
+
+fn declare_cfgs() {
+    for &(_, _, cfg) in ADDITIONS {
+        println!("cargo:rustc-check-cfg=cfg({})", cfg);
+    }
+}
+
+fn enable(cfg: &str) {
+    println!("cargo:rustc-cfg={}", cfg);
+    println!("cargo:{}=1", cfg);
+}
+
+/// Enable the cfgs of the additions declared by the headers in the given directories
+fn detect(include_dirs: &[PathBuf]) {
+    for &(header, symbol, cfg) in ADDITIONS {
+        let text = include_dirs.iter().filter_map(|dir| std::fs::read_to_string(dir.join(header)).ok()).next();
+        if text.is_some_and(|text| text.contains(symbol)) {
+            enable(cfg);
+        }
+    }
+}
+
+/// The mock feature replaces the library, and the dynamic feature loads it at runtime. Both
+/// provide Pa_GetVersionInfo, and neither the host API extensions.
+fn replaced() -> bool {
+    if std::env::var_os("CARGO_FEATURE_MOCK").is_some() || std::env::var_os("CARGO_FEATURE_DYNAMIC").is_some() {
+        enable("portaudio_version_info");
+        return true;
+    }
+    false
+}
 
 #[cfg(not(windows))]
 fn main() {
+    declare_cfgs();
+    if replaced() {
+        return;
+    }
+
+    #[cfg(feature = "bundled")]
+    {
+        detect(&[bundled::build()]);
+        return;
+    }
+
+    #[allow(unreachable_code)]
     match pkg_config::find_library("portaudio-2.0")
     {
-        Ok(..) => {},
+        Ok(library) => {
+            let mut include_dirs = library.include_paths;
+            include_dirs.push(PathBuf::from("/usr/include"));
+            include_dirs.push(PathBuf::from("/usr/local/include"));
+            detect(&include_dirs);
+        },
         Err(e) => panic!("{}", e),
     }
 }
 
+#[cfg(all(not(windows), feature = "bundled"))]
+mod bundled {
+    use std;
+    use std::path::PathBuf;
+
+    use cc;
+
+    const COMMON_SOURCES: &[&str] = &[
+        "src/common/pa_allocation.c",
+        "src/common/pa_converters.c",
+        "src/common/pa_cpuload.c",
+        "src/common/pa_debugprint.c",
+        "src/common/pa_dither.c",
+        "src/common/pa_front.c",
+        "src/common/pa_process.c",
+        "src/common/pa_ringbuffer.c",
+        "src/common/pa_stream.c",
+        "src/common/pa_trace.c",
+        "src/os/unix/pa_unix_hostapis.c",
+        "src/os/unix/pa_unix_util.c",
+    ];
+
+    struct Backend {
+        feature: &'static str,
+        define: &'static str,
+        source: &'static str,
+        lib: Option<&'static str>,
+    }
+
+    const BACKENDS: &[Backend] = &[
+        Backend { feature: "ALSA", define: "PA_USE_ALSA", source: "src/hostapi/alsa/pa_linux_alsa.c", lib: Some("asound") },
+        Backend { feature: "OSS", define: "PA_USE_OSS", source: "src/hostapi/oss/pa_unix_oss.c", lib: None },
+        Backend { feature: "JACK", define: "PA_USE_JACK", source: "src/hostapi/jack/pa_jack.c", lib: Some("jack") },
+    ];
+
+    /// The PortAudio sources in PORTAUDIO_SOURCE_DIR, or else the vendored copy
+    fn source_dir() -> PathBuf {
+        println!("cargo:rerun-if-env-changed=PORTAUDIO_SOURCE_DIR");
+        let dir = match std::env::var_os("PORTAUDIO_SOURCE_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("portaudio"),
+        };
+
+        if !dir.join("src/common/pa_front.c").is_file() {
+            panic!("No PortAudio sources were found in {}. In a git checkout, run \
+                    `git submodule update --init` to fetch the vendored copy.", dir.display());
+        }
+        dir
+    }
+
+    fn enabled(feature: &str) -> bool {
+        std::env::var_os(format!("CARGO_FEATURE_{}", feature)).is_some()
+    }
+
+    /// Build the library, returning the directory of its headers
+    pub fn build() -> PathBuf {
+        let dir = source_dir();
+        println!("cargo:rerun-if-changed={}", dir.display());
+
+        // Without a backend selected, build those that need no libraries beyond ALSA
+        let selected: Vec<&Backend> = BACKENDS.iter().filter(|b| enabled(b.feature)).collect();
+        let backends = if selected.is_empty() { BACKENDS[.. 2].iter().collect() } else { selected };
+
+        let mut build = cc::Build::new();
+        build.include(dir.join("include"))
+             .include(dir.join("src/common"))
+             .include(dir.join("src/os/unix"))
+             .warnings(false);
+        match std::env::var("CARGO_CFG_TARGET_ENDIAN").as_ref().map(|e| &e[..]) {
+            Ok("big") => build.define("PA_BIG_ENDIAN", None),
+            _ => build.define("PA_LITTLE_ENDIAN", None),
+        };
+
+        for source in COMMON_SOURCES {
+            build.file(dir.join(source));
+        }
+        for backend in &backends {
+            build.file(dir.join(backend.source)).define(backend.define, Some("1"));
+            if backend.feature == "OSS" {
+                build.define("HAVE_SYS_SOUNDCARD_H", Some("1"));
+            }
+        }
+        build.compile("portaudio");
+
+        for backend in &backends {
+            if let Some(lib) = backend.lib {
+                println!("cargo:rustc-link-lib={}", lib);
+            }
+        }
+        println!("cargo:rustc-link-lib=pthread");
+        println!("cargo:rustc-link-lib=m");
+        println!("cargo:include={}", dir.join("include").display());
+        dir.join("include")
+    }
+}
+
 #[cfg(windows)]
 mod windows_build {
     use std;
@@ -67,6 +222,14 @@ mod windows_build {
 
 #[cfg(windows)]
 fn main() {
+    declare_cfgs();
+    if replaced() {
+        return;
+    }
+
     windows_build::download_sources();
     windows_build::build_sources();
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    detect(&[out_dir.join("portaudio").join("include")]);
 }
@@ -0,0 +1,144 @@
+//! Loading of the PortAudio library at runtime instead of linking it
+//!
+//! The library is opened at the first call of any Pa_* function. When it or one of its
+//! symbols is missing, Pa_Initialize fails with paUnanticipatedHostError and the other
+//! functions fail as if PortAudio were not initialized. load_error() describes why the library
+//! could not be loaded.
+
+#![allow(clippy::missing_safety_doc)]
+
+use libc::{c_char, c_double, c_int, c_long, c_ulong, c_void};
+use libloading::Library;
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::sync::OnceLock;
+
+use super::*;
+
+type StreamCallback = Option<extern "C" fn(*const c_void, *mut c_void, c_ulong, *const PaStreamCallbackTimeInfo,
+                                           PaStreamCallbackFlags, *mut c_void) -> c_int>;
+type FinishedCallback = Option<extern "C" fn(*mut c_void)>;
+
+/// Names the library is looked up by, after the path in PORTAUDIO_LIBRARY
+#[cfg(all(unix, not(target_os = "macos")))]
+const LIBRARY_NAMES: &[&str] = &["libportaudio.so.2", "libportaudio.so"];
+#[cfg(target_os = "macos")]
+const LIBRARY_NAMES: &[&str] = &["libportaudio.2.dylib", "libportaudio.dylib"];
+#[cfg(windows)]
+const LIBRARY_NAMES: &[&str] = &["portaudio.dll", "portaudio_x64.dll", "portaudio_x86.dll"];
+
+static VERSION_TEXT: &[u8] = b"PortAudio not loaded\0";
+
+static LIBRARY: OnceLock<Result<Api, CString>> = OnceLock::new();
+
+macro_rules! dynamic_api {
+    ($($name:ident($($arg:ident: $ty:ty),*) -> $ret:ty, or $fallback:expr;)*) => {
+        struct Api {
+            $($name: unsafe extern "C" fn($($ty),*) -> $ret,)*
+            version_info: Option<unsafe extern "C" fn() -> *const PaVersionInfo>,
+            _library: Library,
+        }
+
+        impl Api {
+            unsafe fn new(library: Library) -> Result<Api, String> {
+                Ok(Api {
+                    $($name: *library.get::<unsafe extern "C" fn($($ty),*) -> $ret>(concat!(stringify!($name), "\0").as_bytes())
+                        .map_err(|e| format!("PortAudio symbol {} is missing: {}", stringify!($name), e))?,)*
+                    version_info: library.get(b"Pa_GetVersionInfo\0").ok().map(|f| *f),
+                    _library: library,
+                })
+            }
+        }
+
+        $(pub unsafe extern "C" fn $name($($arg: $ty),*) -> $ret {
+            match api() {
+                Ok(api) => (api.$name)($($arg),*),
+                Err(_) => $fallback,
+            }
+        })*
+    }
+}
+
+dynamic_api! {
+    Pa_GetVersion() -> c_int, or 0;
+    Pa_GetVersionText() -> *const c_char, or VERSION_TEXT.as_ptr() as *const c_char;
+    Pa_GetErrorText(errorCode: PaError) -> *const c_char, or error_text(errorCode).as_ptr() as *const c_char;
+    Pa_Initialize() -> PaError, or paUnanticipatedHostError;
+    Pa_Terminate() -> PaError, or paNotInitialized;
+    Pa_GetHostApiCount() -> PaHostApiIndex, or paNotInitialized;
+    Pa_GetDefaultHostApi() -> PaHostApiIndex, or paNotInitialized;
+    Pa_GetHostApiInfo(hostApi: PaHostApiIndex) -> *const PaHostApiInfo, or ptr::null();
+    Pa_HostApiTypeIdToHostApiIndex(_type: PaHostApiTypeId) -> PaHostApiIndex, or paNotInitialized;
+    Pa_HostApiDeviceIndexToDeviceIndex(hostApi: PaHostApiIndex, hostApiDeviceIndex: c_int) -> PaDeviceIndex, or paNotInitialized;
+    Pa_GetLastHostErrorInfo() -> *const PaHostErrorInfo, or ptr::null();
+    Pa_GetDeviceCount() -> PaDeviceIndex, or paNotInitialized;
+    Pa_GetDefaultInputDevice() -> PaDeviceIndex, or paNoDevice;
+    Pa_GetDefaultOutputDevice() -> PaDeviceIndex, or paNoDevice;
+    Pa_GetDeviceInfo(device: PaDeviceIndex) -> *const PaDeviceInfo, or ptr::null();
+    Pa_IsFormatSupported(inputParameters: *const PaStreamParameters, outputParameters: *const PaStreamParameters,
+                         sampleRate: c_double) -> PaError, or paNotInitialized;
+    Pa_OpenStream(stream: *mut *mut PaStream, inputParameters: *const PaStreamParameters,
+                  outputParameters: *const PaStreamParameters, sampleRate: c_double, framesPerBuffer: c_ulong,
+                  streamFlags: PaStreamFlags, streamCallback: StreamCallback, userData: *mut c_void) -> PaError, or paNotInitialized;
+    Pa_OpenDefaultStream(stream: *mut *mut PaStream, numInputChannels: c_int, numOutputChannels: c_int,
+                         sampleFormat: PaSampleFormat, sampleRate: c_double, framesPerBuffer: c_ulong,
+                         streamCallback: StreamCallback, userData: *mut c_void) -> PaError, or paNotInitialized;
+    Pa_CloseStream(stream: *mut PaStream) -> PaError, or paNotInitialized;
+    Pa_SetStreamFinishedCallback(stream: *mut PaStream, streamFinishedCallback: FinishedCallback) -> PaError, or paNotInitialized;
+    Pa_StartStream(stream: *mut PaStream) -> PaError, or paNotInitialized;
+    Pa_StopStream(stream: *mut PaStream) -> PaError, or paNotInitialized;
+    Pa_AbortStream(stream: *mut PaStream) -> PaError, or paNotInitialized;
+    Pa_IsStreamStopped(stream: *mut PaStream) -> PaError, or paNotInitialized;
+    Pa_IsStreamActive(stream: *mut PaStream) -> PaError, or paNotInitialized;
+    Pa_GetStreamInfo(stream: *mut PaStream) -> *const PaStreamInfo, or ptr::null();
+    Pa_GetStreamHostApiType(stream: *mut PaStream) -> PaHostApiTypeId, or paNotInitialized as PaHostApiTypeId;
+    Pa_GetStreamTime(stream: *mut PaStream) -> PaTime, or 0.0;
+    Pa_GetStreamCpuLoad(stream: *mut PaStream) -> c_double, or 0.0;
+    Pa_ReadStream(stream: *mut PaStream, buffer: *mut c_void, frames: c_ulong) -> PaError, or paNotInitialized;
+    Pa_WriteStream(stream: *mut PaStream, buffer: *const c_void, frames: c_ulong) -> PaError, or paNotInitialized;
+    Pa_GetStreamReadAvailable(stream: *mut PaStream) -> c_long, or paNotInitialized as c_long;
+    Pa_GetStreamWriteAvailable(stream: *mut PaStream) -> c_long, or paNotInitialized as c_long;
+    Pa_GetSampleSize(format: PaSampleFormat) -> PaError, or paNotInitialized;
+    Pa_Sleep(msec: c_long) -> (), or ();
+}
+
+/// Pa_GetVersionInfo, which older libraries lack. Returns null when it is missing.
+pub unsafe extern "C" fn Pa_GetVersionInfo() -> *const PaVersionInfo {
+    match api() {
+        Ok(api) => api.version_info.map_or(ptr::null(), |f| f()),
+        Err(_) => ptr::null(),
+    }
+}
+
+/// Whether the library is loaded. Loads it, if that was not tried yet.
+pub fn is_loaded() -> bool {
+    api().is_ok()
+}
+
+/// Why the library or one of its symbols could not be loaded, if it could not. Loads it, if
+/// that was not tried yet.
+pub fn load_error() -> Option<&'static CStr> {
+    api().err()
+}
+
+fn api() -> Result<&'static Api, &'static CStr> {
+    LIBRARY.get_or_init(open).as_ref().map_err(|e| e.as_c_str())
+}
+
+fn open() -> Result<Api, CString> {
+    let path = std::env::var_os("PORTAUDIO_LIBRARY");
+    let candidates: Vec<std::ffi::OsString> = path.into_iter().chain(LIBRARY_NAMES.iter().map(|n| n.into())).collect();
+
+    let mut errors = Vec::new();
+    for candidate in &candidates {
+        match unsafe { Library::new(candidate) } {
+            Ok(library) => return unsafe { Api::new(library) }.map_err(to_c_string),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    Err(to_c_string(format!("PortAudio could not be loaded: {}", errors.join("; "))))
+}
+
+fn to_c_string(message: String) -> CString {
+    CString::new(message.replace('\0', " ")).unwrap()
+}
@@ -5,16 +5,79 @@
 
 extern crate libc;
 
-#[cfg(windows)]
+#[cfg(all(windows, not(feature = "mock"), not(feature = "dynamic")))]
 #[link(name = "portaudio", kind = "static")]
-extern {}
+extern "C" {}
 
-#[cfg(not(windows))]
+// The build from source links its static library from build.rs
+#[cfg(all(not(windows), not(feature = "mock"), not(feature = "bundled"), not(feature = "dynamic")))]
 #[link(name = "portaudio")]
-extern {}
+extern "C" {}
 
-/* automatically generated by rust-bindgen */
+#[cfg(feature = "mock")]
+pub mod mock;
 
+#[cfg(feature = "mock")]
+pub use mock::api::*;
+
+#[cfg(all(feature = "dynamic", not(feature = "mock")))]
+extern crate libloading;
+
+#[cfg(all(feature = "dynamic", not(feature = "mock")))]
+pub mod dynamic;
+
+#[cfg(all(feature = "dynamic", not(feature = "mock")))]
+pub use dynamic::*;
+
+// The texts of Pa_GetErrorText, for the emulation and for when the library could not be loaded
+#[cfg(any(feature = "mock", feature = "dynamic"))]
+fn error_text(code: PaError) -> &'static [u8] {
+    match code {
+        paNoError => b"Success\0",
+        paNotInitialized => b"PortAudio not initialized\0",
+        paUnanticipatedHostError => b"Unanticipated host error\0",
+        paInvalidChannelCount => b"Invalid number of channels\0",
+        paInvalidSampleRate => b"Invalid sample rate\0",
+        paInvalidDevice => b"Invalid device\0",
+        paInvalidFlag => b"Invalid flag\0",
+        paSampleFormatNotSupported => b"Sample format not supported\0",
+        paBadIODeviceCombination => b"Illegal combination of I/O devices\0",
+        paInsufficientMemory => b"Insufficient memory\0",
+        paBufferTooBig => b"Buffer too big\0",
+        paBufferTooSmall => b"Buffer too small\0",
+        paNullCallback => b"No callback routine specified\0",
+        paBadStreamPtr => b"Invalid stream pointer\0",
+        paTimedOut => b"Wait timed out\0",
+        paInternalError => b"Internal PortAudio error\0",
+        paDeviceUnavailable => b"Device unavailable\0",
+        paIncompatibleHostApiSpecificStreamInfo => b"Incompatible host API specific stream info\0",
+        paStreamIsStopped => b"Stream is stopped\0",
+        paStreamIsNotStopped => b"Stream is not stopped\0",
+        paInputOverflowed => b"Input overflowed\0",
+        paOutputUnderflowed => b"Output underflowed\0",
+        paHostApiNotFound => b"Host API not found\0",
+        paInvalidHostApi => b"Invalid host API\0",
+        paCanNotReadFromACallbackStream => b"Can't read from a callback stream\0",
+        paCanNotWriteToACallbackStream => b"Can't write to a callback stream\0",
+        paCanNotReadFromAnOutputOnlyStream => b"Can't read from an output only stream\0",
+        paCanNotWriteToAnInputOnlyStream => b"Can't write to an input only stream\0",
+        paIncompatibleStreamHostApi => b"Incompatible stream host API\0",
+        paBadBufferPtr => b"Bad buffer pointer\0",
+        _ => b"Invalid error code\0",
+    }
+}
+
+/* automatically generated by rust-bindgen, see bindgen.sh */
+
+#[repr(C)]
+pub struct Struct_PaVersionInfo {
+    pub versionMajor: ::libc::c_int,
+    pub versionMinor: ::libc::c_int,
+    pub versionSubMinor: ::libc::c_int,
+    pub versionControlRevision: *const ::libc::c_char,
+    pub versionText: *const ::libc::c_char,
+}
+pub type PaVersionInfo = Struct_PaVersionInfo;
 pub type PaError = ::libc::c_int;
 pub type Enum_PaErrorCode = ::libc::c_int;
 pub const paNoError: ::libc::c_int = 0;
@@ -49,6 +112,8 @@ pub const paIncompatibleStreamHostApi: ::libc::c_int = -9973;
 pub const paBadBufferPtr: ::libc::c_int = -9972;
 pub type PaErrorCode = Enum_PaErrorCode;
 pub type PaDeviceIndex = ::libc::c_int;
+pub const paNoDevice: PaDeviceIndex = -1;
+pub const paUseHostApiSpecificDeviceSpecification: PaDeviceIndex = -2;
 pub type PaHostApiIndex = ::libc::c_int;
 pub type Enum_PaHostApiTypeId = ::libc::c_uint;
 pub const paInDevelopment: ::libc::c_uint = 0;
@@ -65,6 +130,9 @@ pub const paWDMKS: ::libc::c_uint = 11;
 pub const paJACK: ::libc::c_uint = 12;
 pub const paWASAPI: ::libc::c_uint = 13;
 pub const paAudioScienceHPI: ::libc::c_uint = 14;
+pub const paAudioIO: ::libc::c_uint = 15;
+pub const paPulseAudio: ::libc::c_uint = 16;
+pub const paSndio: ::libc::c_uint = 17;
 pub type PaHostApiTypeId = Enum_PaHostApiTypeId;
 #[repr(C)]
 pub struct Struct_PaHostApiInfo {
@@ -85,6 +153,15 @@ pub struct Struct_PaHostErrorInfo {
 pub type PaHostErrorInfo = Struct_PaHostErrorInfo;
 pub type PaTime = ::libc::c_double;
 pub type PaSampleFormat = ::libc::c_ulong;
+pub const paFloat32: PaSampleFormat = 0x0000_0001;
+pub const paInt32: PaSampleFormat = 0x0000_0002;
+pub const paInt24: PaSampleFormat = 0x0000_0004;
+pub const paInt16: PaSampleFormat = 0x0000_0008;
+pub const paInt8: PaSampleFormat = 0x0000_0010;
+pub const paUInt8: PaSampleFormat = 0x0000_0020;
+pub const paCustomFormat: PaSampleFormat = 0x0001_0000;
+pub const paNonInterleaved: PaSampleFormat = 0x8000_0000;
+pub const paFormatIsSupported: PaError = 0;
 #[repr(C)]
 pub struct Struct_PaDeviceInfo {
     pub structVersion: ::libc::c_int,
@@ -110,6 +187,13 @@ pub struct Struct_PaStreamParameters {
 pub type PaStreamParameters = Struct_PaStreamParameters;
 pub type PaStream = ::libc::c_void;
 pub type PaStreamFlags = ::libc::c_ulong;
+pub const paFramesPerBufferUnspecified: ::libc::c_ulong = 0;
+pub const paNoFlag: PaStreamFlags = 0;
+pub const paClipOff: PaStreamFlags = 0x0000_0001;
+pub const paDitherOff: PaStreamFlags = 0x0000_0002;
+pub const paNeverDropInput: PaStreamFlags = 0x0000_0004;
+pub const paPrimeOutputBuffersUsingStreamCallback: PaStreamFlags = 0x0000_0008;
+pub const paPlatformSpecificFlags: PaStreamFlags = 0xFFFF_0000;
 #[repr(C)]
 pub struct Struct_PaStreamCallbackTimeInfo {
     pub inputBufferAdcTime: PaTime,
@@ -118,6 +202,11 @@ pub struct Struct_PaStreamCallbackTimeInfo {
 }
 pub type PaStreamCallbackTimeInfo = Struct_PaStreamCallbackTimeInfo;
 pub type PaStreamCallbackFlags = ::libc::c_ulong;
+pub const paInputUnderflow: PaStreamCallbackFlags = 0x0000_0001;
+pub const paInputOverflow: PaStreamCallbackFlags = 0x0000_0002;
+pub const paOutputUnderflow: PaStreamCallbackFlags = 0x0000_0004;
+pub const paOutputOverflow: PaStreamCallbackFlags = 0x0000_0008;
+pub const paPrimingOutput: PaStreamCallbackFlags = 0x0000_0010;
 pub type Enum_PaStreamCallbackResult = ::libc::c_uint;
 pub const paContinue: ::libc::c_uint = 0;
 pub const paComplete: ::libc::c_uint = 1;
@@ -133,9 +222,13 @@ pub struct Struct_PaStreamInfo {
     pub sampleRate: ::libc::c_double,
 }
 pub type PaStreamInfo = Struct_PaStreamInfo;
+#[cfg(not(any(feature = "mock", feature = "dynamic")))]
 extern "C" {
     pub fn Pa_GetVersion() -> ::libc::c_int;
     pub fn Pa_GetVersionText() -> *const ::libc::c_char;
+    // Since V19.5, see build.rs
+    #[cfg(portaudio_version_info)]
+    pub fn Pa_GetVersionInfo() -> *const PaVersionInfo;
     pub fn Pa_GetErrorText(errorCode: PaError) -> *const ::libc::c_char;
     pub fn Pa_Initialize() -> PaError;
     pub fn Pa_Terminate() -> PaError;
@@ -194,6 +287,7 @@ extern "C" {
     pub fn Pa_IsStreamStopped(stream: *mut PaStream) -> PaError;
     pub fn Pa_IsStreamActive(stream: *mut PaStream) -> PaError;
     pub fn Pa_GetStreamInfo(stream: *mut PaStream) -> *const PaStreamInfo;
+    pub fn Pa_GetStreamHostApiType(stream: *mut PaStream) -> PaHostApiTypeId;
     pub fn Pa_GetStreamTime(stream: *mut PaStream) -> PaTime;
     pub fn Pa_GetStreamCpuLoad(stream: *mut PaStream) -> ::libc::c_double;
     pub fn Pa_ReadStream(stream: *mut PaStream, buffer: *mut ::libc::c_void,
@@ -205,7 +299,117 @@ extern "C" {
     pub fn Pa_GetStreamWriteAvailable(stream: *mut PaStream) ->
      ::libc::c_long;
     pub fn Pa_GetSampleSize(format: PaSampleFormat) -> PaError;
+    pub fn Pa_Sleep(msec: ::libc::c_long);
+}
+
+/// Extensions of the ALSA host API, from pa_linux_alsa.h
+#[cfg(all(target_os = "linux", not(any(feature = "mock", feature = "dynamic"))))]
+pub mod linux_alsa {
+    use super::*;
+
+    #[repr(C)]
+    pub struct Struct_PaAlsaStreamInfo {
+        pub size: ::libc::c_ulong,
+        pub hostApiType: PaHostApiTypeId,
+        pub version: ::libc::c_ulong,
+        pub deviceString: *const ::libc::c_char,
+    }
+    pub type PaAlsaStreamInfo = Struct_PaAlsaStreamInfo;
+
+    extern "C" {
+        pub fn PaAlsa_InitializeStreamInfo(info: *mut PaAlsaStreamInfo);
+        pub fn PaAlsa_EnableRealtimeScheduling(s: *mut PaStream, enable: ::libc::c_int);
+        pub fn PaAlsa_GetStreamInputCard(s: *mut PaStream, card: *mut ::libc::c_int) -> PaError;
+        pub fn PaAlsa_GetStreamOutputCard(s: *mut PaStream, card: *mut ::libc::c_int) -> PaError;
+        pub fn PaAlsa_SetNumPeriods(numPeriods: ::libc::c_int) -> PaError;
+        pub fn PaAlsa_SetRetriesBusy(retries: ::libc::c_int) -> PaError;
+        // Since V19.7, see build.rs
+        #[cfg(portaudio_alsa_library_path)]
+        pub fn PaAlsa_SetLibraryPathName(pathName: *const ::libc::c_char);
+    }
+}
+
+/// Extensions of the JACK host API, from pa_jack.h. Only with the jack feature, as the linked
+/// library need not have been built with JACK.
+#[cfg(all(feature = "jack", not(any(feature = "mock", feature = "dynamic"))))]
+pub mod jack {
+    use super::*;
+
+    extern "C" {
+        pub fn PaJack_SetClientName(name: *const ::libc::c_char) -> PaError;
+        pub fn PaJack_GetClientName(clientName: *mut *const ::libc::c_char) -> PaError;
+    }
+}
+
+#[cfg(all(test, target_pointer_width = "64", not(windows)))]
+mod test {
+    use super::*;
+    use std::mem::{align_of, size_of};
+
+    // std::mem::offset_of! is only stable since Rust 1.77
+    macro_rules! offset_of {
+        ($type:ty, $field:ident) => {{
+            let value = ::std::mem::MaybeUninit::<$type>::uninit();
+            let base = value.as_ptr();
+            unsafe { ::std::ptr::addr_of!((*base).$field) as usize - base as usize }
+        }};
+    }
+
+    #[test]
+    fn layout_version_info() {
+        assert_eq!((size_of::<PaVersionInfo>(), align_of::<PaVersionInfo>()), (32, 8));
+        assert_eq!(offset_of!(PaVersionInfo, versionSubMinor), 8);
+        assert_eq!(offset_of!(PaVersionInfo, versionControlRevision), 16);
+        assert_eq!(offset_of!(PaVersionInfo, versionText), 24);
+    }
+
+    #[test]
+    fn layout_host_api_info() {
+        assert_eq!((size_of::<PaHostApiInfo>(), align_of::<PaHostApiInfo>()), (32, 8));
+        assert_eq!(offset_of!(PaHostApiInfo, _type), 4);
+        assert_eq!(offset_of!(PaHostApiInfo, name), 8);
+        assert_eq!(offset_of!(PaHostApiInfo, deviceCount), 16);
+        assert_eq!(offset_of!(PaHostApiInfo, defaultOutputDevice), 24);
+
+        assert_eq!(size_of::<PaHostErrorInfo>(), 24);
+        assert_eq!(offset_of!(PaHostErrorInfo, errorCode), 8);
+        assert_eq!(offset_of!(PaHostErrorInfo, errorText), 16);
+    }
+
+    #[test]
+    fn layout_device_info() {
+        assert_eq!((size_of::<PaDeviceInfo>(), align_of::<PaDeviceInfo>()), (72, 8));
+        assert_eq!(offset_of!(PaDeviceInfo, name), 8);
+        assert_eq!(offset_of!(PaDeviceInfo, hostApi), 16);
+        assert_eq!(offset_of!(PaDeviceInfo, maxOutputChannels), 24);
+        assert_eq!(offset_of!(PaDeviceInfo, defaultLowInputLatency), 32);
+        assert_eq!(offset_of!(PaDeviceInfo, defaultHighOutputLatency), 56);
+        assert_eq!(offset_of!(PaDeviceInfo, defaultSampleRate), 64);
+    }
+
+    #[test]
+    fn layout_stream_structs() {
+        assert_eq!((size_of::<PaStreamParameters>(), align_of::<PaStreamParameters>()), (32, 8));
+        assert_eq!(offset_of!(PaStreamParameters, channelCount), 4);
+        assert_eq!(offset_of!(PaStreamParameters, sampleFormat), 8);
+        assert_eq!(offset_of!(PaStreamParameters, suggestedLatency), 16);
+        assert_eq!(offset_of!(PaStreamParameters, hostApiSpecificStreamInfo), 24);
+
+        assert_eq!(size_of::<PaStreamCallbackTimeInfo>(), 24);
+        assert_eq!(offset_of!(PaStreamCallbackTimeInfo, outputBufferDacTime), 16);
+
+        assert_eq!(size_of::<PaStreamInfo>(), 32);
+        assert_eq!(offset_of!(PaStreamInfo, inputLatency), 8);
+        assert_eq!(offset_of!(PaStreamInfo, sampleRate), 24);
+    }
 
-    // Unused functions
-    //pub fn Pa_Sleep(msec: ::libc::c_long);
+    #[cfg(all(target_os = "linux", not(any(feature = "mock", feature = "dynamic"))))]
+    #[test]
+    fn layout_alsa_stream_info() {
+        use linux_alsa::PaAlsaStreamInfo;
+        assert_eq!((size_of::<PaAlsaStreamInfo>(), align_of::<PaAlsaStreamInfo>()), (32, 8));
+        assert_eq!(offset_of!(PaAlsaStreamInfo, hostApiType), 8);
+        assert_eq!(offset_of!(PaAlsaStreamInfo, version), 16);
+        assert_eq!(offset_of!(PaAlsaStreamInfo, deviceString), 24);
+    }
 }
@@ -0,0 +1,435 @@
+//! The Pa_* functions of the emulation, with the same signatures as the linked ones
+
+#![allow(clippy::missing_safety_doc)]
+
+use libc::{c_char, c_double, c_int, c_long, c_ulong, c_void};
+use std::ptr;
+
+use super::{sample_size, with_world, FinishedCallback, MockStream, Operation, State, StreamCallback, World};
+use super::super::*;
+
+/// Version 19.7.0, encoded like PortAudio does
+const VERSION: c_int = (19 << 16) | (7 << 8);
+
+static VERSION_TEXT: &[u8] = b"PortAudio V19.7.0-devel, revision mock\0";
+
+struct VersionInfo(Struct_PaVersionInfo);
+
+// The pointers refer to static strings
+unsafe impl Sync for VersionInfo {}
+
+static VERSION_INFO: VersionInfo = VersionInfo(Struct_PaVersionInfo
+{
+    versionMajor: 19,
+    versionMinor: 7,
+    versionSubMinor: 0,
+    versionControlRevision: b"mock\0".as_ptr() as *const c_char,
+    versionText: VERSION_TEXT.as_ptr() as *const c_char,
+});
+
+fn initialized<T, F: FnOnce(&mut World) -> T>(error: T, f: F) -> T
+{
+    with_world(|w| if w.initialized == 0 { error } else { f(w) })
+}
+
+fn with_stream<F: FnOnce(&mut MockStream, f64) -> PaError>(stream: *mut PaStream, f: F) -> PaError
+{
+    with_world(|w|
+    {
+        let time = w.time;
+        match w.streams.get_mut(&(stream as usize))
+        {
+            Some(s) => f(s, time),
+            None => paBadStreamPtr,
+        }
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetVersion() -> c_int
+{
+    VERSION
+}
+
+pub unsafe extern "C" fn Pa_GetVersionText() -> *const c_char
+{
+    VERSION_TEXT.as_ptr() as *const c_char
+}
+
+pub unsafe extern "C" fn Pa_GetVersionInfo() -> *const PaVersionInfo
+{
+    &VERSION_INFO.0
+}
+
+pub unsafe extern "C" fn Pa_GetErrorText(errorCode: PaError) -> *const c_char
+{
+    error_text(errorCode).as_ptr() as *const c_char
+}
+
+pub unsafe extern "C" fn Pa_Initialize() -> PaError
+{
+    with_world(|w|
+    {
+        match w.fail(Operation::Initialize)
+        {
+            paNoError => {},
+            e => return e,
+        }
+        if w.initialized == 0 { w.rescan(); }
+        w.initialized += 1;
+        paNoError
+    })
+}
+
+pub unsafe extern "C" fn Pa_Terminate() -> PaError
+{
+    initialized(paNotInitialized, |w|
+    {
+        w.initialized -= 1;
+        if w.initialized == 0 { w.clear(); }
+        paNoError
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetHostApiCount() -> PaHostApiIndex
+{
+    initialized(paNotInitialized, |w| w.system.host_apis.len() as PaHostApiIndex)
+}
+
+pub unsafe extern "C" fn Pa_GetDefaultHostApi() -> PaHostApiIndex
+{
+    initialized(paNotInitialized, |w| match w.system.default_host_api
+    {
+        i if i < w.system.host_apis.len() => i as PaHostApiIndex,
+        _ => paHostApiNotFound,
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetHostApiInfo(hostApi: PaHostApiIndex) -> *const PaHostApiInfo
+{
+    initialized(ptr::null(), |w|
+    {
+        if hostApi < 0 { return ptr::null() }
+        w.host_api_infos.get(hostApi as usize).map_or(ptr::null(), |i| i as *const _)
+    })
+}
+
+pub unsafe extern "C" fn Pa_HostApiTypeIdToHostApiIndex(_type: PaHostApiTypeId) -> PaHostApiIndex
+{
+    initialized(paNotInitialized, |w|
+    {
+        w.system.host_apis.iter().position(|a| a.type_id == _type).map_or(paHostApiNotFound, |i| i as PaHostApiIndex)
+    })
+}
+
+pub unsafe extern "C" fn Pa_HostApiDeviceIndexToDeviceIndex(hostApi: PaHostApiIndex, hostApiDeviceIndex: c_int) -> PaDeviceIndex
+{
+    initialized(paNotInitialized, |w|
+    {
+        if hostApi < 0 || hostApi as usize >= w.system.host_apis.len() { return paInvalidHostApi }
+        if hostApiDeviceIndex < 0 { return paInvalidDevice }
+
+        w.system.devices.iter().enumerate()
+            .filter(|&(_, d)| d.host_api == hostApi as usize)
+            .nth(hostApiDeviceIndex as usize)
+            .map_or(paInvalidDevice, |(i, _)| i as PaDeviceIndex)
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetLastHostErrorInfo() -> *const PaHostErrorInfo
+{
+    with_world(|w| &w.host_error as *const _)
+}
+
+pub unsafe extern "C" fn Pa_GetDeviceCount() -> PaDeviceIndex
+{
+    initialized(paNotInitialized, |w| w.system.devices.len() as PaDeviceIndex)
+}
+
+pub unsafe extern "C" fn Pa_GetDefaultInputDevice() -> PaDeviceIndex
+{
+    initialized(-1, |w| w.default_device(true).map_or(-1, |d| d as PaDeviceIndex))
+}
+
+pub unsafe extern "C" fn Pa_GetDefaultOutputDevice() -> PaDeviceIndex
+{
+    initialized(-1, |w| w.default_device(false).map_or(-1, |d| d as PaDeviceIndex))
+}
+
+pub unsafe extern "C" fn Pa_GetDeviceInfo(device: PaDeviceIndex) -> *const PaDeviceInfo
+{
+    initialized(ptr::null(), |w|
+    {
+        if device < 0 { return ptr::null() }
+        w.device_infos.get(device as usize).map_or(ptr::null(), |i| i as *const _)
+    })
+}
+
+pub unsafe extern "C" fn Pa_IsFormatSupported(inputParameters: *const PaStreamParameters,
+                                              outputParameters: *const PaStreamParameters,
+                                              sampleRate: c_double) -> PaError
+{
+    with_world(|w| match w.check_format(inputParameters.as_ref(), outputParameters.as_ref(), sampleRate)
+    {
+        Ok(_) => paNoError,
+        Err(e) => e,
+    })
+}
+
+pub unsafe extern "C" fn Pa_OpenStream(stream: *mut *mut PaStream,
+                                       inputParameters: *const PaStreamParameters,
+                                       outputParameters: *const PaStreamParameters,
+                                       sampleRate: c_double,
+                                       framesPerBuffer: c_ulong,
+                                       _streamFlags: PaStreamFlags,
+                                       streamCallback: Option<StreamCallback>,
+                                       userData: *mut c_void) -> PaError
+{
+    let result = with_world(|w| w.open(inputParameters.as_ref(),
+                                       outputParameters.as_ref(),
+                                       sampleRate,
+                                       framesPerBuffer,
+                                       streamCallback,
+                                       userData));
+    match result
+    {
+        Ok(id) => { *stream = id as *mut PaStream; paNoError },
+        Err(e) => e,
+    }
+}
+
+pub unsafe extern "C" fn Pa_OpenDefaultStream(stream: *mut *mut PaStream,
+                                              numInputChannels: c_int,
+                                              numOutputChannels: c_int,
+                                              sampleFormat: PaSampleFormat,
+                                              sampleRate: c_double,
+                                              framesPerBuffer: c_ulong,
+                                              streamCallback: Option<StreamCallback>,
+                                              userData: *mut c_void) -> PaError
+{
+    let params = |input: bool, channels: c_int| with_world(|w|
+    {
+        if channels <= 0 { return Ok(None) }
+        let device = w.default_device(input).ok_or(paInvalidDevice)?;
+        Ok(Some(PaStreamParameters
+        {
+            device: device as PaDeviceIndex,
+            channelCount: channels,
+            sampleFormat,
+            suggestedLatency: w.system.devices[device].low_latency,
+            hostApiSpecificStreamInfo: ptr::null_mut(),
+        }))
+    });
+
+    let (input, output) = match (params(true, numInputChannels), params(false, numOutputChannels))
+    {
+        (Ok(i), Ok(o)) => (i, o),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+    let input_ptr = input.as_ref().map_or(ptr::null(), |p| p as *const _);
+    let output_ptr = output.as_ref().map_or(ptr::null(), |p| p as *const _);
+    Pa_OpenStream(stream, input_ptr, output_ptr, sampleRate, framesPerBuffer, 0, streamCallback, userData)
+}
+
+pub unsafe extern "C" fn Pa_CloseStream(stream: *mut PaStream) -> PaError
+{
+    with_world(|w| match w.streams.remove(&(stream as usize))
+    {
+        Some(_) => paNoError,
+        None => paBadStreamPtr,
+    })
+}
+
+pub unsafe extern "C" fn Pa_SetStreamFinishedCallback(stream: *mut PaStream,
+                                                      streamFinishedCallback: Option<FinishedCallback>) -> PaError
+{
+    with_stream(stream, |s, _|
+    {
+        if s.state != State::Stopped { return paStreamIsNotStopped }
+        s.finished = streamFinishedCallback;
+        paNoError
+    })
+}
+
+pub unsafe extern "C" fn Pa_StartStream(stream: *mut PaStream) -> PaError
+{
+    with_world(|w|
+    {
+        let time = w.time;
+        let failure = w.fail(Operation::StartStream);
+        let s = match w.streams.get_mut(&(stream as usize)) { Some(s) => s, None => return paBadStreamPtr };
+        if s.disconnected { return paDeviceUnavailable }
+        if s.state != State::Stopped { return paStreamIsNotStopped }
+        if failure != paNoError { return failure }
+
+        s.state = State::Active;
+        s.start_time = time;
+        s.frames = 0;
+        s.written = 0;
+        paNoError
+    })
+}
+
+unsafe fn stop(stream: *mut PaStream) -> PaError
+{
+    let mut finished = None;
+    let result = with_world(|w|
+    {
+        let failure = w.fail(Operation::StopStream);
+        let s = match w.streams.get_mut(&(stream as usize)) { Some(s) => s, None => return paBadStreamPtr };
+        if s.state == State::Stopped { return paStreamIsStopped }
+        if failure != paNoError { return failure }
+
+        if s.state == State::Active
+        {
+            finished = s.finished.map(|f| (f, s.user_data));
+        }
+        s.state = State::Stopped;
+        paNoError
+    });
+
+    if let Some((callback, user_data)) = finished
+    {
+        callback(user_data);
+    }
+    result
+}
+
+pub unsafe extern "C" fn Pa_StopStream(stream: *mut PaStream) -> PaError
+{
+    stop(stream)
+}
+
+pub unsafe extern "C" fn Pa_AbortStream(stream: *mut PaStream) -> PaError
+{
+    stop(stream)
+}
+
+pub unsafe extern "C" fn Pa_IsStreamStopped(stream: *mut PaStream) -> PaError
+{
+    with_stream(stream, |s, _| (s.state == State::Stopped) as PaError)
+}
+
+pub unsafe extern "C" fn Pa_IsStreamActive(stream: *mut PaStream) -> PaError
+{
+    with_stream(stream, |s, _| (s.state == State::Active) as PaError)
+}
+
+pub unsafe extern "C" fn Pa_GetStreamInfo(stream: *mut PaStream) -> *const PaStreamInfo
+{
+    with_world(|w| w.streams.get(&(stream as usize)).map_or(ptr::null(), |s| &s.info as *const _))
+}
+
+pub unsafe extern "C" fn Pa_GetStreamHostApiType(stream: *mut PaStream) -> PaHostApiTypeId
+{
+    with_world(|w|
+    {
+        let device = match w.streams.get(&(stream as usize))
+        {
+            Some(s) => s.input.or(s.output).map_or(0, |p| p.device),
+            None => return paBadStreamPtr as PaHostApiTypeId,
+        };
+        let host_api = w.system.devices[device].host_api;
+        w.system.host_apis[host_api].type_id
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetStreamTime(stream: *mut PaStream) -> PaTime
+{
+    with_world(|w| if w.streams.contains_key(&(stream as usize)) { w.time } else { 0.0 })
+}
+
+pub unsafe extern "C" fn Pa_GetStreamCpuLoad(_stream: *mut PaStream) -> c_double
+{
+    0.0
+}
+
+pub unsafe extern "C" fn Pa_ReadStream(stream: *mut PaStream, buffer: *mut c_void, frames: c_ulong) -> PaError
+{
+    with_world(|w|
+    {
+        let failure = w.fail(Operation::ReadStream);
+        let (params, overflow) =
+        {
+            let s = match w.streams.get_mut(&(stream as usize)) { Some(s) => s, None => return paBadStreamPtr };
+            if s.callback.is_some() { return paCanNotReadFromACallbackStream }
+            let params = match s.input { Some(p) => p, None => return paCanNotReadFromAnOutputOnlyStream };
+            if s.disconnected { return paDeviceUnavailable }
+            if s.state != State::Active { return paStreamIsStopped }
+            if failure != paNoError { return failure }
+            if buffer.is_null() { return paBadBufferPtr }
+
+            s.frames += frames as u64;
+            let overflow = s.pending_flags & 0x02 != 0;
+            s.pending_flags &= !0x02;
+            (params, overflow)
+        };
+
+        let bytes = w.record(params, frames as usize);
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buffer as *mut u8, bytes.len());
+        w.catch_up(stream as usize);
+        if overflow { paInputOverflowed } else { paNoError }
+    })
+}
+
+pub unsafe extern "C" fn Pa_WriteStream(stream: *mut PaStream, buffer: *const c_void, frames: c_ulong) -> PaError
+{
+    with_world(|w|
+    {
+        let failure = w.fail(Operation::WriteStream);
+        let (params, underflow) =
+        {
+            let s = match w.streams.get_mut(&(stream as usize)) { Some(s) => s, None => return paBadStreamPtr };
+            if s.callback.is_some() { return paCanNotWriteToACallbackStream }
+            let params = match s.output { Some(p) => p, None => return paCanNotWriteToAnInputOnlyStream };
+            if s.disconnected { return paDeviceUnavailable }
+            if s.state != State::Active { return paStreamIsStopped }
+            if failure != paNoError { return failure }
+            if buffer.is_null() { return paBadBufferPtr }
+
+            s.written += frames as u64;
+            let underflow = s.pending_flags & 0x04 != 0;
+            s.pending_flags &= !0x04;
+            (params, underflow)
+        };
+
+        let len = frames as usize * params.channels * sample_size(params.format).unwrap();
+        let bytes = ::std::slice::from_raw_parts(buffer as *const u8, len);
+        w.play(params, bytes);
+        w.catch_up(stream as usize);
+        if underflow { paOutputUnderflowed } else { paNoError }
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetStreamReadAvailable(stream: *mut PaStream) -> c_long
+{
+    with_world(|w|
+    {
+        let params = match w.streams.get(&(stream as usize))
+        {
+            Some(s) => match s.input { Some(p) => p, None => return paCanNotReadFromAnOutputOnlyStream as c_long },
+            None => return paBadStreamPtr as c_long,
+        };
+        w.input.get(&params.device).map_or(0, |q| (q.len() / params.channels) as c_long)
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetStreamWriteAvailable(stream: *mut PaStream) -> c_long
+{
+    with_world(|w| match w.streams.get(&(stream as usize))
+    {
+        Some(s) if s.output.is_some() => s.frames_per_buffer as c_long,
+        Some(_) => paCanNotWriteToAnInputOnlyStream as c_long,
+        None => paBadStreamPtr as c_long,
+    })
+}
+
+pub unsafe extern "C" fn Pa_GetSampleSize(format: PaSampleFormat) -> PaError
+{
+    sample_size(format).map_or(paSampleFormatNotSupported, |n| n as PaError)
+}
+
+/// Lets the virtual clock run for the given time, running the callbacks of active streams
+pub unsafe extern "C" fn Pa_Sleep(msec: c_long)
+{
+    super::advance(msec.max(0) as f64 / 1000.0);
+}
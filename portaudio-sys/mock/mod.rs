@@ -0,0 +1,685 @@
+//! An emulation of PortAudio for testing without sound hardware
+//!
+//! Enabled by the `mock` feature, which replaces the linked library by Rust implementations of
+//! the Pa_* functions. Every thread has its own emulated system, so tests running in parallel do
+//! not see each other's devices or streams.
+//!
+//! Time only passes when advance() is called, or when a blocking stream reads or writes. Stream
+//! callbacks run on the thread calling advance(). Devices play into an output buffer that can be
+//! inspected with take_output(), and record from an input buffer filled with push_input().
+
+use libc::{c_int, c_ulong, c_void};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::ptr;
+
+use {PaError, PaHostApiTypeId, PaSampleFormat, PaStreamCallbackFlags, PaStreamCallbackTimeInfo, PaTime};
+use {Struct_PaDeviceInfo, Struct_PaHostApiInfo, Struct_PaHostErrorInfo, Struct_PaStreamInfo, Struct_PaStreamParameters};
+use {paInDevelopment, paNoError, paContinue};
+
+#[doc(hidden)]
+pub mod api;
+
+/// Sample formats the mock can convert, as PaSampleFormat bits
+pub const ALL_FORMATS: PaSampleFormat = 0x3F;
+
+/// Frames per buffer used when the stream leaves it to PortAudio
+pub const DEFAULT_FRAMES_PER_BUFFER: c_ulong = 256;
+
+/// A host API of the emulated system
+#[derive(Clone, Debug)]
+pub struct MockHostApi
+{
+    /// One of the paXXX host API type ids
+    pub type_id: PaHostApiTypeId,
+
+    /// Name reported in the host API info
+    pub name: String,
+
+    /// Global index of the default input device of this API
+    pub default_input: Option<usize>,
+
+    /// Global index of the default output device of this API
+    pub default_output: Option<usize>,
+}
+
+/// A device of the emulated system
+#[derive(Clone, Debug)]
+pub struct MockDevice
+{
+    /// Name reported in the device info
+    pub name: String,
+
+    /// Index of the host API the device belongs to
+    pub host_api: usize,
+
+    /// Maximum number of input channels
+    pub max_input_channels: u32,
+
+    /// Maximum number of output channels
+    pub max_output_channels: u32,
+
+    /// Sample rate reported as the default
+    pub default_sample_rate: f64,
+
+    /// Sample rates streams may be opened with
+    pub sample_rates: Vec<f64>,
+
+    /// Sample formats streams may be opened with, as PaSampleFormat bits
+    pub formats: PaSampleFormat,
+
+    /// Default low latency in seconds, for both directions
+    pub low_latency: PaTime,
+
+    /// Default high latency in seconds, for both directions
+    pub high_latency: PaTime,
+}
+
+impl MockDevice
+{
+    /// A device supporting all formats at 44.1 and 48 kHz
+    pub fn new(name: &str, host_api: usize, max_input_channels: u32, max_output_channels: u32) -> MockDevice
+    {
+        MockDevice
+        {
+            name: name.to_string(),
+            host_api,
+            max_input_channels,
+            max_output_channels,
+            default_sample_rate: 44_100.0,
+            sample_rates: vec![44_100.0, 48_000.0],
+            formats: ALL_FORMATS,
+            low_latency: 0.01,
+            high_latency: 0.1,
+        }
+    }
+}
+
+/// The host APIs and devices of the emulated system
+#[derive(Clone, Debug)]
+pub struct MockSystem
+{
+    /// The host APIs, in order of their index
+    pub host_apis: Vec<MockHostApi>,
+
+    /// The devices, in order of their global index
+    pub devices: Vec<MockDevice>,
+
+    /// Index of the default host API
+    pub default_host_api: usize,
+}
+
+impl Default for MockSystem
+{
+    /// A single host API with a stereo input, a stereo output and a duplex device
+    fn default() -> MockSystem
+    {
+        MockSystem
+        {
+            host_apis: vec![MockHostApi
+            {
+                type_id: paInDevelopment,
+                name: "Mock".to_string(),
+                default_input: Some(0),
+                default_output: Some(1),
+            }],
+            devices: vec![MockDevice::new("Mock Input", 0, 2, 0),
+                          MockDevice::new("Mock Output", 0, 0, 2),
+                          MockDevice::new("Mock Duplex", 0, 2, 2)],
+            default_host_api: 0,
+        }
+    }
+}
+
+/// Operations that can be made to fail with fail_next()
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Operation
+{
+    /// Pa_Initialize
+    Initialize,
+
+    /// Pa_OpenStream and Pa_OpenDefaultStream
+    OpenStream,
+
+    /// Pa_StartStream
+    StartStream,
+
+    /// Pa_StopStream and Pa_AbortStream
+    StopStream,
+
+    /// Pa_ReadStream
+    ReadStream,
+
+    /// Pa_WriteStream
+    WriteStream,
+}
+
+/// Replace the emulated system. Takes effect at the next Pa_Initialize, like a rescan of the
+/// devices by PortAudio.
+pub fn configure(system: MockSystem)
+{
+    with_world(|w| w.next_system = system);
+}
+
+/// Let time pass, running the callbacks of the active streams for every buffer that is due
+pub fn advance(seconds: f64)
+{
+    let ids = with_world(|w| { w.time += seconds; w.stream_ids() });
+    for id in ids
+    {
+        while let Some(call) = with_world(|w| w.prepare_callback(id))
+        {
+            run_callback(id, call);
+        }
+    }
+}
+
+/// The current time of the virtual clock, in seconds
+pub fn time() -> f64
+{
+    with_world(|w| w.time)
+}
+
+/// Queue interleaved samples to be recorded from an input device
+pub fn push_input(device: usize, samples: &[f32])
+{
+    with_world(|w| w.input.entry(device).or_default().extend(samples.iter().cloned()));
+}
+
+/// Take the interleaved samples played on an output device so far
+pub fn take_output(device: usize) -> Vec<f32>
+{
+    with_world(|w| w.output.remove(&device).unwrap_or_default())
+}
+
+/// Report the given status flags to the next callback or blocking call of every open stream
+///
+/// For blocking streams, input overflow makes the next read return paInputOverflowed, and
+/// output underflow makes the next write return paOutputUnderflowed.
+pub fn inject_xrun(flags: PaStreamCallbackFlags)
+{
+    with_world(|w| for stream in w.streams.values_mut() { stream.pending_flags |= flags; });
+}
+
+/// Make the next call of the operation fail with the given error
+pub fn fail_next(operation: Operation, error: PaError)
+{
+    with_world(|w| { w.failures.insert(operation, error); });
+}
+
+/// Set the information returned by Pa_GetLastHostErrorInfo
+pub fn set_host_error(host_api_type: PaHostApiTypeId, code: i64, text: &str)
+{
+    with_world(|w|
+    {
+        w.host_error_text = CString::new(text).unwrap_or_default();
+        w.host_error = Struct_PaHostErrorInfo
+        {
+            hostApiType: host_api_type,
+            errorCode: code as _,
+            errorText: w.host_error_text.as_ptr(),
+        };
+    });
+}
+
+/// Make a device disappear while streams use it, as when it is unplugged
+///
+/// Active streams on the device stop, and their finished callbacks are called. Further calls on
+/// those streams fail with paDeviceUnavailable. The device stays in the device list until the
+/// next Pa_Initialize after configure().
+pub fn disconnect(device: usize)
+{
+    let finished = with_world(|w|
+    {
+        w.disconnected.push(device);
+        let mut finished = Vec::new();
+        for stream in w.streams.values_mut().filter(|s| s.uses(device))
+        {
+            if stream.state == State::Active
+            {
+                stream.state = State::Done;
+                finished.extend(stream.finished.map(|f| (f, stream.user_data)));
+            }
+            stream.disconnected = true;
+        }
+        finished
+    });
+
+    for (callback, user_data) in finished
+    {
+        callback(user_data);
+    }
+}
+
+/// The number of streams that are open
+pub fn open_streams() -> usize
+{
+    with_world(|w| w.streams.len())
+}
+
+/// The number of Pa_Initialize calls that were not terminated yet
+pub fn initialize_count() -> u32
+{
+    with_world(|w| w.initialized)
+}
+
+pub type StreamCallback = extern "C" fn(*const c_void, *mut c_void, c_ulong, *const PaStreamCallbackTimeInfo,
+                                        PaStreamCallbackFlags, *mut c_void) -> c_int;
+pub type FinishedCallback = extern "C" fn(*mut c_void);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State
+{
+    Stopped,
+    Active,
+    // The callback returned paComplete or paAbort, but the stream was not stopped yet
+    Done,
+}
+
+#[derive(Copy, Clone)]
+struct Params
+{
+    device: usize,
+    channels: usize,
+    format: PaSampleFormat,
+}
+
+struct MockStream
+{
+    input: Option<Params>,
+    output: Option<Params>,
+    sample_rate: f64,
+    frames_per_buffer: usize,
+    callback: Option<StreamCallback>,
+    finished: Option<FinishedCallback>,
+    user_data: *mut c_void,
+    state: State,
+    start_time: f64,
+    // Frames processed by callbacks or read, and frames written
+    frames: u64,
+    written: u64,
+    pending_flags: PaStreamCallbackFlags,
+    disconnected: bool,
+    info: Struct_PaStreamInfo,
+}
+
+impl MockStream
+{
+    fn uses(&self, device: usize) -> bool
+    {
+        self.input.map(|p| p.device) == Some(device) || self.output.map(|p| p.device) == Some(device)
+    }
+
+    fn position(&self) -> f64
+    {
+        self.start_time + self.frames as f64 / self.sample_rate
+    }
+}
+
+struct CallbackCall
+{
+    callback: StreamCallback,
+    user_data: *mut c_void,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    frames: usize,
+    time_info: PaStreamCallbackTimeInfo,
+    flags: PaStreamCallbackFlags,
+}
+
+struct World
+{
+    initialized: u32,
+    system: MockSystem,
+    next_system: MockSystem,
+    host_api_names: Vec<CString>,
+    host_api_infos: Vec<Struct_PaHostApiInfo>,
+    device_names: Vec<CString>,
+    device_infos: Vec<Struct_PaDeviceInfo>,
+    host_error: Struct_PaHostErrorInfo,
+    host_error_text: CString,
+    streams: HashMap<usize, MockStream>,
+    next_stream: usize,
+    time: f64,
+    input: HashMap<usize, VecDeque<f32>>,
+    output: HashMap<usize, Vec<f32>>,
+    failures: HashMap<Operation, PaError>,
+    disconnected: Vec<usize>,
+}
+
+thread_local!(static WORLD: RefCell<World> = RefCell::new(World::new()));
+
+// Never hold the borrow while calling back into user code, which may call Pa_* functions
+fn with_world<T, F: FnOnce(&mut World) -> T>(f: F) -> T
+{
+    WORLD.with(|w| f(&mut w.borrow_mut()))
+}
+
+impl World
+{
+    fn new() -> World
+    {
+        World
+        {
+            initialized: 0,
+            system: MockSystem { host_apis: Vec::new(), devices: Vec::new(), default_host_api: 0 },
+            next_system: MockSystem::default(),
+            host_api_names: Vec::new(),
+            host_api_infos: Vec::new(),
+            device_names: Vec::new(),
+            device_infos: Vec::new(),
+            host_error: Struct_PaHostErrorInfo { hostApiType: paInDevelopment, errorCode: 0, errorText: b"\0".as_ptr() as *const _ },
+            host_error_text: CString::default(),
+            streams: HashMap::new(),
+            next_stream: 1,
+            time: 0.0,
+            input: HashMap::new(),
+            output: HashMap::new(),
+            failures: HashMap::new(),
+            disconnected: Vec::new(),
+        }
+    }
+
+    // Take over the configured system, and build the info structs PortAudio hands out
+    fn rescan(&mut self)
+    {
+        self.system = self.next_system.clone();
+        self.disconnected.clear();
+
+        self.host_api_names = self.system.host_apis.iter().map(|a| CString::new(a.name.clone()).unwrap_or_default()).collect();
+        self.device_names = self.system.devices.iter().map(|d| CString::new(d.name.clone()).unwrap_or_default()).collect();
+
+        let devices = &self.system.devices;
+        self.host_api_infos = self.system.host_apis.iter().zip(self.host_api_names.iter()).enumerate().map(|(i, (api, name))|
+        {
+            Struct_PaHostApiInfo
+            {
+                structVersion: 1,
+                _type: api.type_id,
+                name: name.as_ptr(),
+                deviceCount: devices.iter().filter(|d| d.host_api == i).count() as c_int,
+                defaultInputDevice: api.default_input.map_or(-1, |d| d as c_int),
+                defaultOutputDevice: api.default_output.map_or(-1, |d| d as c_int),
+            }
+        }).collect();
+
+        self.device_infos = devices.iter().zip(self.device_names.iter()).map(|(device, name)|
+        {
+            Struct_PaDeviceInfo
+            {
+                structVersion: 2,
+                name: name.as_ptr(),
+                hostApi: device.host_api as c_int,
+                maxInputChannels: device.max_input_channels as c_int,
+                maxOutputChannels: device.max_output_channels as c_int,
+                defaultLowInputLatency: device.low_latency,
+                defaultLowOutputLatency: device.low_latency,
+                defaultHighInputLatency: device.high_latency,
+                defaultHighOutputLatency: device.high_latency,
+                defaultSampleRate: device.default_sample_rate,
+            }
+        }).collect();
+    }
+
+    fn clear(&mut self)
+    {
+        self.streams.clear();
+        self.system = MockSystem { host_apis: Vec::new(), devices: Vec::new(), default_host_api: 0 };
+        self.host_api_infos.clear();
+        self.device_infos.clear();
+    }
+
+    fn fail(&mut self, operation: Operation) -> PaError
+    {
+        self.failures.remove(&operation).unwrap_or(paNoError)
+    }
+
+    fn stream_ids(&self) -> Vec<usize>
+    {
+        let mut ids: Vec<usize> = self.streams.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn default_device(&self, input: bool) -> Option<usize>
+    {
+        let api = self.system.host_apis.get(self.system.default_host_api)?;
+        if input { api.default_input } else { api.default_output }
+    }
+
+    // Check one direction of a stream. Returns the parameters to use.
+    fn check_params(&self, params: &Struct_PaStreamParameters, input: bool, sample_rate: f64) -> Result<Params, PaError>
+    {
+        use {paInvalidDevice, paInvalidChannelCount, paSampleFormatNotSupported, paInvalidSampleRate,
+             paIncompatibleHostApiSpecificStreamInfo, paDeviceUnavailable};
+
+        if params.device < 0 { return Err(paInvalidDevice) }
+        let index = params.device as usize;
+        let device = self.system.devices.get(index).ok_or(paInvalidDevice)?;
+        if self.disconnected.contains(&index) { return Err(paDeviceUnavailable) }
+
+        let max = if input { device.max_input_channels } else { device.max_output_channels };
+        if params.channelCount <= 0 || params.channelCount as u32 > max { return Err(paInvalidChannelCount) }
+        if sample_size(params.sampleFormat).is_none() || params.sampleFormat & device.formats == 0
+        {
+            return Err(paSampleFormatNotSupported);
+        }
+        if !device.sample_rates.contains(&sample_rate) { return Err(paInvalidSampleRate) }
+        if !params.hostApiSpecificStreamInfo.is_null() { return Err(paIncompatibleHostApiSpecificStreamInfo) }
+
+        Ok(Params { device: index, channels: params.channelCount as usize, format: params.sampleFormat })
+    }
+
+    fn check_format(&self,
+                    input: Option<&Struct_PaStreamParameters>,
+                    output: Option<&Struct_PaStreamParameters>,
+                    sample_rate: f64) -> Result<(Option<Params>, Option<Params>), PaError>
+    {
+        use {paNotInitialized, paInvalidChannelCount, paBadIODeviceCombination};
+
+        if self.initialized == 0 { return Err(paNotInitialized) }
+        if input.is_none() && output.is_none() { return Err(paInvalidChannelCount) }
+
+        let input = match input { Some(p) => Some(self.check_params(p, true, sample_rate)?), None => None };
+        let output = match output { Some(p) => Some(self.check_params(p, false, sample_rate)?), None => None };
+        if let (Some(i), Some(o)) = (input, output)
+        {
+            if self.system.devices[i.device].host_api != self.system.devices[o.device].host_api
+            {
+                return Err(paBadIODeviceCombination);
+            }
+        }
+        Ok((input, output))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn open(&mut self,
+            input: Option<&Struct_PaStreamParameters>,
+            output: Option<&Struct_PaStreamParameters>,
+            sample_rate: f64,
+            frames_per_buffer: c_ulong,
+            callback: Option<StreamCallback>,
+            user_data: *mut c_void) -> Result<usize, PaError>
+    {
+        let (input_params, output_params) = self.check_format(input, output, sample_rate)?;
+        match self.fail(Operation::OpenStream)
+        {
+            paNoError => {},
+            e => return Err(e),
+        }
+
+        let latency = |p: Option<&Struct_PaStreamParameters>| p.map_or(0.0, |p| p.suggestedLatency);
+        let id = self.next_stream;
+        self.next_stream += 1;
+        self.streams.insert(id, MockStream
+        {
+            input: input_params,
+            output: output_params,
+            sample_rate,
+            frames_per_buffer: match frames_per_buffer { 0 => DEFAULT_FRAMES_PER_BUFFER, n => n } as usize,
+            callback,
+            finished: None,
+            user_data,
+            state: State::Stopped,
+            start_time: 0.0,
+            frames: 0,
+            written: 0,
+            pending_flags: 0,
+            disconnected: false,
+            info: Struct_PaStreamInfo
+            {
+                structVersion: 1,
+                inputLatency: latency(input),
+                outputLatency: latency(output),
+                sampleRate: sample_rate,
+            },
+        });
+        Ok(id)
+    }
+
+    // Take the input of a stream for the given number of frames, in the stream format
+    fn record(&mut self, params: Params, frames: usize) -> Vec<u8>
+    {
+        let queue = self.input.entry(params.device).or_default();
+        let samples: Vec<f32> = (0 .. frames * params.channels).map(|_| queue.pop_front().unwrap_or(0.0)).collect();
+        encode(params.format, &samples)
+    }
+
+    fn play(&mut self, params: Params, bytes: &[u8])
+    {
+        self.output.entry(params.device).or_default().extend(decode(params.format, bytes));
+    }
+
+    // A blocking stream waits until the device got to its frames
+    fn catch_up(&mut self, id: usize)
+    {
+        if let Some(s) = self.streams.get(&id)
+        {
+            let end = s.start_time + s.frames.max(s.written) as f64 / s.sample_rate;
+            if end > self.time { self.time = end; }
+        }
+    }
+
+    // When a buffer of the callback stream is due, take its input and prepare the call
+    fn prepare_callback(&mut self, id: usize) -> Option<CallbackCall>
+    {
+        let time = self.time;
+        let (input, output, frames, callback, user_data, time_info, flags) =
+        {
+            let stream = self.streams.get_mut(&id)?;
+            let callback = stream.callback?;
+            let frames = stream.frames_per_buffer;
+            let end = stream.position() + frames as f64 / stream.sample_rate;
+            if stream.state != State::Active || end > time + 1e-9 { return None }
+
+            let time_info = PaStreamCallbackTimeInfo
+            {
+                inputBufferAdcTime: stream.position() - stream.info.inputLatency,
+                currentTime: stream.position(),
+                outputBufferDacTime: stream.position() + stream.info.outputLatency,
+            };
+            let flags = stream.pending_flags;
+            stream.pending_flags = 0;
+            (stream.input, stream.output, frames, callback, stream.user_data, time_info, flags)
+        };
+
+        Some(CallbackCall
+        {
+            callback,
+            user_data,
+            input: input.map_or(Vec::new(), |p| self.record(p, frames)),
+            output: output.map_or(Vec::new(), |p| vec![silence(p.format); frames * p.channels * sample_size(p.format).unwrap()]),
+            frames,
+            time_info,
+            flags,
+        })
+    }
+}
+
+fn run_callback(id: usize, mut call: CallbackCall)
+{
+    let input_ptr = if call.input.is_empty() { ptr::null() } else { call.input.as_ptr() as *const c_void };
+    let output_ptr = if call.output.is_empty() { ptr::null_mut() } else { call.output.as_mut_ptr() as *mut c_void };
+    let result = (call.callback)(input_ptr, output_ptr, call.frames as c_ulong, &call.time_info, call.flags, call.user_data);
+
+    let finished = with_world(|w|
+    {
+        let output = w.streams.get(&id).and_then(|s| s.output);
+        if let Some(params) = output
+        {
+            w.play(params, &call.output);
+        }
+
+        let stream = w.streams.get_mut(&id)?;
+        stream.frames += call.frames as u64;
+        if result == paContinue as c_int || stream.state != State::Active { return None }
+
+        stream.state = State::Done;
+        stream.finished.map(|f| (f, stream.user_data))
+    });
+
+    if let Some((callback, user_data)) = finished
+    {
+        callback(user_data);
+    }
+}
+
+fn sample_size(format: PaSampleFormat) -> Option<usize>
+{
+    match format & !0x8000_0000
+    {
+        0x01 | 0x02 => Some(4),
+        0x04 => Some(3),
+        0x08 => Some(2),
+        0x10 | 0x20 => Some(1),
+        _ => None,
+    }
+}
+
+fn silence(format: PaSampleFormat) -> u8
+{
+    if format == 0x20 { 0x80 } else { 0 }
+}
+
+fn encode(format: PaSampleFormat, samples: &[f32]) -> Vec<u8>
+{
+    let mut bytes = Vec::with_capacity(samples.len() * sample_size(format).unwrap_or(0));
+    for &s in samples
+    {
+        let s = s.clamp(-1.0, 1.0) as f64;
+        match format
+        {
+            0x01 => bytes.extend_from_slice(&(s as f32).to_ne_bytes()),
+            0x02 => bytes.extend_from_slice(&((s * 2_147_483_647.0).round() as i32).to_ne_bytes()),
+            0x04 =>
+            {
+                let v = ((s * 8_388_607.0).round() as i32).to_ne_bytes();
+                if cfg!(target_endian = "little") { bytes.extend_from_slice(&v[0 .. 3]) } else { bytes.extend_from_slice(&v[1 .. 4]) }
+            },
+            0x08 => bytes.extend_from_slice(&((s * 32_767.0).round() as i16).to_ne_bytes()),
+            0x10 => bytes.push((s * 127.0).round() as i8 as u8),
+            _ => bytes.push(((s * 127.0).round() as i8 as u8) ^ 0x80),
+        }
+    }
+    bytes
+}
+
+fn decode(format: PaSampleFormat, bytes: &[u8]) -> Vec<f32>
+{
+    let size = match sample_size(format) { Some(n) => n, None => return Vec::new() };
+    bytes.chunks(size).map(|b| match format
+    {
+        0x01 => f32::from_ne_bytes([b[0], b[1], b[2], b[3]]),
+        0x02 => (i32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as f64 / 2_147_483_648.0) as f32,
+        0x04 =>
+        {
+            let v = if cfg!(target_endian = "little") { i32::from_le_bytes([0, b[0], b[1], b[2]]) }
+                    else { i32::from_be_bytes([b[0], b[1], b[2], 0]) };
+            (v >> 8) as f32 / 8_388_608.0
+        },
+        0x08 => i16::from_ne_bytes([b[0], b[1]]) as f32 / 32_768.0,
+        0x10 => b[0] as i8 as f32 / 128.0,
+        _ => (b[0] as f32 - 128.0) / 128.0,
+    }).collect()
+}
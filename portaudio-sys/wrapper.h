@@ -0,0 +1,4 @@
+/* The headers lib.rs is generated from, see bindgen.sh */
+#include <portaudio.h>
+#include <pa_linux_alsa.h>
+#include <pa_jack.h>
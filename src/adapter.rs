@@ -0,0 +1,725 @@
+//! Processing between the buffers of the device and the buffers presented to the application
+
+use convert::{self, Conversion, Converter, Dither};
+use resample::{Resampler, ResampleQuality};
+use routing::Routing;
+use stream::{SampleFormat, StreamCallbackFlags, StreamCallbackResult};
+use pa::PaError;
+use libc::c_void;
+use std::slice;
+use std::time::Duration;
+
+/// Frames to allocate room for when the buffer size is left to PortAudio
+const DEFAULT_FRAMES: usize = 1024;
+
+/// Samples of silence encoded at a time
+const SILENCE_SAMPLES: usize = 256;
+
+/// One direction of a stream, as seen by the application and by the device
+#[derive(Clone)]
+pub struct Direction
+{
+    pub channels: u32,
+    pub device_channels: u32,
+    pub app_format: SampleFormat,
+    pub device_format: SampleFormat,
+    pub routing: Option<Routing>,
+}
+
+impl Direction
+{
+    fn app_bytes(&self, frames: usize) -> usize
+    {
+        frames * self.channels as usize * convert::format_size(self.app_format).unwrap()
+    }
+
+    fn device_bytes(&self, frames: usize) -> usize
+    {
+        frames * self.device_channels as usize * convert::format_size(self.device_format).unwrap()
+    }
+
+    fn samples(&self, frames: usize) -> usize
+    {
+        frames * self.channels as usize
+    }
+
+    fn device_samples(&self, frames: usize) -> usize
+    {
+        frames * self.device_channels as usize
+    }
+}
+
+/// Sample rates of a resampling stream
+#[derive(Copy, Clone)]
+pub struct Rates
+{
+    pub app: f64,
+    pub device: f64,
+    pub quality: ResampleQuality,
+}
+
+/// Conversion of the samples of one direction
+struct Path
+{
+    direction: Direction,
+    converter: Converter,
+    resampler: Option<Resampler>,
+    primed: usize,
+    device_float: Vec<f32>,
+    app_float: Vec<f32>,
+    routed: Vec<f32>,
+    app: Vec<f32>,
+}
+
+impl Path
+{
+    fn new(direction: Direction, conversion: Conversion, resampler: Option<Resampler>, frames: usize) -> Path
+    {
+        let mut path = Path
+        {
+            direction,
+            converter: Converter::new(conversion),
+            resampler,
+            primed: 0,
+            device_float: Vec::new(),
+            app_float: Vec::new(),
+            routed: Vec::new(),
+            app: Vec::new(),
+        };
+
+        // Allocate the buffers up front so the callback does not need to
+        let samples = path.direction.samples(frames).max(path.direction.device_samples(frames));
+        path.device_float.resize(samples, 0.0);
+        path.app_float.resize(samples, 0.0);
+        path.routed.resize(samples, 0.0);
+        bytes_mut(&mut path.app, path.direction.app_bytes(frames));
+        path
+    }
+
+    /// Convert device samples into the application buffer, and return that buffer
+    fn convert_input(&mut self, device: &[u8], frames: usize) -> &[u8]
+    {
+        let device_samples = self.direction.device_samples(frames);
+        grow(&mut self.device_float, device_samples);
+
+        convert::decode(self.direction.device_format, device, &mut self.device_float[.. device_samples]);
+        let samples = route(&self.direction.routing, &self.device_float[.. device_samples], &mut self.routed, frames);
+        let app = bytes_mut(&mut self.app, self.direction.app_bytes(frames));
+        self.converter.encode(samples, self.direction.app_format, app);
+        app
+    }
+
+    /// The application buffer, to be filled before calling convert_output or push_app
+    fn app_buffer(&mut self, frames: usize) -> &mut [u8]
+    {
+        bytes_mut(&mut self.app, self.direction.app_bytes(frames))
+    }
+
+    /// Convert the application buffer into the given device buffer
+    fn convert_output(&mut self, frames: usize, device: &mut [u8])
+    {
+        let samples = self.direction.samples(frames);
+        grow(&mut self.app_float, samples);
+
+        let app = bytes_mut(&mut self.app, self.direction.app_bytes(frames));
+        convert::decode(self.direction.app_format, app, &mut self.app_float[.. samples]);
+        let samples = route(&self.direction.routing, &self.app_float[.. samples], &mut self.routed, frames);
+        self.converter.encode(samples, self.direction.device_format, device);
+    }
+
+    /// Make room in the resampler for the given number of frames more, for the blocking calls.
+    /// The callback stays within what Adapter::new() reserved.
+    fn reserve(&mut self, frames: usize)
+    {
+        self.resampler.as_mut().unwrap().reserve(frames);
+    }
+
+    /// Pass device samples into the input resampler
+    fn push_device(&mut self, device: &[u8], frames: usize)
+    {
+        let device_samples = self.direction.device_samples(frames);
+        grow(&mut self.device_float, device_samples);
+
+        convert::decode(self.direction.device_format, device, &mut self.device_float[.. device_samples]);
+        let samples = route(&self.direction.routing, &self.device_float[.. device_samples], &mut self.routed, frames);
+        self.resampler.as_mut().unwrap().push(samples);
+    }
+
+    /// Take resampled input into the application buffer, padding with silence when not enough
+    /// input is available. Also returns whether padding was needed.
+    fn pull_app(&mut self, frames: usize) -> (&[u8], bool)
+    {
+        let samples = self.direction.samples(frames);
+        grow(&mut self.app_float, samples);
+
+        let pulled = self.resampler.as_mut().unwrap().pull(&mut self.app_float[.. samples]);
+        for v in &mut self.app_float[self.direction.samples(pulled) .. samples] { *v = 0.0; }
+
+        let app = bytes_mut(&mut self.app, self.direction.app_bytes(frames));
+        self.converter.encode(&self.app_float[.. samples], self.direction.app_format, app);
+        (app, pulled < frames)
+    }
+
+    /// Pass the application buffer into the output resampler
+    fn push_app(&mut self, frames: usize)
+    {
+        let samples = self.direction.samples(frames);
+        grow(&mut self.app_float, samples);
+
+        let app = bytes_mut(&mut self.app, self.direction.app_bytes(frames));
+        convert::decode(self.direction.app_format, app, &mut self.app_float[.. samples]);
+        self.resampler.as_mut().unwrap().push(&self.app_float[.. samples]);
+    }
+
+    /// Take resampled output into the given device buffer, padding with silence when not enough
+    /// output is available
+    fn pull_device(&mut self, frames: usize, device: &mut [u8])
+    {
+        let samples = self.direction.samples(frames);
+        grow(&mut self.device_float, samples);
+
+        let pulled = self.resampler.as_mut().unwrap().pull(&mut self.device_float[.. samples]);
+        for v in &mut self.device_float[self.direction.samples(pulled) .. samples] { *v = 0.0; }
+        let samples = route(&self.direction.routing, &self.device_float[.. samples], &mut self.routed, frames);
+        self.converter.encode(samples, self.direction.device_format, device);
+    }
+
+    fn available(&self) -> usize
+    {
+        self.resampler.as_ref().map_or(0, |r| r.available())
+    }
+}
+
+/// Sits between PortAudio and the application callback or the blocking read and write calls
+pub struct Adapter
+{
+    input: Option<Direction>,
+    output: Option<Direction>,
+    input_path: Option<Path>,
+    output_path: Option<Path>,
+    rates: Option<Rates>,
+    sample_rate: f64,
+    frames_per_buffer: usize,
+    max_frames: usize,
+}
+
+impl Adapter
+{
+    /// Returns None when the directions need no processing at all
+    ///
+    /// The buffers are sized for the given frames per buffer of the application and the
+    /// matching number of device frames, so the callback does not allocate.
+    pub fn new(input: Option<Direction>,
+               output: Option<Direction>,
+               conversion: Conversion,
+               sample_rate: f64,
+               rates: Option<Rates>,
+               frames_per_buffer: u64) -> Option<Adapter>
+    {
+        let app_frames = match frames_per_buffer { 0 => DEFAULT_FRAMES, n => n as usize };
+        let device_frames = rates.map_or(app_frames, |r| (app_frames as f64 * r.device / r.app).ceil() as usize);
+        let frames = app_frames.max(device_frames);
+        let path = |direction: Option<Direction>, is_input: bool| match (direction, rates)
+        {
+            (Some(d), Some(r)) =>
+            {
+                let (from, to) = if is_input { (r.device, r.app) } else { (r.app, r.device) };
+                let mut resampler = Resampler::new(d.channels, from, to, r.quality);
+                // The filter window and the input for a block pulled, a block pushed on top of
+                // that, and the input primed below
+                let window = 2 * resampler.latency_frames() + 1;
+                resampler.reserve(window + (frames as f64 * from / to).ceil() as usize + frames + device_frames + 2);
+                Some(Path::new(d, conversion, Some(resampler), frames))
+            },
+            (Some(d), None) if d.app_format != d.device_format || d.routing.is_some() => Some(Path::new(d, conversion, None, frames)),
+            _ => None,
+        };
+
+        let mut input_path = path(input.clone(), true);
+        let output_path = path(output.clone(), false);
+        if input_path.is_none() && output_path.is_none()
+        {
+            return None;
+        }
+
+        // In a duplex stream the output decides when the application runs, which may be up to a
+        // block before the input has caught up. Delay the input so it never runs dry.
+        if let (Some(path), true, Some(r)) = (input_path.as_mut(), output_path.is_some(), rates)
+        {
+            let block = (frames_per_buffer as f64 * r.device / r.app).ceil() as usize;
+            path.primed = block + 2;
+            let silence = vec![0.0; path.direction.samples(path.primed)];
+            path.resampler.as_mut().unwrap().push(&silence);
+        }
+
+        Some(Adapter
+        {
+            input,
+            output,
+            input_path,
+            output_path,
+            rates,
+            sample_rate,
+            frames_per_buffer: frames_per_buffer as usize,
+            max_frames: frames,
+        })
+    }
+
+    /// Whether blocking reads have to pass through the adapter
+    pub fn converts_input(&self) -> bool
+    {
+        self.input_path.is_some()
+    }
+
+    /// Whether blocking writes have to pass through the adapter
+    pub fn converts_output(&self) -> bool
+    {
+        self.output_path.is_some()
+    }
+
+    /// The delay the adapter adds to the input
+    pub fn input_delay(&self) -> Duration
+    {
+        match (self.input_path.as_ref(), self.rates)
+        {
+            (Some(&Path { resampler: Some(ref resampler), primed, .. }), Some(rates)) =>
+                Duration::from_secs_f64((resampler.latency_frames() + primed) as f64 / rates.device),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// The delay the adapter adds to the output
+    pub fn output_delay(&self) -> Duration
+    {
+        match (self.output_path.as_ref().and_then(|p| p.resampler.as_ref()), self.rates)
+        {
+            (Some(resampler), Some(rates)) => Duration::from_secs_f64(resampler.latency_frames() as f64 / rates.app),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// Process one buffer of the device, calling the application callback with converted buffers
+    ///
+    /// The callback receives the input and output buffers, the time since the start of this
+    /// device buffer, and flags to add to those of the device. When resampling, or when the
+    /// device buffer is larger than the adapter was sized for, the callback may be called
+    /// multiple times, or not at all.
+    ///
+    /// The pointers must be valid for the given number of frames, in the device formats.
+    pub unsafe fn process<F>(&mut self,
+                             input: *const c_void,
+                             output: *mut c_void,
+                             frames: usize,
+                             mut callback: F) -> StreamCallbackResult
+        where F: FnMut(&[u8], &mut [u8], Duration, StreamCallbackFlags) -> StreamCallbackResult
+    {
+        let device_input: &[u8] = match self.input
+        {
+            Some(ref d) if !input.is_null() => slice::from_raw_parts(input as *const u8, d.device_bytes(frames)),
+            _ => &[],
+        };
+        let device_output: &mut [u8] = match self.output
+        {
+            Some(ref d) if !output.is_null() => slice::from_raw_parts_mut(output as *mut u8, d.device_bytes(frames)),
+            _ => &mut [],
+        };
+
+        // Work in blocks the buffers were sized for. Once the application is done, the rest of the
+        // output is silent, as PortAudio does.
+        let (input_bytes, output_bytes) = (device_input.len() / frames.max(1), device_output.len() / frames.max(1));
+        let mut result = StreamCallbackResult::Continue;
+        let mut start = 0;
+        while start < frames
+        {
+            let count = (frames - start).min(self.max_frames);
+            let input_block = &device_input[start * input_bytes .. (start + count) * input_bytes];
+            let output_block = &mut device_output[start * output_bytes .. (start + count) * output_bytes];
+            if result == StreamCallbackResult::Continue
+            {
+                let base = Duration::from_secs_f64(start as f64 / self.sample_rate);
+                result = self.process_block(input_block, output_block, count, |i, o, offset, flags| callback(i, o, base + offset, flags));
+            }
+            else if let Some(ref d) = self.output
+            {
+                silence(d.device_format, output_block);
+            }
+            start += count;
+        }
+        result
+    }
+
+    // Process at most max_frames frames of the device
+    fn process_block<F>(&mut self,
+                        device_input: &[u8],
+                        device_output: &mut [u8],
+                        frames: usize,
+                        mut callback: F) -> StreamCallbackResult
+        where F: FnMut(&[u8], &mut [u8], Duration, StreamCallbackFlags) -> StreamCallbackResult
+    {
+        let rates = match self.rates
+        {
+            Some(rates) => rates,
+            None => return self.process_direct(device_input, device_output, frames, callback),
+        };
+
+        if let Some(ref mut path) = self.input_path
+        {
+            path.push_device(device_input, frames);
+        }
+
+        let mut result = StreamCallbackResult::Continue;
+        let mut done = 0;
+        let offset = |done: usize| Duration::from_secs_f64(done as f64 / rates.app);
+
+        match self.output_path
+        {
+            Some(ref mut output_path) =>
+            {
+                // Run the application until the output resampler can fill the device buffer
+                while result == StreamCallbackResult::Continue && output_path.available() < frames
+                {
+                    let block = match self.frames_per_buffer
+                    {
+                        0 => output_path.resampler.as_ref().unwrap().input_needed(frames).clamp(1, self.max_frames),
+                        n => n,
+                    };
+                    let (app_input, underflow) = match self.input_path
+                    {
+                        Some(ref mut path) => path.pull_app(block),
+                        None => (&[][..], false),
+                    };
+                    let flags = if underflow { StreamCallbackFlags::INPUT_UNDERFLOW } else { StreamCallbackFlags::empty() };
+
+                    result = callback(app_input, output_path.app_buffer(block), offset(done), flags);
+                    output_path.push_app(block);
+                    done += block;
+                }
+                output_path.pull_device(frames, device_output);
+            },
+            None => if let Some(ref mut input_path) = self.input_path
+            {
+                // Hand all complete blocks of resampled input to the application
+                while result == StreamCallbackResult::Continue
+                {
+                    let available = input_path.available();
+                    let block = match self.frames_per_buffer
+                    {
+                        0 if available > 0 => available.min(self.max_frames),
+                        n if n > 0 && available >= n => n,
+                        _ => break,
+                    };
+
+                    let (app_input, _) = input_path.pull_app(block);
+                    result = callback(app_input, &mut [], offset(done), StreamCallbackFlags::empty());
+                    done += block;
+                }
+            },
+        }
+
+        result
+    }
+
+    // Without resampling, the application buffers match those of the device one to one
+    fn process_direct<F>(&mut self,
+                         device_input: &[u8],
+                         device_output: &mut [u8],
+                         frames: usize,
+                         mut callback: F) -> StreamCallbackResult
+        where F: FnMut(&[u8], &mut [u8], Duration, StreamCallbackFlags) -> StreamCallbackResult
+    {
+        let no_offset = Duration::from_secs(0);
+        let app_input = match self.input_path
+        {
+            Some(ref mut path) => path.convert_input(device_input, frames),
+            None => device_input,
+        };
+
+        match self.output_path
+        {
+            Some(ref mut path) =>
+            {
+                let result = callback(app_input, path.app_buffer(frames), no_offset, StreamCallbackFlags::empty());
+                path.convert_output(frames, device_output);
+                result
+            },
+            None => callback(app_input, device_output, no_offset, StreamCallbackFlags::empty()),
+        }
+    }
+
+    /// Read and convert the given number of application frames, for use with blocking reads
+    ///
+    /// The read function is given a device buffer and the number of device frames to read into
+    /// it.
+    pub fn capture<R, E>(&mut self, frames: usize, mut read: R) -> Result<&[u8], E>
+        where R: FnMut(&mut [u8], usize) -> Result<(), E>, E: From<PaError>
+    {
+        let rates = self.rates;
+        let path = match self.input_path
+        {
+            Some(ref mut path) => path,
+            None => return Err(PaError::InternalError.into()),
+        };
+
+        match rates
+        {
+            None =>
+            {
+                let mut device = vec![0u8; path.direction.device_bytes(frames)];
+                read(&mut device, frames)?;
+                Ok(path.convert_input(&device, frames))
+            },
+            Some(rates) =>
+            {
+                while path.available() < frames
+                {
+                    let missing = (frames - path.available()) as f64 * rates.device / rates.app;
+                    let device_frames = missing.ceil() as usize + 1;
+
+                    let mut device = vec![0u8; path.direction.device_bytes(device_frames)];
+                    read(&mut device, device_frames)?;
+                    path.reserve(device_frames);
+                    path.push_device(&device, device_frames);
+                }
+                Ok(path.pull_app(frames).0)
+            },
+        }
+    }
+
+    /// Convert and write the given application frames, for use with blocking writes
+    ///
+    /// The write function is given a device buffer and the number of device frames it holds.
+    pub fn render<W, E>(&mut self, app: &[u8], frames: usize, mut write: W) -> Result<(), E>
+        where W: FnMut(&[u8], usize) -> Result<(), E>, E: From<PaError>
+    {
+        let resampling = self.rates.is_some();
+        let path = match self.output_path
+        {
+            Some(ref mut path) => path,
+            None => return Err(PaError::InternalError.into()),
+        };
+
+        path.app_buffer(frames).copy_from_slice(app);
+        if resampling
+        {
+            path.reserve(frames);
+            path.push_app(frames);
+            let device_frames = path.available();
+            let mut device = vec![0u8; path.direction.device_bytes(device_frames)];
+            path.pull_device(device_frames, &mut device);
+            write(&device, device_frames)
+        }
+        else
+        {
+            let mut device = vec![0u8; path.direction.device_bytes(frames)];
+            path.convert_output(frames, &mut device);
+            write(&device, frames)
+        }
+    }
+}
+
+// Routes the samples into the routed buffer when there is a routing, and returns the samples to
+// use from here on
+fn route<'a>(routing: &Option<Routing>, samples: &'a [f32], routed: &'a mut Vec<f32>, frames: usize) -> &'a [f32]
+{
+    match *routing
+    {
+        Some(ref routing) =>
+        {
+            let len = frames * routing.destinations() as usize;
+            grow(routed, len);
+            routing.apply(samples, &mut routed[.. len]);
+            &routed[.. len]
+        },
+        None => samples,
+    }
+}
+
+// The buffers are sized for the blocks of process(), so this only allocates for blocking reads
+// and writes of more frames
+fn grow(buffer: &mut Vec<f32>, len: usize)
+{
+    if buffer.len() < len { buffer.resize(len, 0.0); }
+}
+
+// Views the storage as a byte buffer of the given length. Using f32 for the storage keeps the
+// buffer aligned for every sample type. Like grow(), this only allocates outside of process().
+fn bytes_mut(storage: &mut Vec<f32>, len: usize) -> &mut [u8]
+{
+    let words = len.div_ceil(4);
+    if storage.len() < words { storage.resize(words, 0.0); }
+    unsafe { slice::from_raw_parts_mut(storage.as_mut_ptr() as *mut u8, len) }
+}
+
+// Fill a buffer of the given format with silence
+fn silence(format: SampleFormat, buffer: &mut [u8])
+{
+    let size = convert::format_size(format).unwrap();
+    let mut converter = Converter::new(Conversion { dither: Dither::None, clip: true });
+    for chunk in buffer.chunks_mut(SILENCE_SAMPLES * size)
+    {
+        converter.encode(&[0.0; SILENCE_SAMPLES][.. chunk.len() / size], format, chunk);
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Adapter, Direction, Rates};
+    use convert::Conversion;
+    use resample::ResampleQuality;
+    use routing::Routing;
+    use stream::{SampleFormat, StreamCallbackFlags, StreamCallbackResult};
+    use libc::c_void;
+    use std::ptr;
+
+    fn float(channels: u32) -> Direction
+    {
+        Direction
+        {
+            channels,
+            device_channels: channels,
+            app_format: SampleFormat::FLOAT32,
+            device_format: SampleFormat::FLOAT32,
+            routing: None,
+        }
+    }
+
+    #[test]
+    fn routed_duplex()
+    {
+        let input = Direction
+        {
+            device_channels: 4,
+            device_format: SampleFormat::INT16,
+            routing: Routing::input_channels(4, &[3, 1]),
+            .. float(2)
+        };
+        let output = Direction { device_channels: 2, routing: Some(Routing::mono_to_stereo()), .. float(1) };
+        let mut adapter = Adapter::new(Some(input), Some(output), Conversion::default(), 48_000.0, None, 0).unwrap();
+
+        let device_input = [0i16, 1000, 2000, 16_384];
+        let mut device_output = [0.0f32; 2];
+        unsafe
+        {
+            adapter.process(device_input.as_ptr() as *const c_void, device_output.as_mut_ptr() as *mut c_void, 1, |i, o, _, _|
+            {
+                assert_eq!(i.len(), 2 * 4);
+                assert_eq!(o.len(), 4);
+                assert_eq!(&i[.. 4], &0.5f32.to_ne_bytes());
+                o.copy_from_slice(&i[.. 4]);
+                StreamCallbackResult::Continue
+            });
+        }
+
+        assert_eq!(device_output, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn large_device_buffer_in_blocks()
+    {
+        let output = Direction { device_format: SampleFormat::INT16, .. float(1) };
+        let mut adapter = Adapter::new(None, Some(output), Conversion::default(), 48_000.0, None, 0).unwrap();
+
+        let mut device_output = [1234i16; 3000];
+        let mut blocks = Vec::new();
+        let result = unsafe
+        {
+            adapter.process(ptr::null(), device_output.as_mut_ptr() as *mut c_void, 3000, |_, o, offset, _|
+            {
+                for v in o.chunks_mut(4) { v.copy_from_slice(&0.5f32.to_ne_bytes()); }
+                blocks.push((o.len() / 4, offset));
+                if blocks.len() == 2 { StreamCallbackResult::Complete } else { StreamCallbackResult::Continue }
+            })
+        };
+
+        assert!(result == StreamCallbackResult::Complete);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].0, 1024);
+        assert!((blocks[1].1.as_secs_f64() - 1024.0 / 48_000.0).abs() < 1e-9);
+        assert!(device_output[.. 2048].iter().all(|&v| (v - 16_384).abs() <= 1));
+        assert!(device_output[2048 ..].iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn resampled_output()
+    {
+        let rates = Rates { app: 44_100.0, device: 48_000.0, quality: ResampleQuality::Linear };
+        let mut adapter = Adapter::new(None, Some(float(2)), Conversion::default(), 48_000.0, Some(rates), 0).unwrap();
+
+        let mut app_frames = 0;
+        let mut device = vec![0.0f32; 2 * 480];
+        for _ in 0 .. 100
+        {
+            let result = unsafe
+            {
+                adapter.process(ptr::null(), device.as_mut_ptr() as *mut c_void, 480, |_, output, _, _|
+                {
+                    for v in output.chunks_mut(4) { v.copy_from_slice(&0.5f32.to_ne_bytes()); }
+                    app_frames += output.len() / 8;
+                    StreamCallbackResult::Continue
+                })
+            };
+            assert!(result == StreamCallbackResult::Continue);
+        }
+
+        assert!(device.iter().all(|&v| (v - 0.5).abs() < 1e-6));
+        assert!((app_frames as isize - 44_100).abs() <= 2);
+    }
+
+    #[test]
+    fn resampled_duplex_in_blocks()
+    {
+        let rates = Rates { app: 48_000.0, device: 44_100.0, quality: ResampleQuality::WindowedSinc };
+        let mut adapter = Adapter::new(Some(float(1)), Some(float(1)), Conversion::default(), 44_100.0, Some(rates), 256).unwrap();
+
+        let input = vec![0.25f32; 441];
+        let mut output = vec![0.0f32; 441];
+        let mut underflows = 0;
+        for _ in 0 .. 50
+        {
+            unsafe
+            {
+                adapter.process(input.as_ptr() as *const c_void, output.as_mut_ptr() as *mut c_void, 441, |i, o, _, flags|
+                {
+                    assert_eq!(i.len(), 256 * 4);
+                    assert_eq!(o.len(), 256 * 4);
+                    o.copy_from_slice(i);
+                    if flags.contains(StreamCallbackFlags::INPUT_UNDERFLOW) { underflows += 1; }
+                    StreamCallbackResult::Continue
+                });
+            }
+        }
+
+        assert!(underflows <= 2, "{} underflows", underflows);
+        assert!(output.iter().all(|&v| (v - 0.25).abs() < 1e-2));
+    }
+
+    #[test]
+    fn callback_stays_within_reserve()
+    {
+        // Resampler::push asserts that it was given no more than was reserved at open
+        for &(app, device) in [(8000.0, 48_000.0), (48_000.0, 8000.0), (44_100.0, 48_000.0)].iter()
+        {
+            let rates = Rates { app, device, quality: ResampleQuality::WindowedSinc };
+            for &frames_per_buffer in [0, 64, 1000].iter()
+            {
+                let mut duplex = Adapter::new(Some(float(2)), Some(float(2)), Conversion::default(), device, Some(rates), frames_per_buffer).unwrap();
+                let mut input_only = Adapter::new(Some(float(2)), None, Conversion::default(), device, Some(rates), frames_per_buffer).unwrap();
+                for &frames in [1, 480, 5000, 17, 4096, 3].iter()
+                {
+                    let input = vec![0.25f32; 2 * frames];
+                    let mut output = vec![0.0f32; 2 * frames];
+                    unsafe
+                    {
+                        duplex.process(input.as_ptr() as *const c_void, output.as_mut_ptr() as *mut c_void, frames, |i, o, _, _|
+                        {
+                            o.copy_from_slice(i);
+                            StreamCallbackResult::Continue
+                        });
+                        input_only.process(input.as_ptr() as *const c_void, ptr::null_mut(), frames, |_, _, _, _| StreamCallbackResult::Continue);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,599 @@
+//! Traits over the audio system, so applications can replace PortAudio by a test double
+//!
+//! Code that is generic over `AudioHost` runs on the sound hardware with `PortAudio`, and
+//! without any hardware on a `MemoryHost`, which reads its inputs from and writes its outputs to
+//! buffers.
+
+use device::{self, DeviceIndex, DeviceInfo};
+use stream::{Stream, StreamParameters, StreamCallback, StreamCallbackFlags, StreamCallbackResult,
+             StreamFlags, StreamInfo, StreamTimeInfo, SampleType, FRAMES_PER_BUFFER_UNSPECIFIED};
+use convert::{Conversion, Converter, Dither};
+use pa::PaError;
+use error::{Endpoint, Error, Operation};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use std::{mem, ptr, slice};
+
+/// A device of an audio host
+pub trait AudioDevice
+{
+    /// The index with which the device is passed in StreamParameters
+    fn index(&self) -> DeviceIndex;
+
+    /// Information about the device
+    fn info(&self) -> &DeviceInfo;
+}
+
+/// A stream opened on an audio host
+///
+/// The methods behave like those of Stream, which implements this trait.
+pub trait AudioStream<I, O>
+{
+    /// Starts the stream
+    fn start(&self) -> Result<(), Error>;
+
+    /// Stops the stream after all audio has finished playing
+    fn stop(&self) -> Result<(), Error>;
+
+    /// Stops the stream immediately
+    fn abort(&self) -> Result<(), Error>;
+
+    /// Returns wether the stream is stopped
+    fn is_stopped(&self) -> Result<bool, Error>;
+
+    /// Returns wether the stream is active
+    fn is_active(&self) -> Result<bool, Error>;
+
+    /// Reads the requested number of frames from a blocking stream
+    fn read(&self, frames: u32) -> Result<Vec<I>, Error>;
+
+    /// Writes the given buffer to a blocking stream
+    fn write(&self, buffer: &[O]) -> Result<(), Error>;
+
+    /// The current timestamp of the stream
+    fn time(&self) -> Duration;
+
+    /// The actual latencies and sample rate
+    fn info(&self) -> Option<StreamInfo>;
+}
+
+/// An audio system that provides devices and opens streams on them
+pub trait AudioHost
+{
+    /// The type of the devices of this host
+    type Device: AudioDevice;
+
+    /// All devices of the host
+    fn devices(&self) -> Result<Vec<Self::Device>, PaError>;
+
+    /// The device with the given index, if it exists
+    fn device(&self, index: DeviceIndex) -> Option<Self::Device>;
+
+    /// The default input device, if there is one
+    fn default_input_device(&self) -> Option<Self::Device>;
+
+    /// The default output device, if there is one
+    fn default_output_device(&self) -> Option<Self::Device>;
+
+    /// Opens a stream, with the same arguments as Stream::open()
+    fn open_stream<'a, I: SampleType + 'a, O: SampleType + 'a>(&self,
+                                                               input: Option<StreamParameters<I>>,
+                                                               output: Option<StreamParameters<O>>,
+                                                               sample_rate: f64,
+                                                               frames_per_buffer: u64,
+                                                               flags: StreamFlags,
+                                                               callback: Option<Box<StreamCallback<'a, I, O>>>)
+                                                              -> Result<Box<dyn AudioStream<I, O> + 'a>, Error>;
+}
+
+/// A device together with its index, as returned by the hosts of this module
+#[derive(Clone)]
+pub struct Device
+{
+    /// Index of the device
+    pub index: DeviceIndex,
+
+    /// Information about the device
+    pub info: DeviceInfo,
+}
+
+impl AudioDevice for Device
+{
+    fn index(&self) -> DeviceIndex { self.index }
+    fn info(&self) -> &DeviceInfo { &self.info }
+}
+
+/// The host backed by PortAudio
+///
+/// PortAudio must be initialized while the host is in use.
+#[derive(Copy, Clone, Default)]
+pub struct PortAudio;
+
+impl PortAudio
+{
+    fn get(index: Option<DeviceIndex>) -> Option<Device>
+    {
+        index.and_then(|index| device::get_info(index).map(|info| Device { index, info }))
+    }
+}
+
+impl AudioHost for PortAudio
+{
+    type Device = Device;
+
+    fn devices(&self) -> Result<Vec<Device>, PaError>
+    {
+        let count = device::get_count()?;
+        Ok((0..count).filter_map(|index| PortAudio::get(Some(index))).collect())
+    }
+
+    fn device(&self, index: DeviceIndex) -> Option<Device> { PortAudio::get(Some(index)) }
+    fn default_input_device(&self) -> Option<Device> { PortAudio::get(device::get_default_input_index()) }
+    fn default_output_device(&self) -> Option<Device> { PortAudio::get(device::get_default_output_index()) }
+
+    fn open_stream<'a, I: SampleType + 'a, O: SampleType + 'a>(&self,
+                                                               input: Option<StreamParameters<I>>,
+                                                               output: Option<StreamParameters<O>>,
+                                                               sample_rate: f64,
+                                                               frames_per_buffer: u64,
+                                                               flags: StreamFlags,
+                                                               callback: Option<Box<StreamCallback<'a, I, O>>>)
+                                                              -> Result<Box<dyn AudioStream<I, O> + 'a>, Error>
+    {
+        let stream = Stream::open(input, output, sample_rate, frames_per_buffer, flags, callback)?;
+        Ok(Box::new(stream))
+    }
+}
+
+impl<'a, I: SampleType, O: SampleType> AudioStream<I, O> for Stream<'a, I, O>
+{
+    fn start(&self) -> Result<(), Error> { Stream::start(self) }
+    fn stop(&self) -> Result<(), Error> { Stream::stop(self) }
+    fn abort(&self) -> Result<(), Error> { Stream::abort(self) }
+    fn is_stopped(&self) -> Result<bool, Error> { Stream::is_stopped(self) }
+    fn is_active(&self) -> Result<bool, Error> { Stream::is_active(self) }
+    fn read(&self, frames: u32) -> Result<Vec<I>, Error> { Stream::read(self, frames) }
+    fn write(&self, buffer: &[O]) -> Result<(), Error> { Stream::write(self, buffer) }
+    fn time(&self) -> Duration { Stream::time(self) }
+    fn info(&self) -> Option<StreamInfo> { Stream::info(self) }
+}
+
+/// Frames per callback of a memory stream opened with FRAMES_PER_BUFFER_UNSPECIFIED
+const MEMORY_FRAMES_PER_BUFFER: u64 = 256;
+
+struct MemoryState
+{
+    devices: Vec<DeviceInfo>,
+    default_input: Option<DeviceIndex>,
+    default_output: Option<DeviceIndex>,
+    inputs: Vec<VecDeque<f32>>,
+    outputs: Vec<Vec<f32>>,
+    run_length: Option<u64>,
+}
+
+/// A host without hardware, whose devices are buffers
+///
+/// Input devices play back the samples given to push_input(), and output devices record the
+/// samples that can be collected with take_output(). Samples are interleaved 32 bit floats.
+///
+/// Starting a callback stream runs its callback right away, until the callback completes, the
+/// input runs out or the run length has passed. The stream is then inactive until it is stopped.
+/// Blocking streams read silence when the input has run out.
+///
+/// Clones share the same devices and buffers.
+#[derive(Clone)]
+pub struct MemoryHost
+{
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl Default for MemoryHost
+{
+    fn default() -> MemoryHost { MemoryHost::new() }
+}
+
+impl MemoryHost
+{
+    /// Create a host without any devices
+    pub fn new() -> MemoryHost
+    {
+        MemoryHost
+        {
+            state: Arc::new(Mutex::new(MemoryState
+            {
+                devices: Vec::new(),
+                default_input: None,
+                default_output: None,
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                run_length: None,
+            })),
+        }
+    }
+
+    /// Add a device and return its index
+    ///
+    /// The first devices with inputs and with outputs become the defaults.
+    pub fn add_device(&self, name: &str, max_input_channels: u32, max_output_channels: u32, default_sample_rate: f64) -> DeviceIndex
+    {
+        let mut state = self.lock();
+        let index = state.devices.len() as DeviceIndex;
+        state.devices.push(DeviceInfo
+        {
+            name: name.to_string(),
+            host_api: 0,
+            max_input_channels,
+            max_output_channels,
+            default_low_input_latency: Duration::from_secs(0),
+            default_low_output_latency: Duration::from_secs(0),
+            default_high_input_latency: Duration::from_secs(0),
+            default_high_output_latency: Duration::from_secs(0),
+            default_sample_rate,
+        });
+        state.inputs.push(VecDeque::new());
+        state.outputs.push(Vec::new());
+        if max_input_channels > 0 && state.default_input.is_none() { state.default_input = Some(index) }
+        if max_output_channels > 0 && state.default_output.is_none() { state.default_output = Some(index) }
+        index
+    }
+
+    /// Set the default input device
+    pub fn set_default_input(&self, device: Option<DeviceIndex>)
+    {
+        self.lock().default_input = device;
+    }
+
+    /// Set the default output device
+    pub fn set_default_output(&self, device: Option<DeviceIndex>)
+    {
+        self.lock().default_output = device;
+    }
+
+    /// Queue interleaved samples on the input of a device
+    ///
+    /// ## Panics
+    /// When the device does not exist.
+    pub fn push_input(&self, device: DeviceIndex, samples: &[f32])
+    {
+        self.lock().inputs[device as usize].extend(samples.iter().cloned());
+    }
+
+    /// Take the interleaved samples written to the output of a device so far
+    ///
+    /// ## Panics
+    /// When the device does not exist.
+    pub fn take_output(&self, device: DeviceIndex) -> Vec<f32>
+    {
+        mem::take(&mut self.lock().outputs[device as usize])
+    }
+
+    /// Set the number of frames a callback stream processes when started, or None to process
+    /// one second of audio. A stream with inputs stops earlier when its input runs out.
+    pub fn set_run_length(&self, frames: Option<u64>)
+    {
+        self.lock().run_length = frames;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryState>
+    {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get(&self, index: Option<DeviceIndex>) -> Option<Device>
+    {
+        let state = self.lock();
+        index.and_then(|index| state.devices.get(index as usize).map(|info| Device { index, info: info.clone() }))
+    }
+
+    fn check<T>(&self, parameters: &Option<StreamParameters<T>>, input: bool) -> Result<Option<(DeviceIndex, u32)>, PaError>
+    {
+        let parameters = match *parameters
+        {
+            Some(ref p) => p,
+            None => return Ok(None),
+        };
+        let state = self.lock();
+        let info = state.devices.get(parameters.device as usize).ok_or(PaError::InvalidDevice)?;
+        let max = if input { info.max_input_channels } else { info.max_output_channels };
+        if parameters.channel_count == 0 || parameters.channel_count > max
+        {
+            return Err(PaError::InvalidChannelCount)
+        }
+        Ok(Some((parameters.device, parameters.channel_count)))
+    }
+}
+
+impl AudioHost for MemoryHost
+{
+    type Device = Device;
+
+    fn devices(&self) -> Result<Vec<Device>, PaError>
+    {
+        let count = self.lock().devices.len() as DeviceIndex;
+        Ok((0..count).filter_map(|index| self.get(Some(index))).collect())
+    }
+
+    fn device(&self, index: DeviceIndex) -> Option<Device> { self.get(Some(index)) }
+    fn default_input_device(&self) -> Option<Device> { let index = self.lock().default_input; self.get(index) }
+    fn default_output_device(&self) -> Option<Device> { let index = self.lock().default_output; self.get(index) }
+
+    fn open_stream<'a, I: SampleType + 'a, O: SampleType + 'a>(&self,
+                                                               input: Option<StreamParameters<I>>,
+                                                               output: Option<StreamParameters<O>>,
+                                                               sample_rate: f64,
+                                                               frames_per_buffer: u64,
+                                                               _flags: StreamFlags,
+                                                               callback: Option<Box<StreamCallback<'a, I, O>>>)
+                                                              -> Result<Box<dyn AudioStream<I, O> + 'a>, Error>
+    {
+        let endpoint = |(device, channel_count)| Endpoint { device: Some(device), channel_count };
+        let operation = Operation::Open
+        {
+            input: input.as_ref().map(|p| endpoint((p.device, p.channel_count))),
+            output: output.as_ref().map(|p| endpoint((p.device, p.channel_count))),
+            sample_rate,
+            frames_per_buffer,
+        };
+        let fail = |code| Error::new(code, operation.clone());
+
+        let input = self.check(&input, true).map_err(fail)?;
+        let output = self.check(&output, false).map_err(fail)?;
+        if input.is_none() && output.is_none() { return Err(fail(PaError::BadIODeviceCombination)) }
+        if sample_rate <= 0.0 { return Err(fail(PaError::InvalidSampleRate)) }
+
+        Ok(Box::new(MemoryStream
+        {
+            host: self.clone(),
+            input,
+            output,
+            sample_rate,
+            frames_per_buffer: if frames_per_buffer == FRAMES_PER_BUFFER_UNSPECIFIED { MEMORY_FRAMES_PER_BUFFER } else { frames_per_buffer },
+            callback: RefCell::new(callback),
+            converter: RefCell::new(Converter::new(Conversion { dither: Dither::None, clip: true })),
+            stopped: Cell::new(true),
+            active: Cell::new(false),
+            frames: Cell::new(0),
+        }))
+    }
+}
+
+struct MemoryStream<'a, I, O>
+{
+    host: MemoryHost,
+    input: Option<(DeviceIndex, u32)>,
+    output: Option<(DeviceIndex, u32)>,
+    sample_rate: f64,
+    frames_per_buffer: u64,
+    callback: RefCell<Option<Box<StreamCallback<'a, I, O>>>>,
+    converter: RefCell<Converter>,
+    stopped: Cell<bool>,
+    active: Cell<bool>,
+    frames: Cell<u64>,
+}
+
+impl<'a, I: SampleType, O: SampleType> MemoryStream<'a, I, O>
+{
+    fn channels(device: Option<(DeviceIndex, u32)>) -> usize
+    {
+        device.map_or(0, |(_, channels)| channels as usize)
+    }
+
+    /// Take samples from the input device, padded with silence when it has run out. Returns
+    /// wether any input was left.
+    fn capture(&self, frames: usize, storage: &mut Vec<f32>) -> bool
+    {
+        storage.clear();
+        let (device, channels) = match self.input { Some(d) => d, None => return false };
+        let mut state = self.host.lock();
+        let queue = &mut state.inputs[device as usize];
+        let available = !queue.is_empty();
+        let take = queue.len().min(frames * channels as usize);
+        storage.extend(queue.drain(..take));
+        storage.resize(frames * channels as usize, 0.0);
+        available
+    }
+
+    fn render(&self, samples: &[f32])
+    {
+        if let Some((device, _)) = self.output
+        {
+            self.host.lock().outputs[device as usize].extend_from_slice(samples);
+        }
+    }
+
+    fn run(&self, callback: &mut StreamCallback<'a, I, O>)
+    {
+        let inputs = MemoryStream::<I, O>::channels(self.input);
+        let outputs = MemoryStream::<I, O>::channels(self.output);
+        let limit = self.host.lock().run_length.unwrap_or(self.sample_rate as u64);
+        let mut converter = self.converter.borrow_mut();
+        let (mut captured, mut rendered) = (Vec::new(), Vec::new());
+        let (mut app_in, mut app_out) = (Vec::new(), Vec::new());
+        let mut processed = 0;
+
+        while processed < limit
+        {
+            let frames = (limit - processed).min(self.frames_per_buffer) as usize;
+            if !self.capture(frames, &mut captured) && self.input.is_some() { break }
+
+            let input_buffer: &mut [I] = samples_mut(&mut app_in, frames * inputs);
+            converter.convert(&captured, input_buffer);
+            let output_buffer: &mut [O] = samples_mut(&mut app_out, frames * outputs);
+
+            let time = self.time();
+            let time_info = StreamTimeInfo { input_adc_time: time, current_time: time, output_dac_time: time };
+            let result = callback(input_buffer, output_buffer, time_info, StreamCallbackFlags::empty());
+
+            rendered.clear();
+            rendered.resize(frames * outputs, 0.0);
+            converter.convert(&*output_buffer, &mut rendered[..]);
+            self.render(&rendered);
+
+            processed += frames as u64;
+            self.frames.set(self.frames.get() + frames as u64);
+            if result != StreamCallbackResult::Continue { break }
+        }
+    }
+}
+
+impl<'a, I: SampleType, O: SampleType> AudioStream<I, O> for MemoryStream<'a, I, O>
+{
+    fn start(&self) -> Result<(), Error>
+    {
+        if !self.stopped.get() { return Err(Error::new(PaError::StreamIsNotStopped, Operation::Start)) }
+        self.stopped.set(false);
+        self.active.set(true);
+
+        if let Some(ref mut callback) = *self.callback.borrow_mut()
+        {
+            self.run(&mut **callback);
+            self.active.set(false);
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), Error>
+    {
+        if self.stopped.get() { return Err(Error::new(PaError::StreamIsStopped, Operation::Stop)) }
+        self.stopped.set(true);
+        self.active.set(false);
+        Ok(())
+    }
+
+    fn abort(&self) -> Result<(), Error>
+    {
+        self.stop().map_err(|e| Error::new(e.code(), Operation::Abort))
+    }
+
+    fn is_stopped(&self) -> Result<bool, Error> { Ok(self.stopped.get()) }
+    fn is_active(&self) -> Result<bool, Error> { Ok(self.active.get()) }
+
+    fn read(&self, frames: u32) -> Result<Vec<I>, Error>
+    {
+        let fail = |code| Err(Error::new(code, Operation::Read { frames }));
+        let inputs = MemoryStream::<I, O>::channels(self.input);
+        if inputs == 0 { return fail(PaError::CanNotReadFromAnOutputOnlyStream) }
+        if self.callback.borrow().is_some() { return fail(PaError::CanNotReadFromACallbackStream) }
+        if !self.active.get() { return fail(PaError::StreamIsStopped) }
+
+        let mut captured = Vec::new();
+        self.capture(frames as usize, &mut captured);
+        let mut storage = Vec::new();
+        let samples: &mut [I] = samples_mut(&mut storage, captured.len());
+        self.converter.borrow_mut().convert(&captured, samples);
+        self.frames.set(self.frames.get() + frames as u64);
+
+        let mut buffer = Vec::with_capacity(samples.len());
+        unsafe
+        {
+            ptr::copy_nonoverlapping(samples.as_ptr(), buffer.as_mut_ptr(), samples.len());
+            buffer.set_len(samples.len());
+        }
+        Ok(buffer)
+    }
+
+    fn write(&self, buffer: &[O]) -> Result<(), Error>
+    {
+        let outputs = MemoryStream::<I, O>::channels(self.output);
+        let fail = |code| Err(Error::new(code, Operation::Write { frames: (buffer.len() / outputs.max(1)) as u32 }));
+        if outputs == 0 { return fail(PaError::CanNotWriteToAnInputOnlyStream) }
+        if buffer.len() % outputs != 0 { return fail(PaError::BadBufferPtr) }
+        if self.callback.borrow().is_some() { return fail(PaError::CanNotWriteToACallbackStream) }
+        if !self.active.get() { return fail(PaError::StreamIsStopped) }
+
+        let mut rendered = vec![0.0; buffer.len()];
+        self.converter.borrow_mut().convert(buffer, &mut rendered[..]);
+        self.render(&rendered);
+        self.frames.set(self.frames.get() + (buffer.len() / outputs) as u64);
+        Ok(())
+    }
+
+    fn time(&self) -> Duration
+    {
+        Duration::from_secs_f64(self.frames.get() as f64 / self.sample_rate)
+    }
+
+    fn info(&self) -> Option<StreamInfo>
+    {
+        Some(StreamInfo
+        {
+            input_latency: Duration::from_secs(0),
+            output_latency: Duration::from_secs(0),
+            sample_rate: self.sample_rate,
+        })
+    }
+}
+
+/// Zeroed samples backed by the given storage
+///
+/// The storage consists of f32 words, which keeps it aligned for every sample type.
+fn samples_mut<T: SampleType>(storage: &mut Vec<f32>, len: usize) -> &mut [T]
+{
+    let words = (len * mem::size_of::<T>()).div_ceil(4);
+    storage.clear();
+    storage.resize(words, 0.0);
+    unsafe { slice::from_raw_parts_mut(storage.as_mut_ptr() as *mut T, len) }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{AudioHost, AudioDevice, MemoryHost};
+    use stream::{StreamParameters, StreamCallbackResult, StreamFlags};
+    use pa::PaError;
+    use std::time::Duration;
+
+    fn parameters<T>(device: u32, channel_count: u32, data: T) -> StreamParameters<T>
+    {
+        StreamParameters { device, channel_count, suggested_latency: Duration::from_secs(0), data }
+    }
+
+    #[test]
+    fn callback_through_buffers()
+    {
+        let host = MemoryHost::new();
+        let mic = host.add_device("mic", 1, 0, 48000.0);
+        let speakers = host.add_device("speakers", 0, 2, 48000.0);
+        assert_eq!(host.default_input_device().unwrap().index(), mic);
+        assert_eq!(host.default_output_device().unwrap().info().name, "speakers");
+        assert_eq!(host.devices().unwrap().len(), 2);
+
+        host.push_input(mic, &[0.5, -0.25, 0.125]);
+        let stream = host.open_stream(Some(parameters(mic, 1, 0i16)), Some(parameters(speakers, 2, 0.0f32)),
+                                      48000.0, 2, StreamFlags::empty(),
+                                      Some(Box::new(|input: &[i16], output: &mut [f32], _, _|
+                                      {
+                                          for (frame, &v) in output.chunks_mut(2).zip(input) { frame[0] = v as f32 / 32768.0; frame[1] = 1.0; }
+                                          StreamCallbackResult::Continue
+                                      }))).unwrap();
+
+        stream.start().unwrap();
+        assert!(!stream.is_active().unwrap());
+        assert_eq!(host.take_output(speakers), vec![0.5, 1.0, -0.25, 1.0, 0.125, 1.0, 0.0, 1.0]);
+        assert_eq!(stream.time(), Duration::from_secs_f64(4.0 / 48000.0));
+        assert_eq!(stream.read(1).unwrap_err(), PaError::CanNotReadFromACallbackStream);
+        stream.stop().unwrap();
+    }
+
+    #[test]
+    fn blocking_through_buffers()
+    {
+        let host = MemoryHost::new();
+        let device = host.add_device("duplex", 2, 2, 44100.0);
+        host.push_input(device, &[0.25, 0.5]);
+
+        let stream = host.open_stream(Some(parameters(device, 2, 0.0f32)), Some(parameters(device, 2, 0.0f32)),
+                                      44100.0, 0, StreamFlags::empty(), None).unwrap();
+        assert_eq!(stream.write(&[0.0, 0.0]).unwrap_err(), PaError::StreamIsStopped);
+        stream.start().unwrap();
+        assert_eq!(stream.read(2).unwrap(), vec![0.25, 0.5, 0.0, 0.0]);
+        stream.write(&[0.75, -0.75]).unwrap();
+        assert_eq!(host.take_output(device), vec![0.75, -0.75]);
+        assert_eq!(stream.write(&[0.0]).unwrap_err(), PaError::BadBufferPtr);
+
+        assert_eq!(host.open_stream::<f32, f32>(None, Some(parameters(device, 3, 0.0)), 44100.0, 0, StreamFlags::empty(), None).err().map(|e| e.code()),
+                   Some(PaError::InvalidChannelCount));
+    }
+}
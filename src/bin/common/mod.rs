@@ -0,0 +1,95 @@
+//! What the command line tools have in common
+
+use portaudio::convert::{Conversion, Converter, Dither};
+use portaudio::stream::{SampleFormat, SampleType};
+use std::{fmt, process};
+use std::error::Error;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub type Result<T> = ::std::result::Result<T, Box<dyn Error>>;
+
+// Frames per read or write
+pub const CHUNK_FRAMES: usize = 1024;
+
+// Makes read_full() return early, such as when set by a signal handler
+pub static STOP: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub struct UsageError(String);
+
+impl fmt::Display for UsageError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+pub fn usage<T>(message: &str) -> Result<T>
+{
+    Err(Box::new(UsageError(message.to_string())))
+}
+
+// Report the error and exit, showing the usage after a usage error
+pub fn exit(tool: &str, usage: &str, error: Box<dyn Error>) -> !
+{
+    eprintln!("{}: {}", tool, error);
+    if error.is::<UsageError>()
+    {
+        eprint!("\n{}", usage);
+        process::exit(2);
+    }
+    process::exit(1);
+}
+
+pub fn number<T: ::std::str::FromStr>(name: &str, value: &str) -> Result<T>
+{
+    match value.parse()
+    {
+        Ok(n) => Ok(n),
+        Err(_) => usage(&format!("invalid value {} for {}", value, name)),
+    }
+}
+
+pub fn parse_format(name: &str) -> Result<SampleFormat>
+{
+    match name
+    {
+        "f32" => Ok(SampleFormat::FLOAT32),
+        "i32" => Ok(SampleFormat::INT32),
+        "i24" => Ok(SampleFormat::INT24),
+        "i16" => Ok(SampleFormat::INT16),
+        "i8" => Ok(SampleFormat::INT8),
+        "u8" => Ok(SampleFormat::UINT8),
+        _ => usage(&format!("unknown sample format {}", name)),
+    }
+}
+
+// The given number of silent samples. Unsigned formats are silent halfway their range, so let
+// the converter find the value.
+pub fn silence<T: SampleType + Copy + Default>(samples: usize) -> Vec<T>
+{
+    let mut silence = vec![T::default(); samples];
+    Converter::new(Conversion { dither: Dither::None, clip: true }).convert(&vec![0.0f32; samples], &mut silence);
+    silence
+}
+
+// Read until the buffer is full, the end of the input, or STOP is set
+pub fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize>
+{
+    let mut filled = 0;
+    while filled < buffer.len() && !STOP.load(Ordering::SeqCst)
+    {
+        match reader.read(&mut buffer[filled ..])
+        {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
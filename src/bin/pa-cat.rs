@@ -0,0 +1,260 @@
+//! Pipes raw PCM between stdin or stdout and a device, like arecord and aplay
+//!
+//! `pa-cat --rec | ./dsp | pa-cat --play` records, processes and plays interleaved samples in
+//! native byte order. Underflows and overflows are counted and reported instead of ending the
+//! stream, and the frames lost to an overflow are recorded as silence. SIGINT and SIGTERM stop
+//! the stream and exit, as does a closed pipe, which shows up as an EPIPE error because Rust
+//! ignores SIGPIPE. Built with the `cli` feature.
+
+extern crate libc;
+extern crate portaudio_rs as portaudio;
+
+mod common;
+
+use common::{number, parse_format, read_full, silence, usage, Result, CHUNK_FRAMES, STOP};
+use portaudio::{convert, device};
+use portaudio::spec::{DeviceSpec, Direction};
+use portaudio::stream::{SampleFormat, SampleType, Stream, StreamFlags, StreamParameters, FRAMES_PER_BUFFER_UNSPECIFIED};
+use std::{env, mem};
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+const USAGE: &str = "\
+usage: pa-cat (--play | --rec) [options]
+
+  -p, --play               play the samples read from stdin
+  -R, --rec                write the recorded samples to stdout
+
+options:
+  -d, --device <spec>      the device, such as default, index:3, name~USB or alsa:hw:1,0
+  -r, --rate <hz>          sample rate (default: 44100)
+  -c, --channels <n>       number of channels (default: 2)
+  -f, --format <format>    f32, i32, i16, i8 or u8 (default: i16)
+  -l, --latency <secs>     suggested latency (default: the high latency of the device)
+  -q, --quiet              do not report underflows and overflows
+";
+
+struct Options
+{
+    play: bool,
+    device: DeviceSpec,
+    rate: f64,
+    channels: u32,
+    format: SampleFormat,
+    latency: Option<Duration>,
+    quiet: bool,
+}
+
+impl Options
+{
+    fn parse(args: &[String]) -> Result<Options>
+    {
+        let mut direction = None;
+        let mut options = Options
+        {
+            play: false,
+            device: DeviceSpec::Default(None),
+            rate: 44100.0,
+            channels: 2,
+            format: SampleFormat::INT16,
+            latency: None,
+            quiet: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next()
+        {
+            let mut value = |name: &str| match args.next()
+            {
+                Some(value) => Ok(value.clone()),
+                None => usage(&format!("{} needs a value", name)),
+            };
+            match arg.as_str()
+            {
+                "-p" | "--play" => direction = Some(true),
+                "-R" | "--rec" => direction = Some(false),
+                "-d" | "--device" => options.device = value(arg)?.parse()?,
+                "-r" | "--rate" => options.rate = number(arg, &value(arg)?)?,
+                "-c" | "--channels" => options.channels = number(arg, &value(arg)?)?,
+                "-f" | "--format" => options.format = parse_format(&value(arg)?)?,
+                "-l" | "--latency" =>
+                {
+                    let secs: f64 = number(arg, &value(arg)?)?;
+                    options.latency = Some(Duration::try_from_secs_f64(secs).or_else(|_| usage("the latency can not be negative"))?);
+                },
+                "-q" | "--quiet" => options.quiet = true,
+                _ => return usage(&format!("unexpected argument {}", arg)),
+            }
+        }
+        options.play = match direction
+        {
+            Some(play) => play,
+            None => return usage("either --play or --rec is needed"),
+        };
+        if options.channels == 0 { return usage("at least one channel is needed") }
+        if options.format == SampleFormat::INT24 { return usage("the samples can be f32, i32, i16, i8 or u8") }
+        Ok(options)
+    }
+}
+
+fn main()
+{
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help")
+    {
+        print!("{}", USAGE);
+        return;
+    }
+    if let Err(e) = run(&args)
+    {
+        common::exit("pa-cat", USAGE, e);
+    }
+}
+
+fn run(args: &[String]) -> Result<()>
+{
+    let options = Options::parse(args)?;
+    install_signal_handlers();
+
+    portaudio::initialize()?;
+    let result = match options.format
+    {
+        SampleFormat::FLOAT32 => pipe::<f32>(&options),
+        SampleFormat::INT32 => pipe::<i32>(&options),
+        SampleFormat::INT16 => pipe::<i16>(&options),
+        SampleFormat::INT8 => pipe::<i8>(&options),
+        _ => pipe::<u8>(&options),
+    };
+    portaudio::terminate()?;
+    result
+}
+
+fn pipe<T: SampleType + Copy + Default>(options: &Options) -> Result<()>
+{
+    let direction = if options.play { Direction::Output } else { Direction::Input };
+    let device = options.device.resolve(direction)?.index;
+    let info = device::get_info(device).ok_or("the device disappeared")?;
+    let default_latency = if options.play { info.default_high_output_latency } else { info.default_high_input_latency };
+    let parameters = StreamParameters
+    {
+        device,
+        channel_count: options.channels,
+        suggested_latency: options.latency.unwrap_or(default_latency),
+        data: T::default(),
+    };
+
+    let (input, output) = if options.play { (None, Some(parameters)) } else { (Some(parameters), None) };
+    let stream: Stream<T, T> = Stream::open(input, output, options.rate, FRAMES_PER_BUFFER_UNSPECIFIED, StreamFlags::empty(), None)?;
+    let mut xruns = Xruns { count: 0, quiet: options.quiet };
+
+    stream.start()?;
+    let result = if options.play { play(&stream, options.channels, &mut xruns) } else { record(&stream, options.channels, &mut xruns) };
+    let finished = match result
+    {
+        // Let the buffered output play after the end of the input, but not after a signal
+        Ok(()) if options.play && !STOP.load(Ordering::SeqCst) => stream.stop(),
+        _ => stream.abort(),
+    };
+    if xruns.count > 0 && !options.quiet
+    {
+        eprintln!("pa-cat: {} {}", xruns.count, if options.play { "underflows" } else { "overflows" });
+    }
+    result?;
+    finished?;
+    Ok(())
+}
+
+// Counts the underflows and overflows, reporting the first
+struct Xruns
+{
+    count: u64,
+    quiet: bool,
+}
+
+impl Xruns
+{
+    fn check(&mut self, result: ::std::result::Result<(), portaudio::Error>) -> Result<()>
+    {
+        match result
+        {
+            Err(ref e) if e.is_transient() =>
+            {
+                if self.count == 0 && !self.quiet { eprintln!("pa-cat: {}", e) }
+                self.count += 1;
+                Ok(())
+            },
+            result => Ok(result?),
+        }
+    }
+}
+
+fn play<T: SampleType + Copy + Default>(stream: &Stream<T, T>, channels: u32, xruns: &mut Xruns) -> Result<()>
+{
+    let frame_bytes = channels as usize * mem::size_of::<T>();
+    let mut buffer = vec![T::default(); CHUNK_FRAMES * channels as usize];
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    loop
+    {
+        let bytes = read_full(&mut stdin, convert::as_bytes_mut(&mut buffer))?;
+        // A partial frame at the end of the input is dropped
+        let samples = bytes / frame_bytes * channels as usize;
+        if samples > 0 { xruns.check(stream.write(&buffer[.. samples]))? }
+        if bytes < buffer.len() * mem::size_of::<T>() || STOP.load(Ordering::SeqCst) { return Ok(()) }
+    }
+}
+
+fn record<T: SampleType + Copy + Default>(stream: &Stream<T, T>, channels: u32, xruns: &mut Xruns) -> Result<()>
+{
+    let silence = silence::<T>(CHUNK_FRAMES * channels as usize);
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    while !STOP.load(Ordering::SeqCst)
+    {
+        let read = stream.read(CHUNK_FRAMES as u32);
+        let samples = match read
+        {
+            Ok(ref samples) => &samples[..],
+            // Write silence in place of the frames lost to an overflow, so what follows keeps its
+            // place in time
+            Err(e) => { xruns.check(Err(e))?; &silence[..] },
+        };
+        match stdout.write_all(convert::as_bytes(samples)).and_then(|_| stdout.flush())
+        {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(_signal: libc::c_int)
+{
+    STOP.store(true, Ordering::SeqCst);
+}
+
+// Without SA_RESTART, a blocking read of stdin returns EINTR so the signal is noticed
+#[cfg(unix)]
+fn install_signal_handlers()
+{
+    unsafe
+    {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        for &signal in &[libc::SIGINT, libc::SIGTERM]
+        {
+            libc::sigaction(signal, &action, ::std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers()
+{
+    unsafe { libc::signal(libc::SIGINT, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t); }
+}
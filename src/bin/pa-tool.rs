@@ -0,0 +1,381 @@
+//! Command line tool to list, probe, play and record with PortAudio
+//!
+//! Run `pa-tool help` for the usage. Built with the `cli` feature.
+
+extern crate portaudio_rs as portaudio;
+
+mod common;
+
+use common::{number, parse_format, read_full, silence, usage, Result, CHUNK_FRAMES};
+use portaudio::{convert, device, hostapi, wav};
+use portaudio::device::DeviceInfo;
+use portaudio::diagnostics::{self, PROBE_SAMPLE_RATES};
+use portaudio::spec::{DeviceSpec, Direction};
+use portaudio::stream::{SampleFormat, SampleType, Stream, StreamFlags, StreamParameters, FRAMES_PER_BUFFER_UNSPECIFIED};
+use std::env;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+const USAGE: &str = "\
+usage: pa-tool <command> [options]
+
+commands:
+  devices                  list the host APIs and devices
+  probe                    show the sample formats and rates the devices accept
+  play <file>              play a WAV file, or a raw file with --raw or another extension
+  rec <file>               record to a WAV file, or a raw file, for --duration seconds
+  tone                     play a sine on each channel in turn
+  help                     show this text
+
+options:
+  -d, --device <spec>      the device, such as default, index:3, name~USB or alsa:hw:1,0
+  -r, --rate <hz>          sample rate (raw files and rec: 44100, tone: the device default)
+  -c, --channels <n>       number of channels (raw files and rec: 2, tone: all of the device)
+  -f, --format <format>    f32, i32, i24 (WAV only), i16, i8 or u8 (default: i16)
+  -t, --duration <secs>    recording time, or the time per channel of tone (default: 1)
+      --frequency <hz>     frequency of tone (default: 440)
+      --raw                read or write raw interleaved samples in native byte order
+";
+
+struct Options
+{
+    file: Option<String>,
+    device: DeviceSpec,
+    device_given: bool,
+    rate: Option<f64>,
+    channels: Option<u32>,
+    format: SampleFormat,
+    duration: f64,
+    frequency: f32,
+    raw: bool,
+}
+
+impl Options
+{
+    fn parse(args: &[String]) -> Result<Options>
+    {
+        let mut options = Options
+        {
+            file: None,
+            device: DeviceSpec::Default(None),
+            device_given: false,
+            rate: None,
+            channels: None,
+            format: SampleFormat::INT16,
+            duration: 1.0,
+            frequency: 440.0,
+            raw: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next()
+        {
+            let mut value = |name: &str| match args.next()
+            {
+                Some(value) => Ok(value.clone()),
+                None => usage(&format!("{} needs a value", name)),
+            };
+            match arg.as_str()
+            {
+                "-d" | "--device" => { options.device = value(arg)?.parse()?; options.device_given = true },
+                "-r" | "--rate" => options.rate = Some(number(arg, &value(arg)?)?),
+                "-c" | "--channels" => options.channels = Some(number(arg, &value(arg)?)?),
+                "-f" | "--format" => options.format = parse_format(&value(arg)?)?,
+                "-t" | "--duration" => options.duration = number(arg, &value(arg)?)?,
+                "--frequency" => options.frequency = number(arg, &value(arg)?)?,
+                "--raw" => options.raw = true,
+                _ if arg.starts_with('-') => return usage(&format!("unknown option {}", arg)),
+                _ if options.file.is_none() => options.file = Some(arg.clone()),
+                _ => return usage(&format!("unexpected argument {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+
+    fn file(&self) -> Result<&str>
+    {
+        match self.file
+        {
+            Some(ref file) => Ok(file),
+            None => usage("a file is needed"),
+        }
+    }
+
+    fn is_raw(&self) -> Result<bool>
+    {
+        Ok(self.raw || !self.file()?.to_lowercase().ends_with(".wav"))
+    }
+}
+
+// Calls a generic function with the sample type of a format
+macro_rules! with_sample_type {
+    ($format:expr, $function:ident($($arg:expr),*)) => {
+        match $format
+        {
+            SampleFormat::FLOAT32 => $function::<f32>($($arg),*),
+            SampleFormat::INT32 => $function::<i32>($($arg),*),
+            SampleFormat::INT16 => $function::<i16>($($arg),*),
+            SampleFormat::INT8 => $function::<i8>($($arg),*),
+            SampleFormat::UINT8 => $function::<u8>($($arg),*),
+            _ => usage("raw samples can be f32, i32, i16, i8 or u8"),
+        }
+    }
+}
+
+fn main()
+{
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args)
+    {
+        common::exit("pa-tool", USAGE, e);
+    }
+}
+
+fn run(args: &[String]) -> Result<()>
+{
+    let (command, args) = match args.split_first()
+    {
+        Some((command, args)) => (command.as_str(), args),
+        None => return usage("missing command"),
+    };
+    if command == "help" || command == "-h" || command == "--help"
+    {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let options = Options::parse(args)?;
+
+    portaudio::initialize()?;
+    let result = match command
+    {
+        "devices" => devices(),
+        "probe" => probe(&options),
+        "play" => play(&options),
+        "rec" => rec(&options),
+        "tone" => tone(&options),
+        _ => usage(&format!("unknown command {}", command)),
+    };
+    portaudio::terminate()?;
+    result
+}
+
+fn devices() -> Result<()>
+{
+    println!("{}", portaudio::version_text());
+    let default_api = hostapi::get_default_index().ok();
+    println!("Host APIs:");
+    for (index, api) in hostapi::host_apis()
+    {
+        let default = if default_api == Some(index) { ", default" } else { "" };
+        println!("  {}: {} ({}), {} devices{}", index, api.name, api.api_type, api.device_count, default);
+    }
+
+    println!("Devices:");
+    for index in 0 .. device::get_count()?
+    {
+        let info = match device::get_info(index) { Some(info) => info, None => continue };
+        let api = hostapi::get_info(info.host_api).map_or("?".to_string(), |api| api.api_type.to_string());
+        let mut defaults = String::new();
+        if device::get_default_input_index() == Some(index) { defaults.push_str(", default input") }
+        if device::get_default_output_index() == Some(index) { defaults.push_str(", default output") }
+        println!("  {}: {} ({}), {} in, {} out, {} Hz{}", index, info.name, api, info.max_input_channels,
+                 info.max_output_channels, info.default_sample_rate, defaults);
+    }
+    Ok(())
+}
+
+fn probe(options: &Options) -> Result<()>
+{
+    let indices: Vec<_> = if options.device_given
+    {
+        let resolved = options.device.resolve(Direction::Output).or_else(|_| options.device.resolve(Direction::Input))?;
+        vec![resolved.index]
+    }
+    else
+    {
+        (0 .. device::get_count()?).collect()
+    };
+
+    let formats = [(SampleFormat::FLOAT32, "f32"), (SampleFormat::INT32, "i32"), (SampleFormat::INT16, "i16"),
+                   (SampleFormat::INT8, "i8"), (SampleFormat::UINT8, "u8")];
+    for index in indices
+    {
+        let info = match device::get_info(index) { Some(info) => info, None => continue };
+        for &(direction, max) in &[(Direction::Input, info.max_input_channels), (Direction::Output, info.max_output_channels)]
+        {
+            if max == 0 { continue }
+            let channels = options.channels.unwrap_or(max.min(2));
+            let name = if direction == Direction::Input { "input" } else { "output" };
+            println!("{}: {}, {} with {} channels", index, info.name, name, channels);
+            print!("  {:>8}", "rate");
+            for &(_, format) in &formats { print!(" {:>4}", format) }
+            println!();
+            for &rate in PROBE_SAMPLE_RATES
+            {
+                print!("  {:>8}", rate);
+                let supported = diagnostics::probe_formats(index, channels, direction == Direction::Input, rate);
+                for &(format, _) in &formats
+                {
+                    print!(" {:>4}", if supported.contains(format) { "yes" } else { "-" });
+                }
+                println!();
+            }
+        }
+    }
+    Ok(())
+}
+
+// The parameters for the device of the options, with its info
+fn parameters<T: SampleType + Default>(options: &Options, direction: Direction, channels: Option<u32>)
+    -> Result<(StreamParameters<T>, DeviceInfo)>
+{
+    let device = options.device.resolve(direction)?.index;
+    let info = device::get_info(device).ok_or("the device disappeared")?;
+    let (max, suggested_latency) = match direction
+    {
+        Direction::Input => (info.max_input_channels, info.default_high_input_latency),
+        Direction::Output => (info.max_output_channels, info.default_high_output_latency),
+    };
+    let channel_count = channels.unwrap_or(max.min(2));
+    Ok((StreamParameters { device, channel_count, suggested_latency, data: T::default() }, info))
+}
+
+fn open_output<T: SampleType + Default>(options: &Options, channels: Option<u32>, rate: Option<f64>)
+    -> Result<(Stream<'static, T, T>, u32, f64)>
+{
+    let (parameters, info) = parameters::<T>(options, Direction::Output, channels)?;
+    let rate = rate.unwrap_or(info.default_sample_rate);
+    let channel_count = parameters.channel_count;
+    let stream = Stream::open(None, Some(parameters), rate, FRAMES_PER_BUFFER_UNSPECIFIED, StreamFlags::empty(), None)?;
+    Ok((stream, channel_count, rate))
+}
+
+fn open_input<T: SampleType + Default>(options: &Options, channels: Option<u32>, rate: f64)
+    -> Result<Stream<'static, T, T>>
+{
+    let (parameters, _) = parameters::<T>(options, Direction::Input, channels)?;
+    Ok(Stream::open(Some(parameters), None, rate, FRAMES_PER_BUFFER_UNSPECIFIED, StreamFlags::empty(), None)?)
+}
+
+fn play(options: &Options) -> Result<()>
+{
+    if options.is_raw()?
+    {
+        return with_sample_type!(options.format, play_raw(options));
+    }
+
+    let mut reader = wav::WavReader::open(options.file()?)?;
+    let spec = reader.spec();
+    let (stream, _, _) = open_output::<f32>(options, Some(u32::from(spec.channels)), Some(f64::from(spec.sample_rate)))?;
+    stream.start()?;
+    wav::play(&stream, &mut reader)?;
+    stream.stop()?;
+    Ok(())
+}
+
+fn play_raw<T: SampleType + Copy + Default>(options: &Options) -> Result<()>
+{
+    let mut file = BufReader::new(File::open(options.file()?)?);
+    let (stream, channels, _) = open_output::<T>(options, Some(options.channels.unwrap_or(2)), Some(options.rate.unwrap_or(44100.0)))?;
+    let mut buffer = vec![T::default(); CHUNK_FRAMES * channels as usize];
+    stream.start()?;
+    loop
+    {
+        let bytes = read_full(&mut file, convert::as_bytes_mut(&mut buffer))?;
+        let frames = bytes / (channels as usize * ::std::mem::size_of::<T>());
+        if frames == 0 { break }
+        write_tolerant(&stream, &buffer[.. frames * channels as usize])?;
+    }
+    stream.stop()?;
+    Ok(())
+}
+
+fn rec(options: &Options) -> Result<()>
+{
+    let rate = options.rate.unwrap_or(44100.0);
+    let frames = (options.duration * rate).round() as u64;
+    if options.is_raw()?
+    {
+        return with_sample_type!(options.format, rec_raw(options, rate, frames));
+    }
+
+    let channels = options.channels.unwrap_or(2);
+    let stream = open_input::<f32>(options, Some(channels), rate)?;
+    let spec = wav::WavSpec { channels: channels as u16, sample_rate: rate as u32, format: options.format };
+    let mut writer = wav::WavWriter::create(options.file()?, spec)?;
+    stream.start()?;
+    wav::record(&stream, &mut writer, frames)?;
+    stream.stop()?;
+    writer.finalize()?;
+    Ok(())
+}
+
+fn rec_raw<T: SampleType + Copy + Default>(options: &Options, rate: f64, frames: u64) -> Result<()>
+{
+    let mut file = BufWriter::new(File::create(options.file()?)?);
+    let channels = options.channels.unwrap_or(2);
+    let stream = open_input::<T>(options, Some(channels), rate)?;
+    let silence = silence::<T>(CHUNK_FRAMES * channels as usize);
+    stream.start()?;
+    let mut remaining = frames;
+    while remaining > 0
+    {
+        let count = remaining.min(CHUNK_FRAMES as u64);
+        match stream.read(count as u32)
+        {
+            Ok(samples) => file.write_all(convert::as_bytes(&samples))?,
+            // Write silence in place of the lost frames, so what follows keeps its place in time
+            Err(ref e) if e.is_transient() =>
+            {
+                eprintln!("pa-tool: {}", e);
+                file.write_all(convert::as_bytes(&silence[.. count as usize * channels as usize]))?;
+            },
+            Err(e) => return Err(e.into()),
+        }
+        remaining -= count;
+    }
+    stream.stop()?;
+    file.flush()?;
+    Ok(())
+}
+
+fn tone(options: &Options) -> Result<()>
+{
+    let (_, info) = parameters::<f32>(options, Direction::Output, None)?;
+    let channels = options.channels.unwrap_or(info.max_output_channels);
+    let (stream, channels, rate) = open_output::<f32>(options, Some(channels), Some(options.rate.unwrap_or(info.default_sample_rate)))?;
+    let frames = (options.duration * rate).round() as usize;
+    let step = 2.0 * PI * options.frequency / rate as f32;
+    let mut buffer = vec![0.0f32; CHUNK_FRAMES * channels as usize];
+
+    stream.start()?;
+    for channel in 0 .. channels as usize
+    {
+        println!("channel {}", channel);
+        let mut frame = 0;
+        while frame < frames
+        {
+            let count = (frames - frame).min(CHUNK_FRAMES);
+            for (i, samples) in buffer.chunks_mut(channels as usize).take(count).enumerate()
+            {
+                for sample in samples.iter_mut() { *sample = 0.0 }
+                samples[channel] = 0.25 * (step * (frame + i) as f32).sin();
+            }
+            write_tolerant(&stream, &buffer[.. count * channels as usize])?;
+            frame += count;
+        }
+    }
+    stream.stop()?;
+    Ok(())
+}
+
+// Write to a blocking stream, reporting underflows instead of failing on them
+fn write_tolerant<T: SampleType>(stream: &Stream<T, T>, samples: &[T]) -> Result<()>
+{
+    match stream.write(samples)
+    {
+        Err(ref e) if e.is_transient() => { eprintln!("pa-tool: {}", e); Ok(()) },
+        result => Ok(result?),
+    }
+}
@@ -0,0 +1,321 @@
+//! Conversion between sample formats
+//!
+//! All conversions go through 32 bit floating point samples in the range [-1.0, 1.0]. Integer
+//! formats are scaled the same way PortAudio scales them internally.
+
+use stream::{SampleFormat, SampleType};
+use std::{mem, slice};
+
+/// Dithering applied when samples are converted to a format with a lower resolution
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Dither
+{
+    /// Round to the nearest value without adding noise
+    None,
+
+    /// Add triangular probability density function (TPDF) noise of one least significant bit
+    Triangular,
+}
+
+/// Options to convert between the sample format of the application and that of the device
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Conversion
+{
+    /// Dithering to use when the target format has a lower resolution
+    pub dither: Dither,
+
+    /// Clip out of range samples. When false, out of range samples wrap around.
+    pub clip: bool,
+}
+
+impl Default for Conversion
+{
+    fn default() -> Conversion
+    {
+        Conversion
+        {
+            dither: Dither::Triangular,
+            clip: true,
+        }
+    }
+}
+
+/// Returns the size in bytes of a single sample of the given format
+///
+/// Returns None when the format is not one of the interleaved formats this module can convert.
+pub fn format_size(format: SampleFormat) -> Option<usize>
+{
+    match format
+    {
+        SampleFormat::FLOAT32 | SampleFormat::INT32 => Some(4),
+        SampleFormat::INT24 => Some(3),
+        SampleFormat::INT16 => Some(2),
+        SampleFormat::INT8 | SampleFormat::UINT8 => Some(1),
+        _ => None,
+    }
+}
+
+/// Decodes the samples of the given format into floating point samples
+///
+/// Decodes as many samples as fit into output.
+///
+/// ## Panics
+/// When the format can not be converted, or when input holds less than output.len() samples.
+pub fn decode(format: SampleFormat, input: &[u8], output: &mut [f32])
+{
+    let size = format_size(format).expect("unsupported sample format");
+    let input = &input[.. output.len() * size];
+
+    match format
+    {
+        SampleFormat::FLOAT32 => for (o, i) in output.iter_mut().zip(input.chunks(4))
+        {
+            *o = f32::from_ne_bytes([i[0], i[1], i[2], i[3]]);
+        },
+        SampleFormat::INT32 => for (o, i) in output.iter_mut().zip(input.chunks(4))
+        {
+            *o = (i32::from_ne_bytes([i[0], i[1], i[2], i[3]]) as f64 / 2_147_483_648.0) as f32;
+        },
+        SampleFormat::INT24 => for (o, i) in output.iter_mut().zip(input.chunks(3))
+        {
+            *o = read_i24([i[0], i[1], i[2]]) as f32 / 8_388_608.0;
+        },
+        SampleFormat::INT16 => for (o, i) in output.iter_mut().zip(input.chunks(2))
+        {
+            *o = i16::from_ne_bytes([i[0], i[1]]) as f32 / 32_768.0;
+        },
+        SampleFormat::INT8 => for (o, &i) in output.iter_mut().zip(input.iter())
+        {
+            *o = i as i8 as f32 / 128.0;
+        },
+        _ => for (o, &i) in output.iter_mut().zip(input.iter())
+        {
+            *o = (i as f32 - 128.0) / 128.0;
+        },
+    }
+}
+
+/// Converts floating point samples to other formats, keeping the state needed for dithering
+pub struct Converter
+{
+    conversion: Conversion,
+    seed: u32,
+}
+
+impl Converter
+{
+    /// Create a converter with the given options
+    pub fn new(conversion: Conversion) -> Converter
+    {
+        Converter
+        {
+            conversion,
+            seed: 22_222,
+        }
+    }
+
+    /// Encodes floating point samples into the given format
+    ///
+    /// ## Panics
+    /// When the format can not be converted, or when output can not hold input.len() samples.
+    pub fn encode(&mut self, input: &[f32], format: SampleFormat, output: &mut [u8])
+    {
+        let size = format_size(format).expect("unsupported sample format");
+        let output = &mut output[.. input.len() * size];
+
+        match format
+        {
+            SampleFormat::FLOAT32 => for (&i, o) in input.iter().zip(output.chunks_mut(4))
+            {
+                let value = if self.conversion.clip { i.clamp(-1.0, 1.0) } else { i };
+                o.copy_from_slice(&value.to_ne_bytes());
+            },
+            SampleFormat::INT32 => for (&i, o) in input.iter().zip(output.chunks_mut(4))
+            {
+                let value = self.quantize(i as f64 * 2_147_483_647.0, 32, false);
+                o.copy_from_slice(&(value as i32).to_ne_bytes());
+            },
+            SampleFormat::INT24 => for (&i, o) in input.iter().zip(output.chunks_mut(3))
+            {
+                let value = self.quantize(i as f64 * 8_388_607.0, 24, true);
+                o.copy_from_slice(&write_i24(value as i32));
+            },
+            SampleFormat::INT16 => for (&i, o) in input.iter().zip(output.chunks_mut(2))
+            {
+                let value = self.quantize(i as f64 * 32_767.0, 16, true);
+                o.copy_from_slice(&(value as i16).to_ne_bytes());
+            },
+            SampleFormat::INT8 => for (&i, o) in input.iter().zip(output.iter_mut())
+            {
+                *o = self.quantize(i as f64 * 127.0, 8, true) as i8 as u8;
+            },
+            _ => for (&i, o) in input.iter().zip(output.iter_mut())
+            {
+                *o = (self.quantize(i as f64 * 127.0, 8, true) as i8 as u8) ^ 0x80;
+            },
+        }
+    }
+
+    /// Convert samples of one type into another
+    ///
+    /// ## Panics
+    /// When output is shorter than input.
+    pub fn convert<I: SampleType, O: SampleType>(&mut self, input: &[I], output: &mut [O])
+    {
+        let in_format = <I as SampleType>::sample_format();
+        let out_format = <O as SampleType>::sample_format();
+        let in_size = mem::size_of::<I>();
+        let out_size = mem::size_of::<O>();
+
+        let input = as_bytes(input);
+        let output = as_bytes_mut(&mut output[.. input.len() / in_size]);
+
+        let mut buffer = [0.0f32; 256];
+        for (i, o) in input.chunks(buffer.len() * in_size).zip(output.chunks_mut(buffer.len() * out_size))
+        {
+            let samples = &mut buffer[.. i.len() / in_size];
+            decode(in_format, i, samples);
+            self.encode(samples, out_format, o);
+        }
+    }
+
+    // Scale is already applied to value. Returns the rounded value, clipped or wrapped to the
+    // given number of bits.
+    fn quantize(&mut self, value: f64, bits: u32, dither: bool) -> i64
+    {
+        let value = if dither && self.conversion.dither == Dither::Triangular
+        {
+            value + self.triangular() as f64
+        }
+        else
+        {
+            value
+        };
+
+        let max = (1i64 << (bits - 1)) - 1;
+        let min = -max - 1;
+        let rounded = value.round() as i64;
+        if self.conversion.clip
+        {
+            rounded.max(min).min(max)
+        }
+        else
+        {
+            // Keep the lower bits, sign extended
+            (rounded << (64 - bits)) >> (64 - bits)
+        }
+    }
+
+    // Triangular noise in the range (-1.0, 1.0)
+    fn triangular(&mut self) -> f32
+    {
+        let a = self.next_random();
+        let b = self.next_random();
+        a + b
+    }
+
+    // Uniform noise in the range [-0.5, 0.5)
+    fn next_random(&mut self) -> f32
+    {
+        // Same linear congruential generator as PortAudio uses for its dither
+        self.seed = self.seed.wrapping_mul(196_314_165).wrapping_add(907_633_515);
+        (self.seed >> 8) as f32 / 16_777_216.0 - 0.5
+    }
+}
+
+/// View a slice of samples as raw bytes
+pub fn as_bytes<T: SampleType>(samples: &[T]) -> &[u8]
+{
+    unsafe { slice::from_raw_parts(samples.as_ptr() as *const u8, mem::size_of_val(samples)) }
+}
+
+/// View a mutable slice of samples as raw bytes
+pub fn as_bytes_mut<T: SampleType>(samples: &mut [T]) -> &mut [u8]
+{
+    unsafe { slice::from_raw_parts_mut(samples.as_mut_ptr() as *mut u8, mem::size_of_val(samples)) }
+}
+
+#[cfg(target_endian = "little")]
+fn read_i24(b: [u8; 3]) -> i32 { i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8 }
+
+#[cfg(target_endian = "big")]
+fn read_i24(b: [u8; 3]) -> i32 { i32::from_be_bytes([b[0], b[1], b[2], 0]) >> 8 }
+
+#[cfg(target_endian = "little")]
+fn write_i24(v: i32) -> [u8; 3] { let b = v.to_le_bytes(); [b[0], b[1], b[2]] }
+
+#[cfg(target_endian = "big")]
+fn write_i24(v: i32) -> [u8; 3] { let b = v.to_be_bytes(); [b[1], b[2], b[3]] }
+
+#[cfg(test)]
+mod test
+{
+    use super::{Conversion, Converter, Dither};
+
+    fn exact() -> Converter
+    {
+        Converter::new(Conversion { dither: Dither::None, clip: true })
+    }
+
+    #[test]
+    fn integer_roundtrip()
+    {
+        let input = [i16::MIN, -12_345, -1, 0, 1, 12_345, i16::MAX];
+        let mut float = [0.0f32; 7];
+        let mut output = [0i16; 7];
+
+        exact().convert(&input, &mut float);
+        assert_eq!(float[0], -1.0);
+        assert_eq!(float[3], 0.0);
+
+        let mut converter = exact();
+        let mut wide = [0i32; 7];
+        converter.convert(&input, &mut wide);
+        converter.convert(&wide, &mut output);
+        assert_eq!(&output[1 .. 6], &input[1 .. 6]);
+    }
+
+    #[test]
+    fn clipping()
+    {
+        let input = [-2.0f32, 2.0, 0.5];
+        let mut output = [0i16; 3];
+        exact().convert(&input, &mut output);
+        assert_eq!(output, [i16::MIN, i16::MAX, 16_384]);
+
+        let mut wrapping = Converter::new(Conversion { dither: Dither::None, clip: false });
+        wrapping.convert(&input[1 ..], &mut output[.. 2]);
+        assert!(output[0] < 0);
+    }
+
+    #[test]
+    fn packed_and_unsigned()
+    {
+        let input = [-1.0f32, -0.5, 0.0, 0.5];
+        let mut packed = [0u8; 12];
+        let mut unsigned = [0u8; 4];
+        let mut decoded = [0.0f32; 4];
+
+        let mut converter = exact();
+        converter.encode(&input, ::stream::SampleFormat::INT24, &mut packed);
+        super::decode(::stream::SampleFormat::INT24, &packed, &mut decoded);
+        for (a, b) in input.iter().zip(decoded.iter())
+        {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        converter.convert(&input, &mut unsigned);
+        assert_eq!(unsigned, [1, 64, 128, 192]);
+    }
+
+    #[test]
+    fn triangular_dither()
+    {
+        let input = [0.25f32 / 32_767.0; 1000];
+        let mut output = [0i16; 1000];
+        Converter::new(Conversion::default()).convert(&input, &mut output);
+
+        assert!(output.iter().all(|v| (-1 ..= 1).contains(v)));
+        assert!(output.iter().any(|&v| v != 0));
+    }
+}
@@ -2,15 +2,19 @@
 
 use ll;
 use util::{to_pa_result, pa_time_to_duration};
-use hostapi::HostApiIndex;
+use hostapi::{self, HostApiIndex, HostApiType};
 use pa::PaError;
 use std::time::Duration;
 use std::ffi::CStr;
+use std::{error, fmt};
+use std::str::FromStr;
 
 /// Index of a Device
 pub type DeviceIndex = u32;
 
 /// Information for a specific device
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DeviceInfo
 {
     /// Human readable name
@@ -26,15 +30,19 @@ pub struct DeviceInfo
     pub max_output_channels: u32,
 
     /// Default input latency for interactive performance
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub default_low_input_latency: Duration,
 
     /// Default output latency for interactive performance
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub default_low_output_latency: Duration,
 
     /// Default input latency for robust non-interactive applications
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub default_high_input_latency: Duration,
 
     /// Default output latency for robust non-interactive applications
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub default_high_output_latency: Duration,
 
     /// Default sample rate
@@ -129,3 +137,236 @@ pub fn get_from_host_api_device_index(host_api: HostApiIndex, host_api_device_in
         m => to_pa_result(m).map(|_| 0),
     }
 }
+
+/// Identifies a device by what it is rather than by its position in the device list
+///
+/// The identifier can be saved as a string, and found again with resolve() after the devices
+/// were renumbered by a reboot or a replug. The string form is
+/// `<host API>:<input channels>:<output channels>:<sample rate>:<name>`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceId
+{
+    /// The type of the host API of the device
+    pub api_type: HostApiType,
+
+    /// Name of the device
+    pub name: String,
+
+    /// Maximal number of input channels
+    pub max_input_channels: u32,
+
+    /// Maximal number of output channels
+    pub max_output_channels: u32,
+
+    /// Default sample rate
+    pub default_sample_rate: f64,
+}
+
+impl DeviceId
+{
+    /// The identifier of the device with the given index, if it exists
+    pub fn for_device(index: DeviceIndex) -> Option<DeviceId>
+    {
+        let info = get_info(index)?;
+        Some(DeviceId
+        {
+            api_type: hostapi::get_info(info.host_api).map_or(HostApiType::Unknown(0), |api| api.api_type),
+            name: info.name,
+            max_input_channels: info.max_input_channels,
+            max_output_channels: info.max_output_channels,
+            default_sample_rate: info.default_sample_rate,
+        })
+    }
+
+    // How well the device with the given identifier matches, from 0 to 1. Devices whose names
+    // have nothing in common do not match at all.
+    fn score(&self, other: &DeviceId) -> f32
+    {
+        let name = if self.name == other.name { 1.0 }
+        else
+        {
+            let (a, b) = (normalize_name(&self.name), normalize_name(&other.name));
+            if a == b { 0.75 }
+            else if !a.is_empty() && !b.is_empty() && (a.contains(&b) || b.contains(&a)) { 0.5 }
+            else { return 0.0 }
+        };
+        let matches = |same: bool| if same { 1.0 } else { 0.0 };
+
+        0.6 * name
+            + 0.2 * matches(self.api_type == other.api_type)
+            + 0.075 * matches(self.max_input_channels == other.max_input_channels)
+            + 0.075 * matches(self.max_output_channels == other.max_output_channels)
+            + 0.05 * matches(self.default_sample_rate == other.default_sample_rate)
+    }
+}
+
+// Lowercase the name and leave out ALSA card and device numbers such as "(hw:1,0)", which change
+// when devices are plugged in another order
+fn normalize_name(name: &str) -> String
+{
+    let mut result = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find("(hw:")
+    {
+        result.push_str(&rest[.. start]);
+        rest = rest[start ..].find(')').map_or("", |end| &rest[start + end + 1 ..]);
+    }
+    result.push_str(rest);
+    result.to_lowercase().split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl fmt::Display for DeviceId
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}:{}:{}:{}:{}", self.api_type, self.max_input_channels, self.max_output_channels,
+               self.default_sample_rate, self.name)
+    }
+}
+
+impl FromStr for DeviceId
+{
+    type Err = ParseDeviceIdError;
+
+    /// Parse the string form written by Display
+    fn from_str(s: &str) -> Result<DeviceId, ParseDeviceIdError>
+    {
+        let error = || ParseDeviceIdError { input: s.to_string() };
+        // An unknown host API type is written as "unknown:<id>", which takes two fields
+        let unknown = s.get(.. 8).is_some_and(|prefix| prefix.eq_ignore_ascii_case("unknown:"));
+        let mut parts = s.splitn(if unknown { 6 } else { 5 }, ':');
+        let mut next = || parts.next().ok_or_else(error);
+        let mut api_type = next()?.to_string();
+        if unknown { api_type = format!("{}:{}", api_type, next()?) }
+        let api_type = api_type.parse().map_err(|_| error())?;
+        let max_input_channels = next()?.parse().map_err(|_| error())?;
+        let max_output_channels = next()?.parse().map_err(|_| error())?;
+        let default_sample_rate = next()?.parse().map_err(|_| error())?;
+        let name = next()?.to_string();
+        Ok(DeviceId { api_type, name, max_input_channels, max_output_channels, default_sample_rate })
+    }
+}
+
+/// Error returned when parsing a DeviceId from a string in another form
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseDeviceIdError
+{
+    input: String,
+}
+
+impl fmt::Display for ParseDeviceIdError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "invalid device id \"{}\", expected <host API>:<inputs>:<outputs>:<sample rate>:<name>", self.input)
+    }
+}
+
+impl error::Error for ParseDeviceIdError {}
+
+/// The device found for a DeviceId
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Resolved
+{
+    /// Index of the device
+    pub index: DeviceIndex,
+
+    /// How well the device matches, from 0 to 1
+    ///
+    /// 1 means that all properties are equal. A device with the same name but another number of
+    /// channels or another host API scores lower, as does a device whose name only differs in its
+    /// ALSA card number or is a part of the other name.
+    pub confidence: f32,
+}
+
+/// Find the current device that best matches the identifier
+///
+/// Returns None when no device has a similar name. Of equally good matches, the first one is
+/// returned.
+pub fn resolve(id: &DeviceId) -> Option<Resolved>
+{
+    let count = get_count().unwrap_or(0);
+    let mut best: Option<Resolved> = None;
+    for index in 0..count
+    {
+        let confidence = match DeviceId::for_device(index) { Some(other) => id.score(&other), None => continue };
+        if confidence > 0.0 && best.map_or(true, |b| confidence > b.confidence)
+        {
+            best = Some(Resolved { index, confidence });
+        }
+    }
+    best
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod test
+{
+    use hostapi::HostApiType;
+    use mock::{self, MockDevice, MockSystem};
+
+    #[test]
+    fn enumerate_devices()
+    {
+        let mut system = MockSystem::default();
+        system.devices.push(MockDevice::new("USB Audio", 0, 16, 16));
+        mock::configure(system);
+
+        ::initialize().unwrap();
+        assert_eq!(super::get_count().unwrap(), 4);
+        assert_eq!(super::get_default_input_index(), Some(0));
+        assert_eq!(super::get_default_output_index(), Some(1));
+
+        let info = super::get_info(3).unwrap();
+        assert_eq!(info.name, "USB Audio");
+        assert_eq!(info.max_input_channels, 16);
+        assert!(super::get_info(4).is_none());
+
+        assert_eq!(super::get_from_host_api_device_index(0, 3).unwrap(), 3);
+        assert!(super::get_from_host_api_device_index(1, 0).is_err());
+        ::terminate().unwrap();
+
+        assert!(super::get_count().is_err());
+    }
+
+    #[test]
+    fn resolve_moved_device()
+    {
+        let mut system = MockSystem::default();
+        system.devices.push(MockDevice::new("USB Audio: - (hw:1,0)", 0, 2, 2));
+        mock::configure(system);
+        ::initialize().unwrap();
+        let id = super::DeviceId::for_device(3).unwrap();
+        assert_eq!(id.to_string(), "indevelopment:2:2:44100:USB Audio: - (hw:1,0)");
+        assert_eq!(id.to_string().parse(), Ok(id.clone()));
+        ::terminate().unwrap();
+
+        let mut system = MockSystem::default();
+        system.devices.insert(0, MockDevice::new("USB Audio: - (hw:2,0)", 0, 2, 2));
+        mock::configure(system);
+        ::initialize().unwrap();
+        let moved = super::resolve(&id).unwrap();
+        assert_eq!(moved.index, 0);
+        assert!((moved.confidence - 0.85).abs() < 1e-6);
+        let exact = super::DeviceId { name: "Mock Duplex".to_string(), .. id.clone() };
+        assert_eq!(super::resolve(&exact), Some(super::Resolved { index: 3, confidence: 1.0 }));
+        assert_eq!(super::resolve(&super::DeviceId { name: "HDMI".to_string(), .. id }), None);
+        ::terminate().unwrap();
+    }
+
+    #[test]
+    fn unknown_api_type_round_trip()
+    {
+        let id = super::DeviceId
+        {
+            api_type: HostApiType::Unknown(99),
+            name: "Virtual: 1".to_string(),
+            max_input_channels: 0,
+            max_output_channels: 2,
+            default_sample_rate: 48000.0,
+        };
+        assert_eq!(id.to_string(), "unknown:99:0:2:48000:Virtual: 1");
+        assert_eq!(id.to_string().parse(), Ok(id.clone()));
+        assert!("unknown:0:2:48000:Virtual".parse::<super::DeviceId>().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! A report of the audio setup of the system, for bug reports
+//!
+//! report() collects the PortAudio version, the host APIs, the devices with their latencies and
+//! a quick check of the formats and sample rates they accept, the default devices and the last
+//! host error. Display writes the report as text. With the `serde` feature it can be serialized
+//! as well.
+
+use device::{self, DeviceIndex, DeviceInfo};
+use hostapi::{self, HostApiIndex, HostApiInfo, HostApiType, HostErrorInfo};
+use pa::{self, VersionInfo};
+use stream::{self, SampleFormat, SampleType, StreamParameters};
+use std::fmt;
+use std::time::Duration;
+
+/// The sample rates that are checked for each device
+pub const PROBE_SAMPLE_RATES: &[f64] = &[8000.0, 16000.0, 22050.0, 44100.0, 48000.0, 88200.0, 96000.0, 192000.0];
+
+/// The audio setup of the system
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Report
+{
+    /// The PortAudio version
+    pub version: VersionInfo,
+
+    /// Why PortAudio could not be initialized, in which case the rest of the report is empty
+    pub initialize_error: Option<String>,
+
+    /// The host APIs
+    pub host_apis: Vec<HostApiInfo>,
+
+    /// The default host API
+    pub default_host_api: Option<HostApiIndex>,
+
+    /// The devices
+    pub devices: Vec<DeviceReport>,
+
+    /// The default input device
+    pub default_input: Option<DeviceIndex>,
+
+    /// The default output device
+    pub default_output: Option<DeviceIndex>,
+
+    /// The last error reported by a host API, if any
+    pub last_host_error: Option<HostErrorInfo>,
+}
+
+/// A device in the report
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceReport
+{
+    /// Index of the device
+    pub index: DeviceIndex,
+
+    /// The type of the host API of the device
+    pub api_type: HostApiType,
+
+    /// Information about the device
+    pub info: DeviceInfo,
+
+    /// What the device accepts for input, if it has input channels
+    pub input: Option<Probe>,
+
+    /// What the device accepts for output, if it has output channels
+    pub output: Option<Probe>,
+}
+
+/// The formats and sample rates a device accepts in one direction
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Probe
+{
+    /// The number of channels that was checked, at most two
+    pub channel_count: u32,
+
+    /// The sample formats accepted at the default sample rate
+    pub formats: SampleFormat,
+
+    /// The sample rates of PROBE_SAMPLE_RATES accepted with 32 bit float samples
+    pub sample_rates: Vec<f64>,
+}
+
+/// Collect the report
+///
+/// PortAudio is initialized for this and terminated again, so it may but need not be
+/// initialized already.
+pub fn report() -> Report
+{
+    let mut report = Report
+    {
+        version: pa::version_info(),
+        initialize_error: None,
+        host_apis: Vec::new(),
+        default_host_api: None,
+        devices: Vec::new(),
+        default_input: None,
+        default_output: None,
+        last_host_error: None,
+    };
+    if let Err(e) = pa::initialize()
+    {
+        report.initialize_error = Some(e.to_string());
+        return report;
+    }
+    // Probing the formats below may overwrite the error of the host API
+    report.last_host_error = hostapi::get_last_error().filter(|e| e.code != 0);
+
+    report.host_apis = hostapi::host_apis().map(|(_, info)| info).collect();
+    report.default_host_api = hostapi::get_default_index().ok();
+    let count = device::get_count().unwrap_or(0);
+    report.devices = (0..count).filter_map(|index| device::get_info(index).map(|info|
+    {
+        let api_type = hostapi::get_info(info.host_api).map_or(HostApiType::Unknown(0), |api| api.api_type);
+        DeviceReport
+        {
+            index,
+            api_type,
+            input: if info.max_input_channels > 0 { Some(probe(index, &info, true)) } else { None },
+            output: if info.max_output_channels > 0 { Some(probe(index, &info, false)) } else { None },
+            info,
+        }
+    })).collect();
+    report.default_input = device::get_default_input_index();
+    report.default_output = device::get_default_output_index();
+
+    let _ = pa::terminate();
+    report
+}
+
+fn probe(index: DeviceIndex, info: &DeviceInfo, input: bool) -> Probe
+{
+    let max = if input { info.max_input_channels } else { info.max_output_channels };
+    let channel_count = max.min(2);
+    let formats = probe_formats(index, channel_count, input, info.default_sample_rate);
+    let sample_rates = PROBE_SAMPLE_RATES.iter().cloned().filter(|&rate| is_supported::<f32>(index, channel_count, input, rate)).collect();
+    Probe { channel_count, formats, sample_rates }
+}
+
+/// The sample formats a device accepts with the given number of channels and sample rate
+///
+/// Only the interleaved formats that have a sample type are checked, so INT24 is never included.
+pub fn probe_formats(device: DeviceIndex, channel_count: u32, input: bool, sample_rate: f64) -> SampleFormat
+{
+    let supports = |format: SampleFormat| match format
+    {
+        SampleFormat::FLOAT32 => is_supported::<f32>(device, channel_count, input, sample_rate),
+        SampleFormat::INT32 => is_supported::<i32>(device, channel_count, input, sample_rate),
+        SampleFormat::INT16 => is_supported::<i16>(device, channel_count, input, sample_rate),
+        SampleFormat::INT8 => is_supported::<i8>(device, channel_count, input, sample_rate),
+        _ => is_supported::<u8>(device, channel_count, input, sample_rate),
+    };
+
+    let mut formats = SampleFormat::empty();
+    for &format in &[SampleFormat::FLOAT32, SampleFormat::INT32, SampleFormat::INT16, SampleFormat::INT8, SampleFormat::UINT8]
+    {
+        if supports(format) { formats |= format }
+    }
+    formats
+}
+
+fn is_supported<T: SampleType + Default>(device: DeviceIndex, channel_count: u32, input: bool, sample_rate: f64) -> bool
+{
+    let parameters = Some(StreamParameters { device, channel_count, suggested_latency: Duration::from_secs(0), data: T::default() });
+    let none: Option<StreamParameters<T>> = None;
+    let (input, output) = if input { (parameters, none) } else { (none, parameters) };
+    stream::is_format_supported(input, output, sample_rate).is_ok()
+}
+
+fn millis(duration: Duration) -> f64
+{
+    duration.as_secs_f64() * 1000.0
+}
+
+fn format_names(formats: SampleFormat) -> String
+{
+    let names = [(SampleFormat::FLOAT32, "f32"), (SampleFormat::INT32, "i32"), (SampleFormat::INT16, "i16"),
+                 (SampleFormat::INT8, "i8"), (SampleFormat::UINT8, "u8")];
+    let supported: Vec<&str> = names.iter().filter(|n| formats.contains(n.0)).map(|n| n.1).collect();
+    if supported.is_empty() { "none".to_string() } else { supported.join(" ") }
+}
+
+impl fmt::Display for Report
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "PortAudio {}", self.version)?;
+        if let Some(ref e) = self.initialize_error
+        {
+            return writeln!(f, "Initialization failed: {}", e);
+        }
+
+        writeln!(f, "Host APIs:")?;
+        for api in &self.host_apis
+        {
+            write!(f, "  {}: {} ({}), {} devices", api.index, api.name, api.api_type, api.device_count)?;
+            if self.default_host_api == Some(api.index) { f.write_str(", default")? }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "Devices:")?;
+        for device in &self.devices
+        {
+            let info = &device.info;
+            write!(f, "  {}: {} ({})", device.index, info.name, device.api_type)?;
+            if self.default_input == Some(device.index) { f.write_str(", default input")? }
+            if self.default_output == Some(device.index) { f.write_str(", default output")? }
+            writeln!(f)?;
+            writeln!(f, "     {} inputs, {} outputs, default sample rate {} Hz",
+                     info.max_input_channels, info.max_output_channels, info.default_sample_rate)?;
+            let sides = [("input", &device.input, info.default_low_input_latency, info.default_high_input_latency),
+                         ("output", &device.output, info.default_low_output_latency, info.default_high_output_latency)];
+            for &(name, probe, low, high) in &sides
+            {
+                if let Some(ref probe) = *probe
+                {
+                    let rates: Vec<String> = probe.sample_rates.iter().map(|r| r.to_string()).collect();
+                    writeln!(f, "     {}: latency {:.1} to {:.1} ms, formats {}, rates {}", name, millis(low), millis(high),
+                             format_names(probe.formats), if rates.is_empty() { "none".to_string() } else { rates.join(" ") })?;
+                }
+            }
+        }
+
+        match self.last_host_error
+        {
+            Some(ref e) => writeln!(f, "Last host error: {} error {}: {}", e.api_type, e.code, e.text),
+            None => writeln!(f, "Last host error: none"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod test
+{
+    use mock::{self, MockSystem};
+    use stream::SampleFormat;
+
+    #[test]
+    fn reports_mock_system()
+    {
+        let mut system = MockSystem::default();
+        system.devices[1].formats = (SampleFormat::FLOAT32 | SampleFormat::INT16).bits() as ::libc::c_ulong;
+        mock::configure(system);
+
+        let report = super::report();
+        assert_eq!(report.initialize_error, None);
+        assert_eq!(report.host_apis.len(), 1);
+        assert_eq!(report.devices.len(), 3);
+        assert_eq!((report.default_input, report.default_output), (Some(0), Some(1)));
+        assert!(report.devices[0].output.is_none());
+        let output = report.devices[1].output.as_ref().unwrap();
+        assert_eq!(output.formats, SampleFormat::FLOAT32 | SampleFormat::INT16);
+        assert_eq!(output.sample_rates, vec![44100.0, 48000.0]);
+
+        let text = report.to_string();
+        assert!(text.contains("  1: Mock Output (indevelopment), default output\n"), "{}", text);
+        assert!(text.contains("     output: latency 10.0 to 100.0 ms, formats f32 i16, rates 44100 48000\n"), "{}", text);
+        assert!(text.ends_with("Last host error: none\n"));
+
+        mock::set_host_error(::ll::paALSA, -19, "No such device");
+        let error = super::report().last_host_error.unwrap();
+        assert_eq!((error.code, error.text.as_str()), (-19, "No such device"));
+
+        mock::fail_next(mock::Operation::Initialize, ::ll::paInsufficientMemory);
+        assert!(super::report().initialize_error.is_some());
+    }
+}
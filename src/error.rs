@@ -0,0 +1,333 @@
+//! Errors together with the context in which they occured
+//!
+//! Stream operations return an Error rather than a bare PaError. Besides the error code, it holds
+//! the raw code PortAudio returned, the operation that failed with its parameters and, for
+//! UnanticipatedHostError, the error reported by the host API at the time.
+
+use ll;
+use pa::PaError;
+use device::DeviceIndex;
+use hostapi::{self, HostErrorInfo};
+use std::{error, fmt};
+
+/// How an error should be dealt with
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind
+{
+    /// Samples were dropped or the call timed out, but the stream is still usable (xruns)
+    Transient,
+
+    /// The request cannot be satisfied as made, such as an unsupported sample rate or a write to
+    /// an input-only stream. Retrying with the same parameters fails again.
+    Configuration,
+
+    /// The device, the host API or PortAudio itself failed
+    Fatal,
+
+    /// The operation can not be done right now, such as reinitializing PortAudio while streams
+    /// are open, but may succeed when tried again later
+    Busy,
+}
+
+/// One side of a stream that was opened or checked
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Endpoint
+{
+    /// The device, or None for the default device
+    pub device: Option<DeviceIndex>,
+
+    /// The number of channels
+    pub channel_count: u32,
+}
+
+/// The operation during which an error occured
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation
+{
+    /// Initializing PortAudio
+    Initialize,
+
+    /// Terminating PortAudio
+    Terminate,
+
+    /// Opening a stream
+    Open
+    {
+        /// The input of the stream, if any
+        input: Option<Endpoint>,
+        /// The output of the stream, if any
+        output: Option<Endpoint>,
+        /// The requested sample rate
+        sample_rate: f64,
+        /// The requested number of frames per buffer
+        frames_per_buffer: u64,
+    },
+
+    /// Checking wether a format is supported
+    IsFormatSupported
+    {
+        /// The input that was checked, if any
+        input: Option<Endpoint>,
+        /// The output that was checked, if any
+        output: Option<Endpoint>,
+        /// The sample rate that was checked
+        sample_rate: f64,
+    },
+
+    /// Starting a stream
+    Start,
+
+    /// Stopping a stream
+    Stop,
+
+    /// Aborting a stream
+    Abort,
+
+    /// Querying the state or the available frames of a stream
+    Status,
+
+    /// Reading from a blocking stream
+    Read
+    {
+        /// The number of frames requested
+        frames: u32,
+    },
+
+    /// Writing to a blocking stream
+    Write
+    {
+        /// The number of frames written
+        frames: u32,
+    },
+
+    /// Setting or removing the finished callback of a stream
+    SetFinishedCallback,
+
+    /// Terminating and initializing PortAudio again to rescan the devices
+    Reinitialize,
+}
+
+impl fmt::Display for Operation
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            Operation::Initialize => f.write_str("initialize"),
+            Operation::Terminate => f.write_str("terminate"),
+            Operation::Open { input, output, sample_rate, frames_per_buffer } =>
+            {
+                write!(f, "open (input: {}, output: {}, {} Hz, ", Side(input), Side(output), sample_rate)?;
+                match frames_per_buffer
+                {
+                    0 => f.write_str("unspecified frames per buffer)"),
+                    n => write!(f, "{} frames per buffer)", n),
+                }
+            },
+            Operation::IsFormatSupported { input, output, sample_rate } =>
+                write!(f, "is_format_supported (input: {}, output: {}, {} Hz)", Side(input), Side(output), sample_rate),
+            Operation::Start => f.write_str("start"),
+            Operation::Stop => f.write_str("stop"),
+            Operation::Abort => f.write_str("abort"),
+            Operation::Status => f.write_str("status"),
+            Operation::Read { frames } => write!(f, "read ({} frames)", frames),
+            Operation::Write { frames } => write!(f, "write ({} frames)", frames),
+            Operation::SetFinishedCallback => f.write_str("set_finished_callback"),
+            Operation::Reinitialize => f.write_str("reinitialize"),
+        }
+    }
+}
+
+struct Side(Option<Endpoint>);
+
+impl fmt::Display for Side
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.0
+        {
+            None => f.write_str("none"),
+            Some(Endpoint { device: None, channel_count }) => write!(f, "default device, {} channels", channel_count),
+            Some(Endpoint { device: Some(d), channel_count }) => write!(f, "device {}, {} channels", d, channel_count),
+        }
+    }
+}
+
+/// An error with the operation that caused it
+#[derive(Clone, Debug)]
+pub struct Error
+{
+    code: PaError,
+    raw: i32,
+    host_error: Option<HostErrorInfo>,
+    operation: Option<Operation>,
+    load_error: Option<String>,
+}
+
+impl Error
+{
+    /// Create an error for the given operation
+    pub fn new(code: PaError, operation: Operation) -> Error
+    {
+        Error { operation: Some(operation), .. Error::from(code) }
+    }
+
+    /// Create an error from a code returned by PortAudio
+    ///
+    /// For paUnanticipatedHostError, this fetches the error info of the host API, so it must be
+    /// called right after the failing call.
+    pub(crate) fn from_raw(raw: i32, operation: Option<Operation>) -> Error
+    {
+        let host_error = if raw == ll::paUnanticipatedHostError { hostapi::get_last_error() } else { None };
+        Error { code: PaError::from_i32(raw), raw, host_error, operation, load_error: None }
+    }
+
+    /// Create the error of initialize() when the PortAudio library could not be loaded
+    #[cfg(all(feature = "dynamic", not(feature = "mock")))]
+    pub(crate) fn not_loaded(reason: &::std::ffi::CStr) -> Error
+    {
+        Error
+        {
+            load_error: Some(reason.to_string_lossy().into_owned()),
+            .. Error::new(PaError::LibraryNotLoaded, Operation::Initialize)
+        }
+    }
+
+    /// The error code. Codes this crate does not know are UnknownError, see raw_code().
+    pub fn code(&self) -> PaError
+    {
+        self.code
+    }
+
+    /// The code as returned by PortAudio, which is kept for codes unknown to this crate
+    pub fn raw_code(&self) -> i32
+    {
+        self.raw
+    }
+
+    /// The error reported by the host API, for UnanticipatedHostError
+    pub fn host_error(&self) -> Option<&HostErrorInfo>
+    {
+        self.host_error.as_ref()
+    }
+
+    /// Why the PortAudio library could not be loaded, for LibraryNotLoaded
+    pub fn load_error(&self) -> Option<&str>
+    {
+        self.load_error.as_deref()
+    }
+
+    /// The operation that failed, when known
+    pub fn operation(&self) -> Option<&Operation>
+    {
+        self.operation.as_ref()
+    }
+
+    /// Classify the error
+    pub fn kind(&self) -> ErrorKind
+    {
+        match self.code
+        {
+            PaError::InputOverflowed | PaError::OutputUnderflowed | PaError::TimedOut => ErrorKind::Transient,
+            PaError::InvalidChannelCount | PaError::InvalidSampleRate | PaError::InvalidDevice | PaError::InvalidFlag
+            | PaError::SampleFormatNotSupported | PaError::BadIODeviceCombination | PaError::BufferTooBig
+            | PaError::BufferTooSmall | PaError::NullCallback | PaError::IncompatibleHostApiSpecificStreamInfo
+            | PaError::StreamIsStopped | PaError::StreamIsNotStopped | PaError::HostApiNotFound | PaError::InvalidHostApi
+            | PaError::CanNotReadFromACallbackStream | PaError::CanNotWriteToACallbackStream
+            | PaError::CanNotReadFromAnOutputOnlyStream | PaError::CanNotWriteToAnInputOnlyStream
+            | PaError::IncompatibleStreamHostApi | PaError::BadBufferPtr
+            | PaError::InitializedMoreThanOnce => ErrorKind::Configuration,
+            PaError::NotInitialized | PaError::UnanticipatedHostError | PaError::InsufficientMemory
+            | PaError::BadStreamPtr | PaError::InternalError | PaError::DeviceUnavailable
+            | PaError::UnknownError | PaError::LibraryNotLoaded => ErrorKind::Fatal,
+            PaError::StreamsOpen => ErrorKind::Busy,
+        }
+    }
+
+    /// Returns wether the stream can be used after the error, see ErrorKind::Transient
+    pub fn is_transient(&self) -> bool
+    {
+        self.kind() == ErrorKind::Transient
+    }
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.code
+        {
+            PaError::UnknownError => write!(f, "Unknown error {}", self.raw)?,
+            code => write!(f, "{}", code)?,
+        }
+        if let Some(ref operation) = self.operation
+        {
+            write!(f, " during {}", operation)?;
+        }
+        if let Some(ref host) = self.host_error
+        {
+            write!(f, ": {} error {}: {}", host.api_type, host.code, host.text)?;
+        }
+        if let Some(ref reason) = self.load_error
+        {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+        Some(&self.code)
+    }
+}
+
+impl From<PaError> for Error
+{
+    fn from(code: PaError) -> Error
+    {
+        Error { code, raw: code as i32, host_error: None, operation: None, load_error: None }
+    }
+}
+
+impl From<Error> for PaError
+{
+    fn from(error: Error) -> PaError { error.code }
+}
+
+impl PartialEq<PaError> for Error
+{
+    fn eq(&self, other: &PaError) -> bool { self.code == *other }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Error, ErrorKind, Operation};
+    use pa::PaError;
+
+    #[test]
+    fn classify()
+    {
+        assert_eq!(Error::from(PaError::OutputUnderflowed).kind(), ErrorKind::Transient);
+        assert_eq!(Error::from(PaError::InvalidSampleRate).kind(), ErrorKind::Configuration);
+        assert_eq!(Error::from(PaError::DeviceUnavailable).kind(), ErrorKind::Fatal);
+        assert_eq!(Error::from(PaError::LibraryNotLoaded).kind(), ErrorKind::Fatal);
+        assert_eq!(Error::from(PaError::StreamsOpen).kind(), ErrorKind::Busy);
+        assert_eq!(Error::from(PaError::InitializedMoreThanOnce).kind(), ErrorKind::Configuration);
+        assert!(Error::new(PaError::InputOverflowed, Operation::Read { frames: 64 }).is_transient());
+    }
+
+    #[test]
+    fn keeps_unknown_codes()
+    {
+        let error = Error::from_raw(-12345, Some(Operation::Write { frames: 256 }));
+        assert_eq!(error, PaError::UnknownError);
+        assert_eq!(error.raw_code(), -12345);
+        assert_eq!(error.operation(), Some(&Operation::Write { frames: 256 }));
+        assert_eq!(error.to_string(), "Unknown error -12345 during write (256 frames)");
+        assert!(error.host_error().is_none());
+    }
+}
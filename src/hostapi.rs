@@ -2,43 +2,72 @@
 
 use ll;
 use pa::PaError;
+use device::{self, DeviceIndex, DeviceInfo};
+use std::{error, fmt};
 use std::ffi::CStr;
+use std::str::FromStr;
 use util::to_pa_result;
 
 /// Index number of a Host API
 pub type HostApiIndex = u32;
 
 /// Possible Host API types
-#[repr(u32)]
-#[derive(Copy, Clone)]
+///
+/// Each type has a stable lowercase name, such as "alsa", "jack" or "pulseaudio", used by its
+/// Display and FromStr implementations.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[allow(missing_docs)]
 pub enum HostApiType
 {
-    InDevelopment = ll::paInDevelopment,
-    DirectSound = ll::paDirectSound,
-    MME = ll::paMME,
-    ASIO = ll::paASIO,
-    SoundManager = ll::paSoundManager,
-    CoreAudio = ll::paCoreAudio,
-    OSS = ll::paOSS,
-    ALSA = ll::paALSA,
-    AL = ll::paAL,
-    BeOS = ll::paBeOS,
-    WDMKS = ll::paWDMKS,
-    JACK = ll::paJACK,
-    WASAPI = ll::paWASAPI,
-    AudioScienceHPI = ll::paAudioScienceHPI,
-
-    /// Added for when FromPrimitive returns None
-    Unknown,
+    InDevelopment,
+    DirectSound,
+    MME,
+    ASIO,
+    SoundManager,
+    CoreAudio,
+    OSS,
+    ALSA,
+    AL,
+    BeOS,
+    WDMKS,
+    JACK,
+    WASAPI,
+    AudioScienceHPI,
+    AudioIO,
+    PulseAudio,
+    Sndio,
+
+    /// A type this crate does not know, with its raw identifier
+    Unknown(u32),
 }
 
+/// The known types, their identifiers and their names
+const HOST_API_TYPES: [(HostApiType, u32, &str); 17] = [
+    (HostApiType::InDevelopment, ll::paInDevelopment, "indevelopment"),
+    (HostApiType::DirectSound, ll::paDirectSound, "directsound"),
+    (HostApiType::MME, ll::paMME, "mme"),
+    (HostApiType::ASIO, ll::paASIO, "asio"),
+    (HostApiType::SoundManager, ll::paSoundManager, "soundmanager"),
+    (HostApiType::CoreAudio, ll::paCoreAudio, "coreaudio"),
+    (HostApiType::OSS, ll::paOSS, "oss"),
+    (HostApiType::ALSA, ll::paALSA, "alsa"),
+    (HostApiType::AL, ll::paAL, "al"),
+    (HostApiType::BeOS, ll::paBeOS, "beos"),
+    (HostApiType::WDMKS, ll::paWDMKS, "wdmks"),
+    (HostApiType::JACK, ll::paJACK, "jack"),
+    (HostApiType::WASAPI, ll::paWASAPI, "wasapi"),
+    (HostApiType::AudioScienceHPI, ll::paAudioScienceHPI, "asihpi"),
+    (HostApiType::AudioIO, ll::paAudioIO, "audioio"),
+    (HostApiType::PulseAudio, ll::paPulseAudio, "pulseaudio"),
+    (HostApiType::Sndio, ll::paSndio, "sndio"),
+];
+
 impl HostApiType
 {
     /// Convert a static host API unique identifier, into a runtime host API index.
     pub fn to_api_index(self) -> Result<HostApiIndex, PaError>
     {
-        match unsafe { ll::Pa_HostApiTypeIdToHostApiIndex(self as u32) }
+        match unsafe { ll::Pa_HostApiTypeIdToHostApiIndex(self.to_u32()) }
         {
             n if n >= 0 => Ok(n as HostApiIndex),
             m => to_pa_result(m).map(|_| 0),
@@ -48,29 +77,99 @@ impl HostApiType
     /// Get the enum value corresponding to the u32
     pub fn from_u32(num: u32) -> HostApiType
     {
-        match num {
-            ll::paInDevelopment => HostApiType::InDevelopment,
-            ll::paDirectSound => HostApiType::DirectSound,
-            ll::paMME => HostApiType::MME,
-            ll::paASIO => HostApiType::ASIO,
-            ll::paSoundManager => HostApiType::SoundManager,
-            ll::paCoreAudio => HostApiType::CoreAudio,
-            ll::paOSS => HostApiType::OSS,
-            ll::paALSA => HostApiType::ALSA,
-            ll::paAL => HostApiType::AL,
-            ll::paBeOS => HostApiType::BeOS,
-            ll::paWDMKS => HostApiType::WDMKS,
-            ll::paJACK => HostApiType::JACK,
-            ll::paWASAPI => HostApiType::WASAPI,
-            ll::paAudioScienceHPI => HostApiType::AudioScienceHPI,
-            _ => HostApiType::Unknown,
+        match HOST_API_TYPES.iter().find(|t| t.1 == num)
+        {
+            Some(t) => t.0,
+            None => HostApiType::Unknown(num),
+        }
+    }
+
+    /// Get the identifier PortAudio uses for the type
+    pub fn to_u32(self) -> u32
+    {
+        match self
+        {
+            HostApiType::Unknown(num) => num,
+            known => HOST_API_TYPES.iter().find(|t| t.0 == known).unwrap().1,
         }
     }
+
+    /// The stable name of the type, or None for an unknown type
+    pub fn name(self) -> Option<&'static str>
+    {
+        HOST_API_TYPES.iter().find(|t| t.0 == self).map(|t| t.2)
+    }
+
+    /// Information about the host API of this type
+    ///
+    /// Returns HostApiNotFound when it is not available.
+    pub fn info(self) -> Result<HostApiInfo, PaError>
+    {
+        get_info(self.to_api_index()?).ok_or(PaError::HostApiNotFound)
+    }
+
+    /// The devices of the host API of this type
+    pub fn devices(self) -> Result<Vec<(DeviceIndex, DeviceInfo)>, PaError>
+    {
+        Ok(self.info()?.devices().collect())
+    }
 }
 
+impl fmt::Display for HostApiType
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.name()
+        {
+            Some(name) => f.write_str(name),
+            None => write!(f, "unknown:{}", self.to_u32()),
+        }
+    }
+}
+
+impl FromStr for HostApiType
+{
+    type Err = ParseHostApiTypeError;
+
+    /// Parse a stable name, ignoring case, or "unknown:<id>" as written by Display
+    fn from_str(s: &str) -> Result<HostApiType, ParseHostApiTypeError>
+    {
+        let lower = s.trim().to_ascii_lowercase();
+        if let Some(t) = HOST_API_TYPES.iter().find(|t| t.2 == lower) { return Ok(t.0) }
+        if let Some(id) = lower.strip_prefix("unknown:").and_then(|id| id.parse().ok())
+        {
+            return Ok(HostApiType::from_u32(id))
+        }
+        Err(ParseHostApiTypeError { input: s.to_string() })
+    }
+}
+
+/// Error returned when parsing a HostApiType from an unknown name
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseHostApiTypeError
+{
+    input: String,
+}
+
+impl fmt::Display for ParseHostApiTypeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let names: Vec<&str> = HOST_API_TYPES.iter().map(|t| t.2).collect();
+        write!(f, "unknown host API type \"{}\", expected one of: {}", self.input, names.join(", "))
+    }
+}
+
+impl error::Error for ParseHostApiTypeError {}
+
 /// Information about a specific host API
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HostApiInfo
 {
+    /// Index of the API
+    pub index: HostApiIndex,
+
     /// The type of the API
     pub api_type: HostApiType,
 
@@ -89,10 +188,11 @@ pub struct HostApiInfo
 
 impl HostApiInfo
 {
-    fn from_ll(input: &ll::PaHostApiInfo) -> HostApiInfo
+    fn from_ll(index: HostApiIndex, input: &ll::PaHostApiInfo) -> HostApiInfo
     {
         HostApiInfo
         {
+            index,
             api_type: HostApiType::from_u32(input._type),
             name: String::from_utf8_lossy(unsafe { CStr::from_ptr(input.name).to_bytes() }).into_owned(),
             device_count: input.deviceCount as u32,
@@ -100,9 +200,61 @@ impl HostApiInfo
             default_output: match input.defaultOutputDevice { n if n >= 0 => Some(n as u32), _ => None },
         }
     }
+
+    /// Iterate over the devices of this API, with their global indices
+    pub fn devices(&self) -> HostApiDevices
+    {
+        HostApiDevices { host_api: self.index, next: 0, count: self.device_count }
+    }
+
+    /// The default input device of this API
+    pub fn default_input_device(&self) -> Option<(DeviceIndex, DeviceInfo)>
+    {
+        self.default_input.and_then(|i| device::get_info(i).map(|info| (i, info)))
+    }
+
+    /// The default output device of this API
+    pub fn default_output_device(&self) -> Option<(DeviceIndex, DeviceInfo)>
+    {
+        self.default_output.and_then(|i| device::get_info(i).map(|info| (i, info)))
+    }
+}
+
+/// Iterator over the devices of a host API, created by HostApiInfo::devices()
+pub struct HostApiDevices
+{
+    host_api: HostApiIndex,
+    next: u32,
+    count: u32,
+}
+
+impl Iterator for HostApiDevices
+{
+    type Item = (DeviceIndex, DeviceInfo);
+
+    fn next(&mut self) -> Option<(DeviceIndex, DeviceInfo)>
+    {
+        while self.next < self.count
+        {
+            let i = self.next;
+            self.next += 1;
+            if let Ok(index) = device::get_from_host_api_device_index(self.host_api, i)
+            {
+                if let Some(info) = device::get_info(index) { return Some((index, info)) }
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>)
+    {
+        (0, Some((self.count - self.next) as usize))
+    }
 }
 
 /// Error info obtained by get_last_error
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HostErrorInfo
 {
     /// The error code given
@@ -172,7 +324,120 @@ pub fn get_info(index: HostApiIndex) -> Option<HostApiInfo>
     {
         match ll::Pa_GetHostApiInfo(index as i32) {
             p if p.is_null() => None,
-            p => Some(HostApiInfo::from_ll(&*p)),
+            p => Some(HostApiInfo::from_ll(index, &*p)),
+        }
+    }
+}
+
+/// Iterate over the available host API's
+///
+/// Iterates over nothing when PortAudio is not initialized.
+pub fn host_apis() -> HostApis
+{
+    HostApis { next: 0, count: get_count().unwrap_or(0) }
+}
+
+/// Iterator over the host API's, created by host_apis()
+pub struct HostApis
+{
+    next: HostApiIndex,
+    count: u32,
+}
+
+impl Iterator for HostApis
+{
+    type Item = (HostApiIndex, HostApiInfo);
+
+    fn next(&mut self) -> Option<(HostApiIndex, HostApiInfo)>
+    {
+        while self.next < self.count
+        {
+            let index = self.next;
+            self.next += 1;
+            if let Some(info) = get_info(index) { return Some((index, info)) }
         }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>)
+    {
+        (0, Some((self.count - self.next) as usize))
+    }
+}
+
+/// Get the host API a device belongs to
+///
+/// Returns None when an invalid index is given
+pub fn host_api_for(device: DeviceIndex) -> Option<HostApiInfo>
+{
+    device::get_info(device).and_then(|info| get_info(info.host_api))
+}
+
+#[cfg(test)]
+mod test
+{
+    #[cfg(feature = "mock")] use mock::{self, MockDevice, MockHostApi, MockSystem};
+    #[cfg(feature = "mock")] use ll;
+    use super::HostApiType;
+
+    #[test]
+    fn names()
+    {
+        assert_eq!(HostApiType::ALSA.to_string(), "alsa");
+        assert_eq!("PulseAudio".parse(), Ok(HostApiType::PulseAudio));
+        assert_eq!(HostApiType::from_u32(17), HostApiType::Sndio);
+        assert_eq!(HostApiType::Sndio.to_u32(), 17);
+
+        let unknown = HostApiType::from_u32(99);
+        assert_eq!(unknown, HostApiType::Unknown(99));
+        assert_eq!(unknown.to_string(), "unknown:99");
+        assert_eq!(unknown.to_string().parse(), Ok(unknown));
+        assert!("pulse".parse::<HostApiType>().unwrap_err().to_string().contains("pulseaudio"));
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn enumerate_host_apis()
+    {
+        let mut system = MockSystem::default();
+        system.host_apis.push(MockHostApi { type_id: ll::paJACK, name: "JACK".to_string(), default_input: None, default_output: Some(3) });
+        system.devices.push(MockDevice::new("system", 1, 0, 2));
+        system.default_host_api = 1;
+        mock::configure(system);
+
+        ::initialize().unwrap();
+        assert_eq!(super::get_count().unwrap(), 2);
+        assert_eq!(super::get_default_index().unwrap(), 1);
+
+        let info = super::get_info(1).unwrap();
+        assert_eq!(info.name, "JACK");
+        assert_eq!(info.device_count, 1);
+        assert_eq!(info.default_input, None);
+        assert_eq!(info.default_output, Some(3));
+        assert_eq!(super::HostApiType::JACK.to_api_index().unwrap(), 1);
+        assert!(super::HostApiType::ASIO.to_api_index().is_err());
+
+        mock::set_host_error(ll::paJACK, 42, "server gone");
+        let error = super::get_last_error().unwrap();
+        assert_eq!((error.code, error.text.as_str()), (42, "server gone"));
+
+        assert_eq!(super::HostApiType::JACK.info().unwrap().name, "JACK");
+        let devices = super::HostApiType::JACK.devices().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!((devices[0].0, devices[0].1.name.as_str()), (3, "system"));
+        assert_eq!(super::HostApiType::PulseAudio.info().err(), Some(::PaError::HostApiNotFound));
+
+        let apis: Vec<_> = super::host_apis().map(|(i, info)| (i, info.name)).collect();
+        assert_eq!(apis, vec![(0, "Mock".to_string()), (1, "JACK".to_string())]);
+        assert_eq!(super::host_api_for(3).unwrap().index, 1);
+        assert_eq!(super::host_api_for(0).unwrap().name, "Mock");
+        assert!(super::host_api_for(4).is_none());
+
+        let mock_api = super::get_info(0).unwrap();
+        assert_eq!(mock_api.devices().map(|(i, _)| i).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(mock_api.default_output_device().unwrap().0, mock_api.default_output.unwrap());
+        assert_eq!(info.default_output_device().unwrap().1.name, "system");
+        assert!(info.default_input_device().is_none());
+        ::terminate().unwrap();
     }
 }
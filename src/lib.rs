@@ -42,13 +42,32 @@
 
 extern crate libc;
 #[macro_use] extern crate bitflags;
+#[macro_use] extern crate log;
+#[cfg(feature = "serde")] #[macro_use] extern crate serde;
 extern crate portaudio_sys as ll;
 
-pub use pa::{PaError, PaResult, initialize, terminate, version, version_text};
+pub use pa::{PaError, PaResult, VersionInfo, initialize, terminate, version, version_info, version_text};
+pub use error::{Error, ErrorKind, Operation};
 
 pub mod stream;
 mod pa;
+pub mod error;
 pub mod hostapi;
 pub mod device;
+pub mod spec;
+pub mod overrides;
+pub mod diagnostics;
+pub mod convert;
+pub mod resample;
+pub mod routing;
+pub mod mixer;
+pub mod supervisor;
+pub mod monitor;
+pub mod backend;
+pub mod offline;
+#[cfg(feature = "wav")] pub mod wav;
+#[cfg(feature = "mock")] pub use ll::mock;
 
 mod util;
+#[cfg(feature = "serde")] mod serialize;
+mod adapter;
@@ -0,0 +1,474 @@
+//! Mixing many sources into a single output stream
+//!
+//! Sources are added through a MixerHandle, which may be used from any thread. The audio thread
+//! only exchanges sources through bounded channels and reads atomics, so it never blocks or
+//! allocates. Finished and removed sources are handed back to be dropped on a control thread.
+
+use error::Error;
+use stream::{Stream, StreamCallback, StreamParameters, StreamFlags, StreamCallbackResult};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+/// Number of frames mixed at a time, independent of the buffer size of the stream
+const CHUNK_FRAMES: usize = 256;
+
+/// A producer of audio for the mixer
+pub trait Source: Send
+{
+    /// Fill the buffer with interleaved frames of the given number of channels
+    ///
+    /// Returns the number of frames written. When this is less than the buffer holds, the source
+    /// is finished and will be removed from the mixer.
+    fn fill(&mut self, buffer: &mut [f32], channels: u32) -> usize;
+}
+
+impl<F: FnMut(&mut [f32], u32) -> usize + Send> Source for F
+{
+    fn fill(&mut self, buffer: &mut [f32], channels: u32) -> usize
+    {
+        self(buffer, channels)
+    }
+}
+
+/// A source playing interleaved samples from memory once
+pub struct BufferSource
+{
+    samples: Vec<f32>,
+    position: usize,
+}
+
+impl BufferSource
+{
+    /// Create a source for samples with the same number of channels as the mixer
+    pub fn new(samples: Vec<f32>) -> BufferSource
+    {
+        BufferSource { samples, position: 0 }
+    }
+}
+
+impl Source for BufferSource
+{
+    fn fill(&mut self, buffer: &mut [f32], channels: u32) -> usize
+    {
+        let remaining = &self.samples[self.position ..];
+        let count = remaining.len().min(buffer.len());
+        buffer[.. count].copy_from_slice(&remaining[.. count]);
+        self.position += count;
+        count / channels as usize
+    }
+}
+
+// Settings shared between a SourceHandle and the audio thread
+struct Control
+{
+    gain: AtomicU32,
+    pan: AtomicU32,
+    muted: AtomicBool,
+    removed: AtomicBool,
+    finished: AtomicBool,
+}
+
+struct Entry
+{
+    source: Box<dyn Source>,
+    control: Arc<Control>,
+    // Gains applied at the end of the previous chunk, to ramp from
+    left: f32,
+    right: f32,
+}
+
+impl Entry
+{
+    // The gains for the left and right channel, or for every channel when not stereo
+    fn target(&self, channels: u32) -> (f32, f32)
+    {
+        if self.control.muted.load(Ordering::Relaxed) { return (0.0, 0.0) }
+
+        let gain = f32::from_bits(self.control.gain.load(Ordering::Relaxed));
+        if channels != 2 { return (gain, gain) }
+
+        // Balance: the far side is attenuated, the near side keeps its level
+        let pan = f32::from_bits(self.control.pan.load(Ordering::Relaxed));
+        (gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0))
+    }
+}
+
+// State of the mixer that lives on the audio thread
+struct Engine
+{
+    channels: u32,
+    sources: Vec<Entry>,
+    added: Receiver<Entry>,
+    garbage: SyncSender<Entry>,
+    scratch: Vec<f32>,
+}
+
+impl Engine
+{
+    fn mix(&mut self, output: &mut [f32])
+    {
+        while self.sources.len() < self.sources.capacity()
+        {
+            match self.added.try_recv()
+            {
+                Ok(mut entry) =>
+                {
+                    // Start at the gains set since the source was added, rather than ramping
+                    // to them from unity
+                    let (left, right) = entry.target(self.channels);
+                    entry.left = left;
+                    entry.right = right;
+                    self.sources.push(entry);
+                },
+                Err(_) => break,
+            }
+        }
+
+        for v in output.iter_mut() { *v = 0.0; }
+
+        let channels = self.channels as usize;
+        for chunk in output.chunks_mut(CHUNK_FRAMES * channels)
+        {
+            let frames = chunk.len() / channels;
+            let mut i = 0;
+            while i < self.sources.len()
+            {
+                let done = self.mix_source(i, chunk, frames);
+                if done
+                {
+                    let entry = self.sources.swap_remove(i);
+                    entry.control.finished.store(true, Ordering::Release);
+                    // The channel has room for every source, so this only fails after the mixer
+                    // is gone, in which case the source is dropped here
+                    let _ = self.garbage.try_send(entry);
+                }
+                else
+                {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // Mix one source into the chunk, and return whether it is done
+    fn mix_source(&mut self, index: usize, chunk: &mut [f32], frames: usize) -> bool
+    {
+        let channels = self.channels as usize;
+        let entry = &mut self.sources[index];
+        if entry.control.removed.load(Ordering::Acquire) { return true }
+
+        let scratch = &mut self.scratch[.. chunk.len()];
+        let written = entry.source.fill(scratch, self.channels).min(frames);
+
+        // Ramp the gains over the chunk to avoid clicks when they change
+        let (left, right) = entry.target(self.channels);
+        let step = 1.0 / frames as f32;
+        for (f, (out, input)) in chunk.chunks_mut(channels).zip(scratch.chunks(channels)).take(written).enumerate()
+        {
+            let t = f as f32 * step;
+            let l = entry.left + (left - entry.left) * t;
+            let r = entry.right + (right - entry.right) * t;
+            for (c, (o, &v)) in out.iter_mut().zip(input.iter()).enumerate()
+            {
+                *o += v * if c == 1 { r } else { l };
+            }
+        }
+        entry.left = left;
+        entry.right = right;
+
+        written < frames
+    }
+}
+
+// State shared by all handles of a mixer
+struct Shared
+{
+    capacity: usize,
+    active: AtomicUsize,
+    added: Mutex<SyncSender<Entry>>,
+    garbage: Mutex<Receiver<Entry>>,
+}
+
+impl Shared
+{
+    // Drop the sources the audio thread is done with
+    fn collect(&self)
+    {
+        let garbage = self.garbage.lock().unwrap();
+        while garbage.try_recv().is_ok()
+        {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Adds sources to a Mixer from any thread
+#[derive(Clone)]
+pub struct MixerHandle
+{
+    shared: Arc<Shared>,
+}
+
+impl MixerHandle
+{
+    /// Add a source to the mixer, at unity gain and centered
+    ///
+    /// Returns None when the mixer already holds its maximum number of sources.
+    pub fn add<S: Source + 'static>(&self, source: S) -> Option<SourceHandle>
+    {
+        self.shared.collect();
+
+        let active = &self.shared.active;
+        if active.fetch_add(1, Ordering::AcqRel) >= self.shared.capacity
+        {
+            active.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+
+        let control = Arc::new(Control
+        {
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            pan: AtomicU32::new(0.0f32.to_bits()),
+            muted: AtomicBool::new(false),
+            removed: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+        });
+        // The audio thread sets the gains to ramp from when it takes the entry
+        let entry = Entry { source: Box::new(source), control: control.clone(), left: 0.0, right: 0.0 };
+
+        if self.shared.added.lock().unwrap().try_send(entry).is_err()
+        {
+            active.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+
+        Some(SourceHandle { control, detached: false })
+    }
+
+    /// The number of sources that are playing or waiting to be dropped
+    pub fn active(&self) -> usize
+    {
+        self.shared.collect();
+        self.shared.active.load(Ordering::Acquire)
+    }
+}
+
+/// Controls a source added to a Mixer
+///
+/// The source is removed when the handle is dropped, unless it was detached.
+pub struct SourceHandle
+{
+    control: Arc<Control>,
+    detached: bool,
+}
+
+impl SourceHandle
+{
+    /// Set the linear gain of the source
+    pub fn set_gain(&self, gain: f32)
+    {
+        self.control.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Set the position of the source between left (-1.0) and right (1.0). Only has an effect
+    /// on stereo mixers.
+    pub fn set_pan(&self, pan: f32)
+    {
+        self.control.pan.store(pan.clamp(-1.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Silence the source without removing it
+    pub fn set_muted(&self, muted: bool)
+    {
+        self.control.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// Whether the source finished or was removed, and is no longer mixed
+    pub fn is_finished(&self) -> bool
+    {
+        self.control.finished.load(Ordering::Acquire)
+    }
+
+    /// Remove the source from the mixer
+    pub fn remove(self)
+    {
+    }
+
+    /// Let the source play until it finishes, without keeping the handle
+    pub fn detach(mut self)
+    {
+        self.detached = true;
+    }
+}
+
+impl Drop for SourceHandle
+{
+    fn drop(&mut self)
+    {
+        if !self.detached
+        {
+            self.control.removed.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// An output stream playing the mix of its sources
+pub struct Mixer
+{
+    stream: Stream<'static, f32, f32>,
+    handle: MixerHandle,
+}
+
+impl Mixer
+{
+    /// Open a mixer on the given output
+    ///
+    /// * output: The output device and its channel count
+    /// * sample_rate: Sample rate of the stream
+    /// * frames_per_buffer: Number of frames per buffer, or FRAMES_PER_BUFFER_UNSPECIFIED
+    /// * max_sources: The maximum number of sources playing at the same time
+    pub fn open(output: StreamParameters<f32>,
+                sample_rate: f64,
+                frames_per_buffer: u64,
+                max_sources: usize) -> Result<Mixer, Error>
+    {
+        let (engine, shared) = engine(output.channel_count, max_sources);
+        let callback = mix_callback(engine);
+        let stream = Stream::open(None, Some(output), sample_rate, frames_per_buffer, StreamFlags::empty(), Some(callback))?;
+        Ok(Mixer { stream, handle: MixerHandle { shared: Arc::new(shared) } })
+    }
+
+    /// Open a mixer on the default output device
+    pub fn open_default(channels: u32,
+                        sample_rate: f64,
+                        frames_per_buffer: u64,
+                        max_sources: usize) -> Result<Mixer, Error>
+    {
+        let (engine, shared) = engine(channels, max_sources);
+        let callback = mix_callback(engine);
+        let stream = Stream::open_default(0, channels, sample_rate, frames_per_buffer, Some(callback))?;
+        Ok(Mixer { stream, handle: MixerHandle { shared: Arc::new(shared) } })
+    }
+
+    /// Starts the stream of the mixer
+    pub fn start(&self) -> Result<(), Error>
+    {
+        self.stream.start()
+    }
+
+    /// Stops the stream of the mixer
+    pub fn stop(&self) -> Result<(), Error>
+    {
+        self.stream.stop()
+    }
+
+    /// The stream the mixer plays on
+    pub fn stream(&self) -> &Stream<'static, f32, f32>
+    {
+        &self.stream
+    }
+
+    /// A handle to add sources, which can be sent to other threads
+    pub fn handle(&self) -> MixerHandle
+    {
+        self.handle.clone()
+    }
+
+    /// Add a source to the mixer. See MixerHandle::add().
+    pub fn add<S: Source + 'static>(&self, source: S) -> Option<SourceHandle>
+    {
+        self.handle.add(source)
+    }
+}
+
+fn engine(channels: u32, max_sources: usize) -> (Engine, Shared)
+{
+    let (added_tx, added_rx) = mpsc::sync_channel(max_sources);
+    let (garbage_tx, garbage_rx) = mpsc::sync_channel(max_sources);
+
+    let engine = Engine
+    {
+        channels,
+        sources: Vec::with_capacity(max_sources),
+        added: added_rx,
+        garbage: garbage_tx,
+        scratch: vec![0.0; CHUNK_FRAMES * channels as usize],
+    };
+    let shared = Shared
+    {
+        capacity: max_sources,
+        active: AtomicUsize::new(0),
+        added: Mutex::new(added_tx),
+        garbage: Mutex::new(garbage_rx),
+    };
+    (engine, shared)
+}
+
+fn mix_callback(mut engine: Engine) -> Box<StreamCallback<'static, f32, f32>>
+{
+    Box::new(move |_, output, _, _|
+    {
+        engine.mix(output);
+        StreamCallbackResult::Continue
+    })
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{engine, BufferSource, MixerHandle};
+    use std::sync::Arc;
+
+    #[test]
+    fn mix_and_finish()
+    {
+        let (mut engine, shared) = engine(2, 4);
+        let handle = MixerHandle { shared: Arc::new(shared) };
+
+        let a = handle.add(BufferSource::new(vec![0.25; 2 * 300])).unwrap();
+        let b = handle.add(|buffer: &mut [f32], channels: u32| { for v in buffer.iter_mut() { *v = 0.5; } buffer.len() / channels as usize }).unwrap();
+        b.set_pan(1.0);
+
+        let mut output = vec![0.0; 2 * 512];
+        engine.mix(&mut output);
+        assert_eq!(&output[.. 2], &[0.25, 0.75]);
+        assert_eq!(&output[2 * 400 .. 2 * 401], &[0.0, 0.5]);
+
+        assert!(a.is_finished());
+        assert!(!b.is_finished());
+        assert_eq!(handle.active(), 1);
+    }
+
+    #[test]
+    fn starts_at_the_gain_set_after_add()
+    {
+        let (mut engine, shared) = engine(2, 1);
+        let handle = MixerHandle { shared: Arc::new(shared) };
+
+        let source = handle.add(BufferSource::new(vec![1.0; 2 * 256])).unwrap();
+        source.set_gain(0.5);
+        source.set_pan(-1.0);
+
+        let mut output = vec![0.0; 2 * 256];
+        engine.mix(&mut output);
+        assert!(output.chunks(2).all(|frame| frame == [0.5, 0.0]), "{:?}", &output[.. 8]);
+    }
+
+    #[test]
+    fn capacity_and_removal()
+    {
+        let (mut engine, shared) = engine(1, 1);
+        let handle = MixerHandle { shared: Arc::new(shared) };
+
+        let silence = |buffer: &mut [f32], _: u32| { for v in buffer.iter_mut() { *v = 0.0; } buffer.len() };
+        let source = handle.add(silence).unwrap();
+        assert!(handle.add(silence).is_none());
+
+        source.remove();
+        let mut output = vec![0.0; 64];
+        engine.mix(&mut output);
+        assert_eq!(handle.active(), 0);
+        assert!(handle.add(silence).is_some());
+    }
+}
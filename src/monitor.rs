@@ -0,0 +1,401 @@
+//! Watching the device list for devices that come and go
+//!
+//! PortAudio only scans the devices when it is initialized. A DeviceMonitor rescans them by
+//! terminating PortAudio and initializing it again, and reports what changed. As device indices
+//! shift between scans, devices are matched by their Fingerprint.
+//!
+//! A rescan would close every open stream, so it fails with StreamsOpen while any stream is
+//! open, and poll() tries again. It also fails with InitializedMoreThanOnce when initialize() was
+//! called more than once, as terminating would then not actually rescan.
+
+use device::{self, DeviceIndex, DeviceInfo};
+use error::Error;
+use hostapi::{self, HostApiType};
+use pa::PaError;
+use stream;
+use std::time::{Duration, Instant};
+
+/// What identifies a device across scans of the device list
+///
+/// Identical devices are told apart by the order in which they are listed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint
+{
+    /// The type of the host API of the device
+    pub api_type: HostApiType,
+
+    /// Name of the device
+    pub name: String,
+
+    /// Maximal number of input channels
+    pub max_input_channels: u32,
+
+    /// Maximal number of output channels
+    pub max_output_channels: u32,
+
+    /// The number of identical devices listed before this one
+    pub ordinal: u32,
+}
+
+/// A change of the device list
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent
+{
+    /// A device appeared
+    Added
+    {
+        /// Index of the device
+        index: DeviceIndex,
+        /// The fingerprint of the device
+        fingerprint: Fingerprint,
+    },
+
+    /// A device disappeared
+    Removed
+    {
+        /// The fingerprint of the device
+        fingerprint: Fingerprint,
+    },
+
+    /// A device is still present, at another index
+    Moved
+    {
+        /// The fingerprint of the device
+        fingerprint: Fingerprint,
+        /// The previous index of the device
+        from: DeviceIndex,
+        /// The current index of the device
+        to: DeviceIndex,
+    },
+
+    /// Another device became the default input device, or there no longer is one
+    DefaultInputChanged(Option<DeviceIndex>),
+
+    /// Another device became the default output device, or there no longer is one
+    DefaultOutputChanged(Option<DeviceIndex>),
+}
+
+/// A device found by the monitor
+#[derive(Clone)]
+pub struct MonitoredDevice
+{
+    /// Index of the device
+    pub index: DeviceIndex,
+
+    /// The fingerprint of the device
+    pub fingerprint: Fingerprint,
+
+    /// Information about the device
+    pub info: DeviceInfo,
+}
+
+/// Reports changes of the device list, see the module documentation
+pub struct DeviceMonitor
+{
+    devices: Vec<MonitoredDevice>,
+    default_input: Option<Fingerprint>,
+    default_output: Option<Fingerprint>,
+    interval: Duration,
+    last_scan: Instant,
+    // A rescan was due but could not take place yet
+    pending: bool,
+    #[cfg(target_os = "linux")]
+    uevents: Option<uevent::Socket>,
+}
+
+impl DeviceMonitor
+{
+    /// Start monitoring the devices, rescanning them every interval when polled
+    ///
+    /// PortAudio must be initialized.
+    pub fn new(interval: Duration) -> Result<DeviceMonitor, PaError>
+    {
+        device::get_count()?;
+        let mut monitor = DeviceMonitor
+        {
+            devices: Vec::new(),
+            default_input: None,
+            default_output: None,
+            interval,
+            last_scan: Instant::now(),
+            pending: false,
+            #[cfg(target_os = "linux")]
+            uevents: None,
+        };
+        monitor.take_snapshot();
+        Ok(monitor)
+    }
+
+    /// The devices as of the last scan
+    pub fn devices(&self) -> &[MonitoredDevice]
+    {
+        &self.devices
+    }
+
+    /// The current index of the device with the given fingerprint
+    pub fn find(&self, fingerprint: &Fingerprint) -> Option<DeviceIndex>
+    {
+        self.devices.iter().find(|d| d.fingerprint == *fingerprint).map(|d| d.index)
+    }
+
+    /// Rescan the devices when the interval has passed since the last scan, or when a sound
+    /// device event arrived if watch_udev() is used
+    ///
+    /// When the rescan fails, such as with StreamsOpen, the next poll tries again.
+    pub fn poll(&mut self) -> Result<Vec<DeviceEvent>, Error>
+    {
+        if self.pending || self.is_due()
+        {
+            self.pending = true;
+            self.refresh()
+        }
+        else
+        {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Rescan the devices now and return the changes
+    ///
+    /// Fails with StreamsOpen, of ErrorKind::Busy, while streams are open, and with
+    /// InitializedMoreThanOnce when initialize() was called more than once. Streams opened on
+    /// other threads wait until the rescan is done.
+    pub fn refresh(&mut self) -> Result<Vec<DeviceEvent>, Error>
+    {
+        stream::reinitialize()?;
+        self.last_scan = Instant::now();
+        self.pending = false;
+
+        let previous = ::std::mem::take(&mut self.devices);
+        let (default_input, default_output) = (self.default_input.take(), self.default_output.take());
+        self.take_snapshot();
+
+        let mut events = Vec::new();
+        for old in &previous
+        {
+            match self.devices.iter().find(|d| d.fingerprint == old.fingerprint)
+            {
+                None => events.push(DeviceEvent::Removed { fingerprint: old.fingerprint.clone() }),
+                Some(new) if new.index != old.index =>
+                    events.push(DeviceEvent::Moved { fingerprint: old.fingerprint.clone(), from: old.index, to: new.index }),
+                Some(_) => {},
+            }
+        }
+        for new in &self.devices
+        {
+            if !previous.iter().any(|d| d.fingerprint == new.fingerprint)
+            {
+                events.push(DeviceEvent::Added { index: new.index, fingerprint: new.fingerprint.clone() });
+            }
+        }
+        if self.default_input != default_input
+        {
+            events.push(DeviceEvent::DefaultInputChanged(device::get_default_input_index()));
+        }
+        if self.default_output != default_output
+        {
+            events.push(DeviceEvent::DefaultOutputChanged(device::get_default_output_index()));
+        }
+        Ok(events)
+    }
+
+    /// Rescan when the kernel reports changes of sound devices, instead of every interval
+    ///
+    /// Listens to the events udev broadcasts after it has set up a device.
+    #[cfg(target_os = "linux")]
+    pub fn watch_udev(&mut self) -> ::std::io::Result<()>
+    {
+        self.uevents = Some(uevent::Socket::open()?);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn is_due(&mut self) -> bool
+    {
+        match self.uevents
+        {
+            Some(ref mut socket) => socket.sound_changed(),
+            None => self.last_scan.elapsed() >= self.interval,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn is_due(&mut self) -> bool
+    {
+        self.last_scan.elapsed() >= self.interval
+    }
+
+    fn take_snapshot(&mut self)
+    {
+        let count = device::get_count().unwrap_or(0);
+        let mut devices: Vec<MonitoredDevice> = Vec::new();
+        for index in 0..count
+        {
+            let info = match device::get_info(index) { Some(info) => info, None => continue };
+            let mut fingerprint = Fingerprint
+            {
+                api_type: hostapi::get_info(info.host_api).map_or(HostApiType::Unknown(0), |api| api.api_type),
+                name: info.name.clone(),
+                max_input_channels: info.max_input_channels,
+                max_output_channels: info.max_output_channels,
+                ordinal: 0,
+            };
+            fingerprint.ordinal = devices.iter().filter(|d| d.fingerprint == fingerprint).count() as u32;
+            devices.push(MonitoredDevice { index, fingerprint, info });
+        }
+
+        let fingerprint = |index: Option<DeviceIndex>| index.and_then(|i| devices.iter().find(|d| d.index == i)).map(|d| d.fingerprint.clone());
+        self.default_input = fingerprint(device::get_default_input_index());
+        self.default_output = fingerprint(device::get_default_output_index());
+        self.devices = devices;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod uevent
+{
+    use libc;
+    use std::{io, mem};
+    use std::time::{Duration, Instant};
+
+    // The multicast group of the events udev sends after processing those of the kernel
+    const UDEV_GROUP: u32 = 2;
+
+    // Devices send several events while they are set up, so scan once they have settled
+    const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+    pub struct Socket
+    {
+        fd: libc::c_int,
+        last_event: Option<Instant>,
+    }
+
+    impl Socket
+    {
+        pub fn open() -> io::Result<Socket>
+        {
+            unsafe
+            {
+                let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                                      libc::NETLINK_KOBJECT_UEVENT);
+                if fd < 0 { return Err(io::Error::last_os_error()) }
+                let socket = Socket { fd, last_event: None };
+
+                let mut address: libc::sockaddr_nl = mem::zeroed();
+                address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+                address.nl_groups = UDEV_GROUP;
+                if libc::bind(fd, &address as *const _ as *const libc::sockaddr, mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(socket)
+            }
+        }
+
+        // Returns wether sound devices changed and have settled since the last time
+        pub fn sound_changed(&mut self) -> bool
+        {
+            let mut buffer = [0u8; 8192];
+            loop
+            {
+                let n = unsafe { libc::recv(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+                if n <= 0 { break }
+                if buffer[.. n as usize].split(|&b| b == 0).any(|field| field == b"SUBSYSTEM=sound")
+                {
+                    self.last_event = Some(Instant::now());
+                }
+            }
+
+            match self.last_event
+            {
+                Some(time) if time.elapsed() >= SETTLE_TIME => { self.last_event = None; true },
+                _ => false,
+            }
+        }
+    }
+
+    impl Drop for Socket
+    {
+        fn drop(&mut self)
+        {
+            unsafe { libc::close(self.fd); }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod test
+{
+    use super::{DeviceEvent, DeviceMonitor};
+    use error::ErrorKind;
+    use mock::{self, MockDevice, MockSystem};
+    use pa::PaError;
+    use stream::Stream;
+    use std::time::Duration;
+
+    #[test]
+    fn reports_changes()
+    {
+        mock::configure(MockSystem::default());
+        ::initialize().unwrap();
+        let mut monitor = DeviceMonitor::new(Duration::from_secs(3600)).unwrap();
+        assert_eq!(monitor.devices().len(), 3);
+        assert!(monitor.poll().unwrap().is_empty());
+
+        let mut system = MockSystem::default();
+        system.devices.remove(0);
+        system.devices.push(MockDevice::new("Mock Output", 0, 0, 2));
+        system.host_apis[0].default_input = Some(1);
+        system.host_apis[0].default_output = Some(2);
+        mock::configure(system);
+
+        // Rescans are refused while streams are open
+        let stream = Stream::<f32, f32>::open_default(0, 2, 48_000.0, 0, None).unwrap();
+        let error = monitor.refresh().unwrap_err();
+        assert_eq!(error, PaError::StreamsOpen);
+        assert_eq!(error.kind(), ErrorKind::Busy);
+        assert!(monitor.poll().is_ok());
+        assert_eq!(monitor.devices().len(), 3);
+        drop(stream);
+
+        let events = monitor.refresh().unwrap();
+        let output = monitor.devices()[0].fingerprint.clone();
+        let duplex = monitor.devices()[1].fingerprint.clone();
+        let second_output = monitor.devices()[2].fingerprint.clone();
+        assert_eq!((output.ordinal, second_output.ordinal), (0, 1));
+        assert_eq!(events.len(), 6);
+        assert!(match events[0] { DeviceEvent::Removed { ref fingerprint } => fingerprint.name == "Mock Input", _ => false });
+        assert_eq!(events[1], DeviceEvent::Moved { fingerprint: output, from: 1, to: 0 });
+        assert_eq!(events[2], DeviceEvent::Moved { fingerprint: duplex.clone(), from: 2, to: 1 });
+        assert_eq!(events[3], DeviceEvent::Added { index: 2, fingerprint: second_output });
+        assert_eq!(events[4], DeviceEvent::DefaultInputChanged(Some(1)));
+        assert_eq!(events[5], DeviceEvent::DefaultOutputChanged(Some(2)));
+        assert_eq!(monitor.find(&duplex), Some(1));
+
+        // A refresh that failed to initialize again does not keep later ones from working
+        mock::fail_next(mock::Operation::Initialize, ::ll::paInsufficientMemory);
+        assert!(monitor.refresh().is_err());
+        assert!(monitor.refresh().unwrap().is_empty());
+        ::terminate().unwrap();
+    }
+
+    #[test]
+    fn refuses_nested_initialize()
+    {
+        mock::configure(MockSystem::default());
+        ::initialize().unwrap();
+        ::initialize().unwrap();
+        let mut monitor = DeviceMonitor::new(Duration::from_secs(3600)).unwrap();
+
+        let mut system = MockSystem::default();
+        system.devices.remove(0);
+        mock::configure(system);
+        assert_eq!(monitor.refresh().unwrap_err(), PaError::InitializedMoreThanOnce);
+        assert_eq!(monitor.devices().len(), 3);
+
+        ::terminate().unwrap();
+        assert_eq!(monitor.refresh().unwrap().len(), 5);
+        assert_eq!(monitor.devices().len(), 2);
+        ::terminate().unwrap();
+    }
+}
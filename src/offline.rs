@@ -0,0 +1,272 @@
+//! Running a stream callback offline, as fast as the CPU allows
+//!
+//! The Renderer drives the same callback a Stream would, from a virtual clock. Its buffer sizes,
+//! latencies and callback flags can be chosen, which makes it useful to render exports and to
+//! test signal processing without sound hardware.
+
+use stream::{StreamCallback, StreamCallbackFlags, StreamCallbackResult, StreamTimeInfo, SampleType};
+use convert::{Conversion, Converter, Dither};
+#[cfg(feature = "wav")] use wav::{self, WavReader, WavWriter};
+#[cfg(feature = "wav")] use pa::PaError;
+#[cfg(feature = "wav")] use std::io::{Read, Write, Seek};
+use std::time::Duration;
+
+/// Drives a stream callback from a virtual clock
+pub struct Renderer<'a, I, O>
+{
+    callback: Box<StreamCallback<'a, I, O>>,
+    input_channels: u32,
+    output_channels: u32,
+    sample_rate: f64,
+    buffer_sizes: Vec<u32>,
+    next_size: usize,
+    input_latency: Duration,
+    output_latency: Duration,
+    flags: Vec<(u64, StreamCallbackFlags)>,
+    frames: u64,
+    finished: bool,
+    input: Vec<I>,
+    output: Vec<O>,
+    input_silence: I,
+    output_silence: O,
+}
+
+impl<'a, I: SampleType + Copy + Default, O: SampleType + Copy + Default> Renderer<'a, I, O>
+{
+    /// Create a renderer calling the callback with buffers of frames_per_buffer frames
+    ///
+    /// ## Panics
+    /// When frames_per_buffer is 0.
+    pub fn new(input_channels: u32,
+               output_channels: u32,
+               sample_rate: f64,
+               frames_per_buffer: u32,
+               callback: Box<StreamCallback<'a, I, O>>)
+              -> Renderer<'a, I, O>
+    {
+        assert!(frames_per_buffer > 0, "frames_per_buffer must not be 0");
+        let mut converter = Converter::new(Conversion { dither: Dither::None, clip: true });
+        let (mut input_silence, mut output_silence) = ([I::default()], [O::default()]);
+        converter.convert(&[0.0f32], &mut input_silence);
+        converter.convert(&[0.0f32], &mut output_silence);
+
+        Renderer
+        {
+            callback,
+            input_channels,
+            output_channels,
+            sample_rate,
+            buffer_sizes: vec![frames_per_buffer],
+            next_size: 0,
+            input_latency: Duration::from_secs(0),
+            output_latency: Duration::from_secs(0),
+            flags: Vec::new(),
+            frames: 0,
+            finished: false,
+            input: Vec::new(),
+            output: Vec::new(),
+            input_silence: input_silence[0],
+            output_silence: output_silence[0],
+        }
+    }
+
+    /// Call the callback with buffers of the given sizes in turn, repeating the sequence
+    ///
+    /// Varying sizes mimic host APIs that do not use a fixed buffer size.
+    ///
+    /// ## Panics
+    /// When no sizes are given or a size is 0.
+    pub fn set_buffer_sizes(&mut self, sizes: &[u32])
+    {
+        assert!(!sizes.is_empty() && !sizes.contains(&0), "buffer sizes must be given and not be 0");
+        self.buffer_sizes = sizes.to_vec();
+        self.next_size = 0;
+    }
+
+    /// Set the latencies used to synthesize the ADC and DAC times of the StreamTimeInfo
+    pub fn set_latency(&mut self, input: Duration, output: Duration)
+    {
+        self.input_latency = input;
+        self.output_latency = output;
+    }
+
+    /// Pass flags to the callback whose buffer contains the given frame
+    pub fn add_flags(&mut self, frame: u64, flags: StreamCallbackFlags)
+    {
+        self.flags.push((frame, flags));
+    }
+
+    /// The number of frames rendered so far
+    pub fn frames(&self) -> u64
+    {
+        self.frames
+    }
+
+    /// The position of the virtual clock
+    pub fn time(&self) -> Duration
+    {
+        Duration::from_secs_f64(self.frames as f64 / self.sample_rate)
+    }
+
+    /// Returns wether the callback has completed or aborted
+    pub fn is_finished(&self) -> bool
+    {
+        self.finished
+    }
+
+    /// Render from interleaved input samples
+    ///
+    /// Renders the given number of frames, with silence after the end of the input. When frames
+    /// is None, renders until the input runs out, or until the callback finishes if there are no
+    /// input channels. Rendering stops early when the callback completes or aborts, after
+    /// including the output of its last buffer.
+    pub fn render(&mut self, input: &[I], frames: Option<u64>) -> Vec<O>
+    {
+        let mut position = 0;
+        let mut output = Vec::new();
+        let result: Result<u64, ()> = self.render_with(frames, |buffer|
+        {
+            let count = buffer.len().min(input.len() - position);
+            buffer[.. count].copy_from_slice(&input[position .. position + count]);
+            position += count;
+            Ok(count)
+        },
+        |buffer|
+        {
+            output.extend_from_slice(buffer);
+            Ok(())
+        });
+        result.unwrap();
+        output
+    }
+
+    /// Render with input and output through closures, returning the number of frames rendered
+    ///
+    /// `read` fills the interleaved input buffer and returns the number of samples it filled,
+    /// which is 0 at the end of the input. The rest of the buffer is silence. `write` receives
+    /// the interleaved output of each callback. Stops like Renderer::render(), or at the first
+    /// error.
+    pub fn render_with<R, W, E>(&mut self, frames: Option<u64>, mut read: R, mut write: W) -> Result<u64, E>
+        where R: FnMut(&mut [I]) -> Result<usize, E>, W: FnMut(&[O]) -> Result<(), E>
+    {
+        let start = self.frames;
+        while !self.finished
+        {
+            let rendered = self.frames - start;
+            let mut count = self.buffer_sizes[self.next_size] as u64;
+            if let Some(total) = frames
+            {
+                if rendered >= total { break }
+                count = count.min(total - rendered);
+            }
+            let count = count as usize;
+
+            let samples = count * self.input_channels as usize;
+            self.input.clear();
+            self.input.resize(samples, self.input_silence);
+            let read = read(&mut self.input)?;
+            if read == 0 && frames.is_none() && self.input_channels > 0 { break }
+            for sample in &mut self.input[read ..] { *sample = self.input_silence; }
+
+            self.process(count);
+            write(&self.output)?;
+        }
+        Ok(self.frames - start)
+    }
+
+    fn process(&mut self, count: usize)
+    {
+        self.output.clear();
+        self.output.resize(count * self.output_channels as usize, self.output_silence);
+
+        let (begin, end) = (self.frames, self.frames + count as u64);
+        let mut flags = StreamCallbackFlags::empty();
+        self.flags.retain(|&(frame, f)|
+        {
+            if frame >= begin && frame < end { flags |= f; false } else { frame >= begin }
+        });
+
+        let now = self.time();
+        let time_info = StreamTimeInfo
+        {
+            input_adc_time: now.checked_sub(self.input_latency).unwrap_or_default(),
+            current_time: now,
+            output_dac_time: now + self.output_latency,
+        };
+
+        let result = (self.callback)(&self.input, &mut self.output, time_info, flags);
+        self.frames = end;
+        self.next_size = (self.next_size + 1) % self.buffer_sizes.len();
+        self.finished = result != StreamCallbackResult::Continue;
+    }
+
+    /// Render a WAV file through the callback into another WAV file
+    ///
+    /// The input file needs to have as many channels as the input of the renderer, and the
+    /// output file as many as its output. Stops like Renderer::render(). Returns the number of
+    /// frames rendered.
+    #[cfg(feature = "wav")]
+    pub fn render_wav<R, W>(&mut self, reader: &mut WavReader<R>, writer: &mut WavWriter<W>, frames: Option<u64>) -> Result<u64, wav::Error>
+        where R: Read + Seek, W: Write + Seek
+    {
+        if reader.spec().channels as u32 != self.input_channels || writer.spec().channels as u32 != self.output_channels
+        {
+            return Err(PaError::InvalidChannelCount.into())
+        }
+
+        self.render_with(frames, |buffer| reader.read_samples(buffer).map_err(wav::Error::from),
+                                 |buffer| writer.write_samples(buffer).map_err(wav::Error::from))
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Renderer;
+    use stream::{StreamCallbackFlags, StreamCallbackResult};
+    use std::time::Duration;
+
+    #[test]
+    fn render_buffer()
+    {
+        let mut sizes = Vec::new();
+        let mut renderer = Renderer::new(1, 2, 1000.0, 4, Box::new(|input: &[f32], output: &mut [f32], time, flags|
+        {
+            sizes.push((input.len(), time.current_time, flags));
+            for (frame, &v) in output.chunks_mut(2).zip(input) { frame[0] = v; frame[1] = -v; }
+            StreamCallbackResult::Continue
+        }));
+        renderer.set_buffer_sizes(&[2, 3]);
+        renderer.add_flags(4, StreamCallbackFlags::INPUT_OVERFLOW);
+
+        let output = renderer.render(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6], None);
+        assert_eq!(output, vec![0.1, -0.1, 0.2, -0.2, 0.3, -0.3, 0.4, -0.4, 0.5, -0.5, 0.6, -0.6, 0.0, -0.0]);
+        assert_eq!(renderer.frames(), 7);
+        assert_eq!(renderer.render(&[], Some(3)).len(), 6);
+        drop(renderer);
+
+        assert_eq!(sizes.iter().map(|s| s.0).collect::<Vec<_>>(), vec![2, 3, 2, 3]);
+        assert_eq!(sizes[2].1, Duration::from_millis(5));
+        assert_eq!(sizes.iter().map(|s| s.2).collect::<Vec<_>>(),
+                   vec![StreamCallbackFlags::empty(), StreamCallbackFlags::INPUT_OVERFLOW, StreamCallbackFlags::empty(),
+                        StreamCallbackFlags::empty()]);
+    }
+
+    #[test]
+    fn stops_when_complete()
+    {
+        let mut calls = 0;
+        let mut renderer = Renderer::new(0, 1, 48000.0, 16, Box::new(move |_: &[f32], output: &mut [i16], _, _|
+        {
+            calls += 1;
+            for v in output.iter_mut() { *v = calls; }
+            if calls == 3 { StreamCallbackResult::Complete } else { StreamCallbackResult::Continue }
+        }));
+
+        let output = renderer.render(&[], None);
+        assert_eq!(output.len(), 48);
+        assert_eq!(output[47], 3);
+        assert!(renderer.is_finished());
+        assert!(renderer.render(&[], Some(16)).is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+//! Overriding the default devices and stream settings with environment variables
+//!
+//! This lets a user redirect an application to other devices without rebuilding it. The
+//! overrides are off unless the application calls enable(). They then apply to every
+//! Stream::open_default, and each override that takes effect is logged through the `log` crate.
+//!
+//! | Variable                        | Overrides                                           |
+//! |---------------------------------|-----------------------------------------------------|
+//! | `PORTAUDIO_INPUT_DEVICE`        | the default input device, as a device specification |
+//! | `PORTAUDIO_OUTPUT_DEVICE`       | the default output device, as a device specification|
+//! | `PORTAUDIO_SAMPLE_RATE`         | the sample rate, in Hz                              |
+//! | `PORTAUDIO_FRAMES_PER_BUFFER`   | the frames per buffer                               |
+//! | `PORTAUDIO_LATENCY`             | the suggested latency, in seconds                   |
+//!
+//! Device specifications are described in the spec module. Invalid values and devices that
+//! cannot be found are logged as warnings and ignored.
+
+use device::{self, DeviceIndex};
+use spec::{DeviceSpec, Direction};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Variable overriding the default input device
+pub const INPUT_DEVICE: &str = "PORTAUDIO_INPUT_DEVICE";
+
+/// Variable overriding the default output device
+pub const OUTPUT_DEVICE: &str = "PORTAUDIO_OUTPUT_DEVICE";
+
+/// Variable overriding the sample rate
+pub const SAMPLE_RATE: &str = "PORTAUDIO_SAMPLE_RATE";
+
+/// Variable overriding the frames per buffer
+pub const FRAMES_PER_BUFFER: &str = "PORTAUDIO_FRAMES_PER_BUFFER";
+
+/// Variable overriding the suggested latency
+pub const LATENCY: &str = "PORTAUDIO_LATENCY";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Apply the overrides from the environment to streams opened from now on
+pub fn enable()
+{
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stop applying the overrides
+pub fn disable()
+{
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Returns wether the overrides are applied
+pub fn is_enabled() -> bool
+{
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// The overrides that are set
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides
+{
+    /// The device to use instead of the default input device
+    pub input_device: Option<DeviceSpec>,
+
+    /// The device to use instead of the default output device
+    pub output_device: Option<DeviceSpec>,
+
+    /// The sample rate to use instead of the requested one
+    pub sample_rate: Option<f64>,
+
+    /// The frames per buffer to use instead of the requested number
+    pub frames_per_buffer: Option<u64>,
+
+    /// The suggested latency to use instead of the default high latency of the devices
+    pub suggested_latency: Option<Duration>,
+}
+
+/// The settings of a default stream once the overrides are applied
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Settings
+{
+    pub input_device: Option<DeviceIndex>,
+    pub output_device: Option<DeviceIndex>,
+    pub sample_rate: f64,
+    pub frames_per_buffer: u64,
+    pub suggested_latency: Option<Duration>,
+}
+
+impl Settings
+{
+    /// Returns wether the stream can not be opened as a plain default stream
+    pub fn needs_parameters(&self) -> bool
+    {
+        self.input_device.is_some() || self.output_device.is_some() || self.suggested_latency.is_some()
+    }
+}
+
+impl Overrides
+{
+    /// Read the overrides from the environment
+    pub fn from_env() -> Overrides
+    {
+        Overrides::from_lookup(|name| ::std::env::var(name).ok())
+    }
+
+    /// Read the overrides with the given function, which returns the value of a variable
+    pub fn from_lookup<F>(lookup: F) -> Overrides
+        where F: Fn(&str) -> Option<String>
+    {
+        fn parse<T: FromStr>(name: &str, value: Option<String>) -> Option<T>
+        {
+            let value = value?;
+            let parsed = value.trim().parse().ok();
+            if parsed.is_none() { warn!("ignoring {}={}: invalid value", name, value) }
+            parsed
+        }
+
+        Overrides
+        {
+            input_device: parse(INPUT_DEVICE, lookup(INPUT_DEVICE)),
+            output_device: parse(OUTPUT_DEVICE, lookup(OUTPUT_DEVICE)),
+            sample_rate: parse(SAMPLE_RATE, lookup(SAMPLE_RATE)).filter(|&rate: &f64| rate > 0.0),
+            frames_per_buffer: parse(FRAMES_PER_BUFFER, lookup(FRAMES_PER_BUFFER)),
+            suggested_latency: parse(LATENCY, lookup(LATENCY))
+                .filter(|&secs: &f64| secs >= 0.0 && secs.is_finite())
+                .map(Duration::from_secs_f64),
+        }
+    }
+
+    /// Apply the overrides to the arguments of Stream::open_default
+    ///
+    /// PortAudio must be initialized to resolve the devices.
+    pub(crate) fn apply(&self, num_input_channels: u32, num_output_channels: u32, sample_rate: f64, frames_per_buffer: u64) -> Settings
+    {
+        let device = |spec: &Option<DeviceSpec>, name: &str, direction: Direction, channels: u32|
+        {
+            let spec = spec.as_ref().filter(|_| channels > 0)?;
+            match spec.resolve(direction)
+            {
+                Ok(resolved) =>
+                {
+                    let device_name = device::get_info(resolved.index).map(|info| info.name).unwrap_or_default();
+                    info!("{}={} selects device {} ({})", name, spec, resolved.index, device_name);
+                    Some(resolved.index)
+                },
+                Err(e) => { warn!("ignoring {}={}: {}", name, spec, e); None },
+            }
+        };
+
+        let settings = Settings
+        {
+            input_device: device(&self.input_device, INPUT_DEVICE, Direction::Input, num_input_channels),
+            output_device: device(&self.output_device, OUTPUT_DEVICE, Direction::Output, num_output_channels),
+            sample_rate: self.sample_rate.unwrap_or(sample_rate),
+            frames_per_buffer: self.frames_per_buffer.unwrap_or(frames_per_buffer),
+            suggested_latency: self.suggested_latency,
+        };
+        if settings.sample_rate != sample_rate
+        {
+            info!("{} changes the sample rate from {} Hz to {} Hz", SAMPLE_RATE, sample_rate, settings.sample_rate);
+        }
+        if settings.frames_per_buffer != frames_per_buffer
+        {
+            info!("{} changes the frames per buffer from {} to {}", FRAMES_PER_BUFFER, frames_per_buffer, settings.frames_per_buffer);
+        }
+        if let Some(latency) = settings.suggested_latency
+        {
+            info!("{} sets the suggested latency to {} s", LATENCY, latency.as_secs_f64());
+        }
+        settings
+    }
+}
+
+/// The overrides from the environment, when they are enabled
+pub(crate) fn active() -> Option<Overrides>
+{
+    if is_enabled() { Some(Overrides::from_env()) } else { None }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Overrides;
+    use spec::{DeviceSpec, Direction};
+    use std::time::Duration;
+
+    fn lookup(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String>
+    {
+        move |name| vars.iter().find(|v| v.0 == name).map(|v| v.1.to_string())
+    }
+
+    #[test]
+    fn reads_variables()
+    {
+        let overrides = Overrides::from_lookup(lookup(&[("PORTAUDIO_OUTPUT_DEVICE", "default-input"),
+                                                        ("PORTAUDIO_SAMPLE_RATE", "48000"),
+                                                        ("PORTAUDIO_FRAMES_PER_BUFFER", "many"),
+                                                        ("PORTAUDIO_LATENCY", "0.05")]));
+        assert_eq!(overrides, Overrides
+        {
+            input_device: None,
+            output_device: Some(DeviceSpec::Default(Some(Direction::Input))),
+            sample_rate: Some(48000.0),
+            frames_per_buffer: None,
+            suggested_latency: Some(Duration::from_millis(50)),
+        });
+        assert_eq!(Overrides::from_lookup(lookup(&[])), Overrides::default());
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn applies_to_default_streams()
+    {
+        use mock::{self, MockSystem};
+        use stream::Stream;
+
+        mock::configure(MockSystem::default());
+        ::initialize().unwrap();
+
+        let overrides = Overrides::from_lookup(lookup(&[("PORTAUDIO_INPUT_DEVICE", "name~duplex"),
+                                                        ("PORTAUDIO_OUTPUT_DEVICE", "name=Speakers"),
+                                                        ("PORTAUDIO_FRAMES_PER_BUFFER", "128")]));
+        let settings = overrides.apply(2, 2, 44100.0, 0);
+        assert_eq!((settings.input_device, settings.output_device), (Some(2), None));
+        assert_eq!((settings.sample_rate, settings.frames_per_buffer), (44100.0, 128));
+        assert!(settings.needs_parameters());
+        assert_eq!(overrides.apply(0, 2, 44100.0, 0).input_device, None);
+
+        let stream = Stream::<f32, f32>::open_default_with(&settings, 2, 2, None).unwrap();
+        assert_eq!(stream.info().unwrap().sample_rate, 44100.0);
+        drop(stream);
+        ::terminate().unwrap();
+    }
+}
@@ -1,9 +1,16 @@
 //! General utilities for PortAudio
 
-use util::to_pa_result;
+use util::to_error;
+use error::{Error, Operation};
 use ll;
 use std::fmt;
 use std::ffi::CStr;
+#[cfg(not(feature = "mock"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The number of successful initialize() calls that were not terminated yet
+#[cfg(not(feature = "mock"))]
+static INITIALIZED: AtomicUsize = AtomicUsize::new(0);
 
 /// PortAudio version
 pub fn version() -> i32
@@ -20,20 +27,141 @@ pub fn version_text() -> String
     version_s.into_owned()
 }
 
+/// Version of the PortAudio library
+///
+/// Versions compare by their major, minor and sub-minor numbers first. Display shows the full
+/// version text.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VersionInfo
+{
+    /// Major version number
+    pub major: u32,
+
+    /// Minor version number
+    pub minor: u32,
+
+    /// Sub-minor version number
+    pub sub_minor: u32,
+
+    /// Revision in the version control system, such as a git hash. Empty when unknown.
+    pub control_revision: String,
+
+    /// Human readable version text
+    pub text: String,
+}
+
+impl VersionInfo
+{
+    /// Returns wether this is the given version or a later one
+    pub fn is_at_least(&self, major: u32, minor: u32, sub_minor: u32) -> bool
+    {
+        (self.major, self.minor, self.sub_minor) >= (major, minor, sub_minor)
+    }
+
+    #[cfg(portaudio_version_info)]
+    fn from_ll(info: &ll::PaVersionInfo) -> VersionInfo
+    {
+        VersionInfo
+        {
+            major: info.versionMajor as u32,
+            minor: info.versionMinor as u32,
+            sub_minor: info.versionSubMinor as u32,
+            control_revision: c_string(info.versionControlRevision),
+            text: c_string(info.versionText),
+        }
+    }
+
+    /// Decode the number of Pa_GetVersion(), for libraries without Pa_GetVersionInfo()
+    fn from_version(version: i32, text: String) -> VersionInfo
+    {
+        let control_revision = match text.find("revision ")
+        {
+            Some(i) => text[i + "revision ".len() ..].trim().to_string(),
+            None => String::new(),
+        };
+        VersionInfo
+        {
+            major: (version >> 16 & 0xFF) as u32,
+            minor: (version >> 8 & 0xFF) as u32,
+            sub_minor: (version & 0xFF) as u32,
+            control_revision,
+            text,
+        }
+    }
+}
+
+impl fmt::Display for VersionInfo
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(&self.text)
+    }
+}
+
+/// Structured PortAudio version
+pub fn version_info() -> VersionInfo
+{
+    #[cfg(portaudio_version_info)]
+    {
+        let info = unsafe { ll::Pa_GetVersionInfo() };
+        if !info.is_null()
+        {
+            return VersionInfo::from_ll(unsafe { &*info });
+        }
+    }
+    VersionInfo::from_version(version(), version_text())
+}
+
+#[cfg(portaudio_version_info)]
+fn c_string(text: *const ::libc::c_char) -> String
+{
+    if text.is_null() { return String::new() }
+    String::from_utf8_lossy(unsafe { CStr::from_ptr(text).to_bytes() }).into_owned()
+}
+
 /// Initialize the PortAudio API
 ///
 /// Each successful call must be matched by a call to terminate
-pub fn initialize() -> PaResult
+///
+/// With the dynamic feature, this is where the PortAudio library is loaded. When it or one of
+/// its functions is missing, this returns LibraryNotLoaded, and the error describes what went
+/// wrong.
+pub fn initialize() -> Result<(), Error>
 {
-    to_pa_result(unsafe { ll::Pa_Initialize() })
+    #[cfg(all(feature = "dynamic", not(feature = "mock")))]
+    {
+        if let Some(reason) = ll::load_error() { return Err(Error::not_loaded(reason)) }
+    }
+    to_error(unsafe { ll::Pa_Initialize() }, Operation::Initialize)?;
+    #[cfg(not(feature = "mock"))]
+    INITIALIZED.fetch_add(1, Ordering::SeqCst);
+    Ok(())
 }
 
 /// Terminate the PortAudio API
 ///
 /// Call this function exactly once for each successful call to initialize
-pub fn terminate() -> PaResult
+pub fn terminate() -> Result<(), Error>
 {
-    to_pa_result(unsafe { ll::Pa_Terminate() })
+    to_error(unsafe { ll::Pa_Terminate() }, Operation::Terminate)?;
+    #[cfg(not(feature = "mock"))]
+    INITIALIZED.fetch_sub(1, Ordering::SeqCst);
+    Ok(())
+}
+
+// The number of initialize() calls that terminate() has not matched yet
+#[cfg(not(feature = "mock"))]
+pub(crate) fn initialize_count() -> usize
+{
+    INITIALIZED.load(Ordering::SeqCst)
+}
+
+// The mock emulates a system per thread, so only the calls of this thread count
+#[cfg(feature = "mock")]
+pub(crate) fn initialize_count() -> usize
+{
+    ll::mock::initialize_count() as usize
 }
 
 // PaError and PaResult
@@ -79,6 +207,16 @@ pub enum PaError
 
     /// Added variant for when FromPrimitive returns None
     UnknownError,
+
+    /// Added variant for when the dynamic feature could not load the PortAudio library
+    LibraryNotLoaded,
+
+    /// Added variant for when PortAudio is not reinitialized because streams are open
+    StreamsOpen,
+
+    /// Added variant for when PortAudio is not reinitialized because initialize() was called
+    /// more than once, so terminate() would not actually terminate it
+    InitializedMoreThanOnce,
 }
 
 impl PaError {
@@ -125,11 +263,14 @@ impl fmt::Display for PaError
         match *self
         {
             PaError::UnknownError => write!(f, "Unknown Error"),
+            PaError::LibraryNotLoaded => write!(f, "PortAudio library not loaded"),
+            PaError::StreamsOpen => write!(f, "Streams are open"),
+            PaError::InitializedMoreThanOnce => write!(f, "PortAudio is initialized more than once"),
             other =>
             {
                 let message_c = unsafe { ll::Pa_GetErrorText(other as i32) };
                 let message_s = String::from_utf8_lossy(unsafe { CStr::from_ptr(message_c).to_bytes() });
-                f.write_str(&*message_s)
+                f.write_str(&message_s)
             }
         }
     }
@@ -148,3 +289,31 @@ impl std::error::Error for PaError {}
 ///
 /// The original NoError is mapped to Ok(()) and other values mapped to Err(x)
 pub type PaResult = Result<(), PaError>;
+
+#[cfg(test)]
+mod test
+{
+    use super::VersionInfo;
+
+    #[test]
+    fn decode_version()
+    {
+        let info = VersionInfo::from_version(0x13_06_00, "PortAudio V19.6.0-devel, revision 396fe4b6".to_string());
+        assert_eq!((info.major, info.minor, info.sub_minor), (19, 6, 0));
+        assert_eq!(info.control_revision, "396fe4b6");
+        assert_eq!(info.to_string(), "PortAudio V19.6.0-devel, revision 396fe4b6");
+
+        let newer = VersionInfo::from_version(0x13_07_00, String::new());
+        assert!(newer > info);
+        assert!(newer.is_at_least(19, 7, 0) && !info.is_at_least(19, 7, 0));
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn mock_version()
+    {
+        let info = super::version_info();
+        assert_eq!((info.major, info.minor, info.sub_minor), (19, 7, 0));
+        assert_eq!(info.control_revision, "mock");
+    }
+}
@@ -0,0 +1,327 @@
+//! Sample rate conversion of interleaved floating point samples
+
+use std::f64::consts::PI;
+
+/// Quality presets for the sample rate conversion
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResampleQuality
+{
+    /// Linear interpolation between neighbouring frames. Cheap, but with audible aliasing.
+    Linear,
+
+    /// Interpolation with a Blackman windowed sinc over 32 frames, filtering above the lowest
+    /// Nyquist frequency of both rates.
+    WindowedSinc,
+}
+
+/// Number of frames on each side of the interpolated position used by WindowedSinc
+const SINC_HALF_WIDTH: usize = 16;
+
+/// Number of precomputed fractional positions of the sinc kernel
+const SINC_PHASES: usize = 256;
+
+/// A streaming sample rate converter
+///
+/// Frames are pushed in at the input rate, and pulled out at the output rate. The resampler
+/// keeps the frames it still needs between calls in a ring buffer, which only grows in
+/// reserve(), so pushing and pulling never allocate.
+pub struct Resampler
+{
+    channels: usize,
+    step: f64,
+    half: usize,
+    table: Vec<f32>,
+    // Each sample is stored twice, at i and at i + capacity, so the buffered samples are always
+    // contiguous
+    history: Vec<f32>,
+    capacity: usize,
+    start: usize,
+    len: usize,
+    pos: f64,
+}
+
+impl Resampler
+{
+    /// Create a resampler for the given number of interleaved channels
+    pub fn new(channels: u32, input_rate: f64, output_rate: f64, quality: ResampleQuality) -> Resampler
+    {
+        let step = input_rate / output_rate;
+        let (half, table) = match quality
+        {
+            ResampleQuality::Linear => (1, Vec::new()),
+            ResampleQuality::WindowedSinc =>
+            {
+                let cutoff = (1.0 / step).min(1.0);
+                let half = (SINC_HALF_WIDTH as f64 / cutoff).ceil() as usize;
+                (half, sinc_table(half, cutoff))
+            },
+        };
+
+        let channels = channels as usize;
+        let mut resampler = Resampler
+        {
+            channels,
+            step,
+            half,
+            table,
+            history: Vec::new(),
+            capacity: 0,
+            start: 0,
+            len: 0,
+            pos: (half - 1) as f64,
+        };
+        // Start with silence before the first frame, so the first output is centered on it
+        resampler.reserve(2 * half);
+        resampler.push(&vec![0.0; (half - 1) * channels]);
+        resampler
+    }
+
+    /// Make room for the given number of input frames besides those buffered, so pushing them
+    /// does not allocate
+    pub fn reserve(&mut self, frames: usize)
+    {
+        let needed = self.len + frames * self.channels;
+        if needed <= self.capacity { return }
+
+        let mut history = vec![0.0; 2 * needed];
+        history[.. self.len].copy_from_slice(self.buffered_samples());
+        history[needed .. needed + self.len].copy_from_slice(self.buffered_samples());
+        self.history = history;
+        self.capacity = needed;
+        self.start = 0;
+    }
+
+    /// The number of input frames the resampler has to look ahead, and thus delays the signal
+    pub fn latency_frames(&self) -> usize
+    {
+        self.half
+    }
+
+    /// Append interleaved frames at the input rate
+    ///
+    /// reserve() must have made room for them. Frames that do not fit are dropped.
+    pub fn push(&mut self, input: &[f32])
+    {
+        debug_assert!(self.len + input.len() <= self.capacity, "pushed more frames than reserved");
+        let input = &input[.. input.len().min(self.capacity - self.len)];
+        for &sample in input
+        {
+            let i = (self.start + self.len) % self.capacity;
+            self.history[i] = sample;
+            self.history[i + self.capacity] = sample;
+            self.len += 1;
+        }
+    }
+
+    /// The number of frames that can be pulled without pushing more input
+    pub fn available(&self) -> usize
+    {
+        let frames = self.buffered();
+        let mut pos = self.pos;
+        let mut count = 0;
+        while pos as usize + self.half < frames
+        {
+            count += 1;
+            pos += self.step;
+        }
+        count
+    }
+
+    /// The number of input frames that still have to be pushed before the given number of
+    /// frames can be pulled
+    pub fn input_needed(&self, output_frames: usize) -> usize
+    {
+        if output_frames == 0 { return 0 }
+
+        let mut pos = self.pos;
+        for _ in 1 .. output_frames
+        {
+            pos += self.step;
+        }
+        (pos as usize + self.half + 1).saturating_sub(self.buffered())
+    }
+
+    /// Fill the output with interleaved frames at the output rate, as far as the input allows
+    ///
+    /// Returns the number of frames written.
+    pub fn pull(&mut self, output: &mut [f32]) -> usize
+    {
+        let frames = self.buffered();
+        let mut written = 0;
+
+        for frame in output.chunks_mut(self.channels)
+        {
+            let base = self.pos as usize;
+            if base + self.half >= frames || frame.len() < self.channels { break }
+
+            let frac = self.pos - base as f64;
+            if self.table.is_empty()
+            {
+                let current = &self.buffered_samples()[base * self.channels ..];
+                for (c, out) in frame.iter_mut().enumerate()
+                {
+                    let a = current[c];
+                    let b = current[self.channels + c];
+                    *out = a + (b - a) * frac as f32;
+                }
+            }
+            else
+            {
+                self.interpolate_sinc(base, frac, frame);
+            }
+
+            self.pos += self.step;
+            written += 1;
+        }
+
+        // Drop the frames that will not be looked at again
+        let consumed = (self.pos as usize).saturating_sub(self.half - 1);
+        if consumed > 0
+        {
+            let consumed = consumed.min(frames);
+            self.start = (self.start + consumed * self.channels) % self.capacity;
+            self.len -= consumed * self.channels;
+            self.pos -= consumed as f64;
+        }
+
+        written
+    }
+
+    fn buffered(&self) -> usize
+    {
+        self.len / self.channels
+    }
+
+    fn buffered_samples(&self) -> &[f32]
+    {
+        &self.history[self.start .. self.start + self.len]
+    }
+
+    fn interpolate_sinc(&self, base: usize, frac: f64, frame: &mut [f32])
+    {
+        let taps = 2 * self.half;
+        let phase = frac * SINC_PHASES as f64;
+        let row = phase as usize;
+        let weight = (phase - row as f64) as f32;
+        let row_a = &self.table[row * taps .. (row + 1) * taps];
+        let row_b = &self.table[(row + 1) * taps .. (row + 2) * taps];
+        let first = base + 1 - self.half;
+        let history = self.buffered_samples();
+
+        for (c, out) in frame.iter_mut().enumerate()
+        {
+            let mut sum = 0.0f32;
+            for k in 0 .. taps
+            {
+                let coefficient = row_a[k] + (row_b[k] - row_a[k]) * weight;
+                sum += history[(first + k) * self.channels + c] * coefficient;
+            }
+            *out = sum;
+        }
+    }
+}
+
+// Rows of 2 * half coefficients, for each fractional position between two frames
+fn sinc_table(half: usize, cutoff: f64) -> Vec<f32>
+{
+    let taps = 2 * half;
+    let mut table = Vec::with_capacity((SINC_PHASES + 1) * taps);
+    for phase in 0 ..= SINC_PHASES
+    {
+        let frac = phase as f64 / SINC_PHASES as f64;
+        for k in 0 .. taps
+        {
+            let x = k as f64 - (half - 1) as f64 - frac;
+            table.push(kernel(x, half as f64, cutoff) as f32);
+        }
+    }
+    table
+}
+
+fn kernel(x: f64, half: f64, cutoff: f64) -> f64
+{
+    if x.abs() >= half { return 0.0 }
+
+    let sinc = if x == 0.0 { 1.0 } else { (PI * cutoff * x).sin() / (PI * cutoff * x) };
+    let u = x / half;
+    let window = 0.42 + 0.5 * (PI * u).cos() + 0.08 * (2.0 * PI * u).cos();
+    cutoff * sinc * window
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{Resampler, ResampleQuality};
+
+    fn run(quality: ResampleQuality, input_rate: f64, output_rate: f64, input: &[f32]) -> Vec<f32>
+    {
+        let mut resampler = Resampler::new(1, input_rate, output_rate, quality);
+        let mut output = Vec::new();
+        for chunk in input.chunks(100)
+        {
+            resampler.reserve(chunk.len());
+            resampler.push(chunk);
+            let mut buffer = vec![0.0; resampler.available()];
+            assert_eq!(resampler.pull(&mut buffer), buffer.len());
+            output.extend(buffer);
+        }
+        output
+    }
+
+    #[test]
+    fn frame_counts()
+    {
+        for &quality in [ResampleQuality::Linear, ResampleQuality::WindowedSinc].iter()
+        {
+            let output = run(quality, 44_100.0, 48_000.0, &[0.0; 44_100]);
+            let expected = 48_000 - 48_000 * Resampler::new(1, 44_100.0, 48_000.0, quality).latency_frames() / 44_100;
+            assert!((output.len() as isize - expected as isize).abs() <= 2, "{} != {}", output.len(), expected);
+        }
+    }
+
+    #[test]
+    fn constant_signal()
+    {
+        for &quality in [ResampleQuality::Linear, ResampleQuality::WindowedSinc].iter()
+        {
+            let output = run(quality, 48_000.0, 44_100.0, &[0.5; 4800]);
+            for &v in &output[100 ..]
+            {
+                assert!((v - 0.5).abs() < 1e-2, "{}", v);
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_history_wraps_around()
+    {
+        let mut resampler = Resampler::new(2, 44_100.0, 48_000.0, ResampleQuality::WindowedSinc);
+        resampler.reserve(512 + 2 * resampler.latency_frames());
+        let capacity = resampler.capacity;
+        for _ in 0 .. 100
+        {
+            resampler.push(&[0.25; 2 * 512]);
+            let mut buffer = vec![0.0; 2 * resampler.available()];
+            resampler.pull(&mut buffer);
+        }
+        assert_eq!(resampler.capacity, capacity);
+
+        resampler.push(&[0.25; 2 * 512]);
+        let mut buffer = vec![0.0; 2 * 512];
+        assert_eq!(resampler.pull(&mut buffer), 512);
+        assert!(buffer.iter().all(|v| (v - 0.25).abs() < 1e-2));
+    }
+
+    #[test]
+    fn input_needed()
+    {
+        let mut resampler = Resampler::new(2, 48_000.0, 44_100.0, ResampleQuality::WindowedSinc);
+        let needed = resampler.input_needed(512);
+        resampler.reserve(needed);
+        resampler.push(&vec![0.0; (needed - 1) * 2]);
+        assert_eq!(resampler.available(), 511);
+        resampler.push(&[0.0, 0.0]);
+        assert_eq!(resampler.available(), 512);
+        assert_eq!(resampler.input_needed(512), 0);
+    }
+}
@@ -0,0 +1,189 @@
+//! Mapping between the physical channels of a device and the logical channels of a stream
+
+use std::f32::consts::FRAC_1_SQRT_2;
+
+/// A gain matrix from source channels to destination channels
+///
+/// For an input stream the sources are the physical channels of the device and the destinations
+/// are the channels presented to the application. For an output stream the sources are the
+/// channels of the application and the destinations are the physical channels of the device.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Routing
+{
+    sources: u32,
+    destinations: u32,
+    gains: Vec<f32>,
+}
+
+impl Routing
+{
+    /// Create a routing in which no source reaches any destination
+    pub fn new(sources: u32, destinations: u32) -> Routing
+    {
+        Routing
+        {
+            sources,
+            destinations,
+            gains: vec![0.0; sources as usize * destinations as usize],
+        }
+    }
+
+    /// Select physical input channels of a device, in the order given
+    ///
+    /// `Routing::input_channels(16, &[4, 5])` presents inputs 5 and 6 of a 16 channel device as
+    /// a stereo stream. Returns None when a channel is out of range.
+    pub fn input_channels(device_channels: u32, channels: &[u32]) -> Option<Routing>
+    {
+        let mut routing = Routing::new(device_channels, channels.len() as u32);
+        for (logical, &physical) in channels.iter().enumerate()
+        {
+            if physical >= device_channels { return None }
+            routing.set_gain(physical, logical as u32, 1.0);
+        }
+        Some(routing)
+    }
+
+    /// Place the channels of the application on physical output channels of a device
+    ///
+    /// `Routing::output_channels(8, &[2, 3])` plays a stereo stream on outputs 3 and 4 of an 8
+    /// channel device. Returns None when a channel is out of range.
+    pub fn output_channels(device_channels: u32, channels: &[u32]) -> Option<Routing>
+    {
+        let mut routing = Routing::new(channels.len() as u32, device_channels);
+        for (logical, &physical) in channels.iter().enumerate()
+        {
+            if physical >= device_channels { return None }
+            routing.set_gain(logical as u32, physical, 1.0);
+        }
+        Some(routing)
+    }
+
+    /// Copy a single channel to both channels of a stereo pair
+    pub fn mono_to_stereo() -> Routing
+    {
+        let mut routing = Routing::new(1, 2);
+        routing.set_gain(0, 0, 1.0);
+        routing.set_gain(0, 1, 1.0);
+        routing
+    }
+
+    /// Mix a stereo pair down to a single channel
+    pub fn stereo_to_mono() -> Routing
+    {
+        let mut routing = Routing::new(2, 1);
+        routing.set_gain(0, 0, 0.5);
+        routing.set_gain(1, 0, 0.5);
+        routing
+    }
+
+    /// Mix 5.1 surround down to stereo
+    ///
+    /// The sources are in the usual order of left, right, center, LFE, left surround and right
+    /// surround. Center and surround channels are mixed in at -3 dB, and the LFE is dropped.
+    pub fn surround_to_stereo() -> Routing
+    {
+        let mut routing = Routing::new(6, 2);
+        routing.set_gain(0, 0, 1.0);
+        routing.set_gain(1, 1, 1.0);
+        routing.set_gain(2, 0, FRAC_1_SQRT_2);
+        routing.set_gain(2, 1, FRAC_1_SQRT_2);
+        routing.set_gain(4, 0, FRAC_1_SQRT_2);
+        routing.set_gain(5, 1, FRAC_1_SQRT_2);
+        routing
+    }
+
+    /// The number of source channels
+    pub fn sources(&self) -> u32
+    {
+        self.sources
+    }
+
+    /// The number of destination channels
+    pub fn destinations(&self) -> u32
+    {
+        self.destinations
+    }
+
+    /// The gain from a source channel to a destination channel
+    ///
+    /// ## Panics
+    /// When a channel is out of range.
+    pub fn gain(&self, source: u32, destination: u32) -> f32
+    {
+        self.gains[self.index(source, destination)]
+    }
+
+    /// Set the gain from a source channel to a destination channel
+    ///
+    /// ## Panics
+    /// When a channel is out of range.
+    pub fn set_gain(&mut self, source: u32, destination: u32, gain: f32)
+    {
+        let index = self.index(source, destination);
+        self.gains[index] = gain;
+    }
+
+    /// Route interleaved frames of the source channels into the destination channels
+    ///
+    /// Routes as many frames as both buffers hold.
+    pub fn apply(&self, input: &[f32], output: &mut [f32])
+    {
+        let sources = self.sources as usize;
+        let destinations = self.destinations as usize;
+        if sources == 0 || destinations == 0 { return }
+
+        for (frame_in, frame_out) in input.chunks_exact(sources).zip(output.chunks_exact_mut(destinations))
+        {
+            for (out, gains) in frame_out.iter_mut().zip(self.gains.chunks_exact(sources))
+            {
+                *out = frame_in.iter().zip(gains.iter()).map(|(&v, &g)| v * g).sum();
+            }
+        }
+    }
+
+    fn index(&self, source: u32, destination: u32) -> usize
+    {
+        assert!(source < self.sources && destination < self.destinations, "channel out of range");
+        (destination * self.sources + source) as usize
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::Routing;
+
+    #[test]
+    fn select_inputs()
+    {
+        let routing = Routing::input_channels(4, &[3, 1]).unwrap();
+        let input = [0.0, 0.1, 0.2, 0.3, 1.0, 1.1, 1.2, 1.3];
+        let mut output = [0.0; 4];
+        routing.apply(&input, &mut output);
+        assert_eq!(output, [0.3, 0.1, 1.3, 1.1]);
+
+        assert!(Routing::input_channels(4, &[4]).is_none());
+    }
+
+    #[test]
+    fn place_outputs()
+    {
+        let routing = Routing::output_channels(4, &[2, 3]).unwrap();
+        let mut output = [1.0; 8];
+        routing.apply(&[0.5, -0.5, 0.25, -0.25], &mut output);
+        assert_eq!(output, [0.0, 0.0, 0.5, -0.5, 0.0, 0.0, 0.25, -0.25]);
+    }
+
+    #[test]
+    fn downmix()
+    {
+        let mut output = [0.0; 2];
+        Routing::surround_to_stereo().apply(&[0.1, 0.2, 0.5, 1.0, 0.0, 0.3], &mut output);
+        assert!((output[0] - (0.1 + 0.5 * 0.70710677)).abs() < 1e-6);
+        assert!((output[1] - (0.2 + 0.8 * 0.70710677)).abs() < 1e-6);
+
+        let mut mono = [0.0; 1];
+        Routing::stereo_to_mono().apply(&[0.5, 0.25], &mut mono);
+        assert_eq!(mono, [0.375]);
+    }
+}
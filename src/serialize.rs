@@ -0,0 +1,105 @@
+//! Serde support for the info and parameter types, enabled by the `serde` feature
+//!
+//! Durations are written as seconds, flags as their bits and host API types by the names
+//! HostApiType displays.
+
+use hostapi::HostApiType;
+use stream::{SampleFormat, StreamCallbackFlags, StreamFlags};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+/// For `#[serde(with)]`, writes a Duration as seconds
+pub mod seconds
+{
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error>
+    {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(|_| D::Error::custom(format!("invalid duration of {} seconds", secs)))
+    }
+}
+
+impl Serialize for HostApiType
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostApiType
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<HostApiType, D::Error>
+    {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
+macro_rules! serde_flags {
+    ($($flags:ident),*) => {$(
+        impl Serialize for $flags
+        {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            {
+                serializer.serialize_u64(self.bits())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $flags
+        {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$flags, D::Error>
+            {
+                let bits = u64::deserialize(deserializer)?;
+                $flags::from_bits(bits).ok_or_else(|| D::Error::custom(format!("invalid {} 0x{:x}", stringify!($flags), bits)))
+            }
+        }
+    )*}
+}
+
+serde_flags!(StreamFlags, StreamCallbackFlags, SampleFormat);
+
+#[cfg(test)]
+mod test
+{
+    extern crate serde_json;
+
+    use device::DeviceId;
+    use hostapi::HostApiType;
+    use stream::{StreamFlags, StreamParameters};
+    use std::time::Duration;
+
+    #[test]
+    fn round_trip()
+    {
+        let parameters = StreamParameters { device: 3, channel_count: 2, suggested_latency: Duration::from_millis(25), data: 0i16 };
+        let json = serde_json::to_string(&parameters).unwrap();
+        assert_eq!(json, r#"{"device":3,"channel_count":2,"suggested_latency":0.025}"#);
+        assert_eq!(serde_json::from_str::<StreamParameters<i16>>(&json).unwrap(), parameters);
+        assert!(serde_json::from_str::<StreamParameters<i16>>(r#"{"device":3,"channel_count":2,"suggested_latency":-1}"#).is_err());
+
+        let flags = StreamFlags::CLIP_OFF | StreamFlags::DITHER_OFF;
+        assert_eq!(serde_json::to_string(&flags).unwrap(), "3");
+        assert_eq!(serde_json::from_str::<StreamFlags>("3").unwrap(), flags);
+        assert!(serde_json::from_str::<StreamFlags>("32").is_err());
+
+        let id = DeviceId
+        {
+            api_type: HostApiType::ALSA,
+            name: "USB Audio".to_string(),
+            max_input_channels: 2,
+            max_output_channels: 2,
+            default_sample_rate: 48000.0,
+        };
+        let json = serde_json::to_string(&id).unwrap();
+        assert!(json.starts_with(r#"{"api_type":"alsa","#));
+        assert_eq!(serde_json::from_str::<DeviceId>(&json).unwrap(), id);
+    }
+}
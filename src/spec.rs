@@ -0,0 +1,403 @@
+//! Device specification strings, for command line options and configuration files
+//!
+//! The accepted forms are:
+//!
+//! * `default`, `default-input` or `default-output`: the default device
+//! * `index:<n>`: the device with the given index
+//! * `name=<name>`: the device with exactly this name
+//! * `name~<text>`: the first device whose name contains the text, ignoring case
+//! * `<host API>:default`: the default device of a host API, such as `jack:default`
+//! * `<host API>:<device>`: a device of a host API by its name or a part of it. ALSA devices
+//!   can also be given by their card and device, as in `alsa:hw:1,0`.
+
+use device::{self, DeviceIndex, DeviceInfo};
+use hostapi::{self, HostApiType};
+use std::{error, fmt};
+use std::str::FromStr;
+
+/// The direction a device is used for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction
+{
+    /// Recording
+    Input,
+
+    /// Playback
+    Output,
+}
+
+impl Direction
+{
+    fn channels(self, info: &DeviceInfo) -> u32
+    {
+        match self
+        {
+            Direction::Input => info.max_input_channels,
+            Direction::Output => info.max_output_channels,
+        }
+    }
+
+    fn name(self) -> &'static str
+    {
+        match self
+        {
+            Direction::Input => "input",
+            Direction::Output => "output",
+        }
+    }
+}
+
+/// A parsed device specification, see the module documentation
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceSpec
+{
+    /// The default device, of the given direction or of the one the device is resolved for
+    Default(Option<Direction>),
+
+    /// A device by its index
+    Index(DeviceIndex),
+
+    /// A device by its exact name
+    Name(String),
+
+    /// A device whose name contains the text, ignoring case
+    NameContains(String),
+
+    /// A device of a host API, by its name or ALSA device, or the default device when None
+    HostApi(HostApiType, Option<String>),
+}
+
+/// A device found for a DeviceSpec
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedDevice
+{
+    /// Index of the device
+    pub index: DeviceIndex,
+
+    /// The ALSA device string, such as "hw:1,0", for ALSA devices that have one
+    pub alsa_device: Option<String>,
+}
+
+impl DeviceSpec
+{
+    /// Find the device for the given direction
+    ///
+    /// PortAudio must be initialized.
+    pub fn resolve(&self, direction: Direction) -> Result<ResolvedDevice, DeviceSpecError>
+    {
+        let found = match *self
+        {
+            DeviceSpec::Default(dir) =>
+            {
+                let dir = dir.unwrap_or(direction);
+                let index = match dir
+                {
+                    Direction::Input => device::get_default_input_index(),
+                    Direction::Output => device::get_default_output_index(),
+                };
+                index.ok_or(DeviceSpecError::NoDefault { host_api: None, direction: dir })?
+            },
+            DeviceSpec::Index(index) => match device::get_info(index)
+            {
+                Some(ref info) if direction.channels(info) > 0 => index,
+                _ => return Err(self.not_found(direction)),
+            },
+            DeviceSpec::Name(ref name) => self.find_device(direction, None, |info| info.name == *name)?,
+            DeviceSpec::NameContains(ref text) =>
+            {
+                let text = text.to_lowercase();
+                self.find_device(direction, None, |info| info.name.to_lowercase().contains(&text))?
+            },
+            DeviceSpec::HostApi(api_type, None) =>
+            {
+                let api = api_type.info().map_err(|_| DeviceSpecError::HostApiNotAvailable(api_type))?;
+                let default = match direction
+                {
+                    Direction::Input => api.default_input,
+                    Direction::Output => api.default_output,
+                };
+                default.ok_or(DeviceSpecError::NoDefault { host_api: Some(api_type), direction })?
+            },
+            DeviceSpec::HostApi(api_type, Some(ref name)) =>
+            {
+                api_type.info().map_err(|_| DeviceSpecError::HostApiNotAvailable(api_type))?;
+                let lower = name.to_lowercase();
+                let card = alsa_card(name).map(|hw| format!("({})", hw));
+                self.find_device(direction, Some(api_type), |info|
+                {
+                    info.name == *name || match card
+                    {
+                        Some(ref card) => info.name.contains(card.as_str()),
+                        None => info.name.to_lowercase().contains(&lower),
+                    }
+                })?
+            },
+        };
+
+        let is_alsa = hostapi::host_api_for(found).is_some_and(|api| api.api_type == HostApiType::ALSA);
+        let alsa_device = match *self
+        {
+            DeviceSpec::HostApi(HostApiType::ALSA, Some(ref name)) if alsa_card(name).is_some() => Some(name.clone()),
+            _ if is_alsa => device::get_info(found).and_then(|info| alsa_card(&info.name).or_else(|| bracketed_card(&info.name))),
+            _ => None,
+        };
+        Ok(ResolvedDevice { index: found, alsa_device })
+    }
+
+    fn find_device<F>(&self, direction: Direction, api_type: Option<HostApiType>, matches: F) -> Result<DeviceIndex, DeviceSpecError>
+        where F: Fn(&DeviceInfo) -> bool
+    {
+        let count = device::get_count().unwrap_or(0);
+        (0..count).find(|&index|
+        {
+            device::get_info(index).is_some_and(|info| direction.channels(&info) > 0 && matches(&info))
+                && api_type.map_or(true, |t| hostapi::host_api_for(index).is_some_and(|api| api.api_type == t))
+        }).ok_or_else(|| self.not_found(direction))
+    }
+
+    fn not_found(&self, direction: Direction) -> DeviceSpecError
+    {
+        let wanted = match *self
+        {
+            DeviceSpec::Name(ref name) | DeviceSpec::NameContains(ref name) | DeviceSpec::HostApi(_, Some(ref name)) => name.clone(),
+            _ => String::new(),
+        };
+        let count = device::get_count().unwrap_or(0);
+        let names: Vec<String> = (0..count).filter_map(device::get_info)
+            .filter(|info| direction.channels(info) > 0)
+            .map(|info| info.name)
+            .collect();
+        DeviceSpecError::NoDevice { spec: self.to_string(), direction, near_misses: near_misses(&wanted, &names) }
+    }
+}
+
+// "hw:1,0" or "plughw:1,0" as the ALSA device "hw:1,0"
+fn alsa_card(name: &str) -> Option<String>
+{
+    let hw = name.strip_prefix("plug").unwrap_or(name);
+    let numbers = hw.strip_prefix("hw:")?;
+    if numbers.split(',').all(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    {
+        Some(hw.to_string())
+    }
+    else { None }
+}
+
+// The ALSA device in a PortAudio device name, such as "USB Audio: - (hw:1,0)"
+fn bracketed_card(name: &str) -> Option<String>
+{
+    let start = name.rfind("(hw:")?;
+    let end = name[start ..].find(')')?;
+    alsa_card(&name[start + 1 .. start + end])
+}
+
+// The names closest to the wanted one, best first
+fn near_misses(wanted: &str, names: &[String]) -> Vec<String>
+{
+    let wanted = wanted.to_lowercase();
+    let mut scored: Vec<(usize, &String)> = names.iter().filter_map(|name|
+    {
+        let lower = name.to_lowercase();
+        let distance = edit_distance(&wanted, &lower);
+        let shared_word = lower.split_whitespace().any(|w| w.len() > 2 && wanted.contains(w));
+        if distance <= wanted.len().max(lower.len()) / 3 + 1 || shared_word { Some((distance, name)) } else { None }
+    }).collect();
+    scored.sort_by_key(|&(distance, _)| distance);
+    let mut result: Vec<String> = Vec::new();
+    for (_, name) in scored
+    {
+        if !result.contains(name) && result.len() < 3 { result.push(name.clone()) }
+    }
+    result
+}
+
+fn edit_distance(a: &str, b: &str) -> usize
+{
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate()
+    {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate()
+        {
+            let substitution = previous + if ca == cb { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+impl FromStr for DeviceSpec
+{
+    type Err = DeviceSpecError;
+
+    fn from_str(s: &str) -> Result<DeviceSpec, DeviceSpecError>
+    {
+        let s = s.trim();
+        let syntax = || DeviceSpecError::Syntax(s.to_string());
+        match s
+        {
+            "default" => return Ok(DeviceSpec::Default(None)),
+            "default-input" => return Ok(DeviceSpec::Default(Some(Direction::Input))),
+            "default-output" => return Ok(DeviceSpec::Default(Some(Direction::Output))),
+            _ => {},
+        }
+        if let Some(index) = s.strip_prefix("index:")
+        {
+            return index.trim().parse().map(DeviceSpec::Index).map_err(|_| syntax());
+        }
+        if let Some(name) = s.strip_prefix("name=") { return Ok(DeviceSpec::Name(name.to_string())) }
+        if let Some(text) = s.strip_prefix("name~") { return Ok(DeviceSpec::NameContains(text.to_string())) }
+
+        // An unknown host API type is written as "unknown:<id>", so its device follows the second colon
+        let skip = if s.get(.. 8).is_some_and(|prefix| prefix.eq_ignore_ascii_case("unknown:")) { 8 } else { 0 };
+        let (api, device) = match s[skip ..].find(':')
+        {
+            Some(i) => (&s[.. skip + i], &s[skip + i + 1 ..]),
+            None => return Err(syntax()),
+        };
+        let api_type = api.parse::<HostApiType>().map_err(|_| syntax())?;
+        match device
+        {
+            "" => Err(syntax()),
+            "default" => Ok(DeviceSpec::HostApi(api_type, None)),
+            device => Ok(DeviceSpec::HostApi(api_type, Some(device.to_string()))),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSpec
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            DeviceSpec::Default(None) => f.write_str("default"),
+            DeviceSpec::Default(Some(dir)) => write!(f, "default-{}", dir.name()),
+            DeviceSpec::Index(index) => write!(f, "index:{}", index),
+            DeviceSpec::Name(ref name) => write!(f, "name={}", name),
+            DeviceSpec::NameContains(ref text) => write!(f, "name~{}", text),
+            DeviceSpec::HostApi(api_type, None) => write!(f, "{}:default", api_type),
+            DeviceSpec::HostApi(api_type, Some(ref device)) => write!(f, "{}:{}", api_type, device),
+        }
+    }
+}
+
+/// Errors from parsing or resolving a DeviceSpec
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceSpecError
+{
+    /// The string is not in one of the accepted forms
+    Syntax(String),
+
+    /// The host API is not available on this system
+    HostApiNotAvailable(HostApiType),
+
+    /// There is no default device for the direction, on the host API if given
+    NoDefault
+    {
+        /// The host API, or None for the default host API
+        host_api: Option<HostApiType>,
+        /// The direction the device was wanted for
+        direction: Direction,
+    },
+
+    /// No device matches
+    NoDevice
+    {
+        /// The specification
+        spec: String,
+        /// The direction the device was wanted for
+        direction: Direction,
+        /// Names of devices that come close
+        near_misses: Vec<String>,
+    },
+}
+
+impl fmt::Display for DeviceSpecError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            DeviceSpecError::Syntax(ref s) =>
+                write!(f, "invalid device \"{}\", expected default, default-input, default-output, index:<n>, \
+                           name=<name>, name~<text> or <host API>:<device>", s),
+            DeviceSpecError::HostApiNotAvailable(api_type) => write!(f, "host API {} is not available", api_type),
+            DeviceSpecError::NoDefault { host_api: None, direction } => write!(f, "there is no default {} device", direction.name()),
+            DeviceSpecError::NoDefault { host_api: Some(api_type), direction } =>
+                write!(f, "host API {} has no default {} device", api_type, direction.name()),
+            DeviceSpecError::NoDevice { ref spec, direction, ref near_misses } =>
+            {
+                write!(f, "no {} device matches \"{}\"", direction.name(), spec)?;
+                if !near_misses.is_empty()
+                {
+                    write!(f, ", did you mean: {}", near_misses.iter().map(|n| format!("\"{}\"", n)).collect::<Vec<_>>().join(", "))?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl error::Error for DeviceSpecError {}
+
+#[cfg(test)]
+mod test
+{
+    use super::{DeviceSpec, Direction};
+    use hostapi::HostApiType;
+
+    #[test]
+    fn parse()
+    {
+        let parse = |s: &str| s.parse::<DeviceSpec>();
+        assert_eq!(parse("alsa:hw:1,0"), Ok(DeviceSpec::HostApi(HostApiType::ALSA, Some("hw:1,0".to_string()))));
+        assert_eq!(parse("jack:default"), Ok(DeviceSpec::HostApi(HostApiType::JACK, None)));
+        assert_eq!(parse("name~USB Audio"), Ok(DeviceSpec::NameContains("USB Audio".to_string())));
+        assert_eq!(parse("index:3"), Ok(DeviceSpec::Index(3)));
+        assert_eq!(parse("default-output"), Ok(DeviceSpec::Default(Some(Direction::Output))));
+        assert!(parse("index:x").is_err());
+        assert!(parse("speakers").is_err());
+        assert_eq!(parse("alsa:hw:1,0").unwrap().to_string(), "alsa:hw:1,0");
+        assert_eq!(parse("unknown:99:default"), Ok(DeviceSpec::HostApi(HostApiType::Unknown(99), None)));
+        assert_eq!(parse("unknown:99:hw:0").unwrap().to_string(), "unknown:99:hw:0");
+    }
+
+    #[test]
+    fn near_misses()
+    {
+        let names = vec!["USB Audio CODEC".to_string(), "HDA Intel PCH".to_string(), "USB Audio Device".to_string()];
+        assert_eq!(super::near_misses("usb audio codex", &names), vec!["USB Audio CODEC", "USB Audio Device"]);
+        assert_eq!(super::bracketed_card("USB Audio: - (hw:1,0)"), Some("hw:1,0".to_string()));
+        assert_eq!(super::alsa_card("plughw:2,0"), Some("hw:2,0".to_string()));
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn resolve()
+    {
+        use mock::{self, MockDevice, MockHostApi, MockSystem};
+        use ll;
+
+        let mut system = MockSystem::default();
+        system.host_apis.push(MockHostApi { type_id: ll::paALSA, name: "ALSA".to_string(), default_input: None, default_output: Some(3) });
+        system.devices.push(MockDevice::new("USB Audio: - (hw:1,0)", 1, 0, 2));
+        mock::configure(system);
+        ::initialize().unwrap();
+
+        let resolve = |s: &str, direction| s.parse::<DeviceSpec>().unwrap().resolve(direction);
+        assert_eq!(resolve("alsa:hw:1,0", Direction::Output).unwrap().index, 3);
+        assert_eq!(resolve("alsa:default", Direction::Output).unwrap().alsa_device, Some("hw:1,0".to_string()));
+        assert_eq!(resolve("name~duplex", Direction::Input).unwrap().index, 2);
+        assert_eq!(resolve("default", Direction::Input).unwrap().index, 0);
+        assert_eq!(resolve("index:1", Direction::Output).unwrap().alsa_device, None);
+        assert!(resolve("index:1", Direction::Input).is_err());
+        assert_eq!(resolve("name=Mock Outptu", Direction::Output).unwrap_err().to_string(),
+                   "no output device matches \"name=Mock Outptu\", did you mean: \"Mock Output\", \"Mock Duplex\"");
+        assert!(resolve("alsa:default", Direction::Input).is_err());
+        ::terminate().unwrap();
+    }
+}
@@ -1,20 +1,71 @@
 //! Contains the Stream class and associated values
 
 use ll;
-use pa::{PaError, PaResult};
-use device::DeviceIndex;
-use util::{to_pa_result, pa_time_to_duration, duration_to_pa_time};
+use pa::{self, PaError, PaResult};
+use error::{Endpoint, Error, Operation};
+use device::{self, DeviceIndex};
+use util::{to_pa_result, to_error, pa_time_to_duration, duration_to_pa_time};
+use adapter::{Adapter, Direction, Rates};
+use convert::{self, Conversion};
+use resample::ResampleQuality;
+use routing::Routing;
+use overrides::{self, Settings};
 use std::time::Duration;
+use std::cell::RefCell;
 use libc::{c_void, c_ulong};
 use std::io::prelude::*;
-use std::ptr;
+use std::{mem, ptr, slice};
+use std::sync::{RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 type StreamCallbackType = extern "C" fn(*const c_void, *mut c_void, ::libc::c_ulong, *const ll::PaStreamCallbackTimeInfo, ll::PaStreamCallbackFlags, *mut c_void) -> ::libc::c_int;
 type StreamFinishedCallbackType = extern "C" fn(*mut c_void);
 
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+// Held for reading while a stream is opened or closed, and for writing while PortAudio is
+// reinitialized, so no stream is opened between checking open_streams() and terminating
+static REINITIALIZE: RwLock<()> = RwLock::new(());
+
+fn opening() -> RwLockReadGuard<'static, ()>
+{
+    REINITIALIZE.read().unwrap_or_else(|e| e.into_inner())
+}
+
+// Terminate PortAudio and initialize it again, which rescans the devices. No stream is opened or
+// closed meanwhile. Refuses while streams are open, as terminating would free them under their
+// owners, and when initialize() was called more than once, as terminate() would only undo one.
+pub(crate) fn reinitialize() -> Result<(), Error>
+{
+    let _reinitializing = REINITIALIZE.write().unwrap_or_else(|e| e.into_inner());
+    if open_streams() > 0 { return Err(Error::new(PaError::StreamsOpen, Operation::Reinitialize)) }
+    if pa::initialize_count() > 1 { return Err(Error::new(PaError::InitializedMoreThanOnce, Operation::Reinitialize)) }
+
+    if let Err(e) = pa::terminate()
+    {
+        // A previous attempt may have failed to initialize again
+        if e != PaError::NotInitialized { return Err(e) }
+    }
+    pa::initialize()
+}
+
+// The number of streams that are open, which terminating PortAudio would close
+#[cfg(not(feature = "mock"))]
+pub(crate) fn open_streams() -> usize
+{
+    OPEN_STREAMS.load(Ordering::SeqCst)
+}
+
+// The mock emulates a system per thread, so only the streams of this thread count
+#[cfg(feature = "mock")]
+pub(crate) fn open_streams() -> usize
+{
+    ll::mock::open_streams()
+}
+
 /// Allowable return values for a StreamCallback
 #[repr(u32)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum StreamCallbackResult
 {
     /// Continue invoking the callback
@@ -39,19 +90,24 @@ struct StreamUserData<'a, I, O>
     num_output: u32,
     callback: Option<Box<StreamCallback<'a, I, O>>>,
     finished_callback: Option<Box<StreamFinishedCallback<'a>>>,
+    adapter: Option<RefCell<Adapter>>,
 }
 
 /// Time information for various stream related values
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StreamTimeInfo
 {
     /// Timestamp for the ADC capture time of the first frame
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub input_adc_time: Duration,
 
     /// Timestamp that the callback was invoked
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub current_time: Duration,
 
     /// Timestamp for the DAC output time of the first frame
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub output_dac_time: Duration,
 }
 
@@ -118,30 +174,61 @@ extern "C" fn stream_callback<I, O>(input: *const c_void,
     // We do not want to deallocate this memory since it is owned by other user code. So leak the box.
     let stream_data: &mut StreamUserData<I, O> = Box::leak( unsafe { Box::from_raw(user_data as *mut StreamUserData<I, O>) } );
 
-    let input_buffer: &[I] = unsafe
-    {
-        ::std::slice::from_raw_parts(input as *const I, frame_count as usize * stream_data.num_input as usize)
-    };
-    let output_buffer: &mut [O] = unsafe
-    {
-        ::std::slice::from_raw_parts_mut(output as *mut O, frame_count as usize * stream_data.num_output as usize)
-    };
-
+    // PaStreamCallbackFlags is a c_ulong, which is only 32 bits on Windows
+    #[allow(clippy::unnecessary_cast)]
     let flags = StreamCallbackFlags::from_bits_truncate(status_flags as u64);
 
     assert!(!time_info.is_null());
     let time_info_ll = unsafe {  &*time_info };
     let timeinfo = StreamTimeInfo::from_ll(time_info_ll);
 
-    let result = match stream_data.callback
+    let result = match *stream_data
     {
-        Some(ref mut f) => (*f)(input_buffer, output_buffer, timeinfo, flags),
-        None => StreamCallbackResult::Abort,
+        StreamUserData { callback: Some(ref mut f), adapter: Some(ref mut adapter), .. } => unsafe
+        {
+            adapter.get_mut().process(input, output, frame_count as usize, |input_bytes, output_bytes, offset, extra_flags|
+            {
+                let timeinfo = StreamTimeInfo
+                {
+                    input_adc_time: timeinfo.input_adc_time + offset,
+                    current_time: timeinfo.current_time,
+                    output_dac_time: timeinfo.output_dac_time + offset,
+                };
+                (*f)(from_bytes(input_bytes), from_bytes_mut(output_bytes), timeinfo, flags | extra_flags)
+            })
+        },
+        StreamUserData { callback: Some(ref mut f), num_input, num_output, .. } =>
+        {
+            // The buffer of a direction the stream does not have is null
+            let input_buffer: &[I] = match num_input
+            {
+                0 => &[],
+                n => unsafe { ::std::slice::from_raw_parts(input as *const I, frame_count as usize * n as usize) },
+            };
+            let output_buffer: &mut [O] = match num_output
+            {
+                0 => &mut [],
+                n => unsafe { ::std::slice::from_raw_parts_mut(output as *mut O, frame_count as usize * n as usize) },
+            };
+            (*f)(input_buffer, output_buffer, timeinfo, flags)
+        },
+        _ => StreamCallbackResult::Abort,
     };
 
     result as i32
 }
 
+// The adapter keeps its buffers aligned for any sample type
+unsafe fn from_bytes<T>(bytes: &[u8]) -> &[T]
+{
+    slice::from_raw_parts(bytes.as_ptr() as *const T, bytes.len() / mem::size_of::<T>())
+}
+
+unsafe fn from_bytes_mut<T>(bytes: &mut [u8]) -> &mut [T]
+{
+    slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut T, bytes.len() / mem::size_of::<T>())
+}
+
 extern "C" fn stream_finished_callback<I, O>(user_data: *mut c_void)
 {
     // We do not want to deallocate this memory since it is owned by other user code. So leak the box.
@@ -152,6 +239,35 @@ extern "C" fn stream_finished_callback<I, O>(user_data: *mut c_void)
     };
 }
 
+bitflags!(
+    #[doc="Sample formats known to PortAudio"]
+    pub struct SampleFormat: u64 {
+        #[doc="32 bit floating point samples in the range [-1.0, 1.0]"]
+        const FLOAT32         = 0x0000_0001;
+
+        #[doc="32 bit signed integer samples"]
+        const INT32           = 0x0000_0002;
+
+        #[doc="24 bit signed integer samples, packed in three bytes"]
+        const INT24           = 0x0000_0004;
+
+        #[doc="16 bit signed integer samples"]
+        const INT16           = 0x0000_0008;
+
+        #[doc="8 bit signed integer samples"]
+        const INT8            = 0x0000_0010;
+
+        #[doc="8 bit unsigned integer samples, centered around 128"]
+        const UINT8           = 0x0000_0020;
+
+        #[doc="A host API specific format"]
+        const CUSTOM_FORMAT   = 0x0001_0000;
+
+        #[doc="Buffers are passed as an array of pointers, one per channel"]
+        const NON_INTERLEAVED = 0x8000_0000;
+    }
+);
+
 /// Types that are allowed to be used as samples in a Stream
 ///
 /// *WARNING*: It is not advised to implement this trait for any other types as the size and flag
@@ -159,18 +275,20 @@ extern "C" fn stream_finished_callback<I, O>(user_data: *mut c_void)
 pub trait SampleType
 {
     /// Should return the PortAudio flag which corresponds to the type
-    fn sample_format() -> u64;
+    fn sample_format() -> SampleFormat;
 }
-impl SampleType for f32 { fn sample_format() -> u64 { 0x0000_0001 } }
-impl SampleType for i32 { fn sample_format() -> u64 { 0x0000_0002 } }
-impl SampleType for i16 { fn sample_format() -> u64 { 0x0000_0008 } }
-impl SampleType for i8 { fn sample_format() -> u64 { 0x0000_0010 } }
-impl SampleType for u8 { fn sample_format() -> u64 { 0x0000_0020 } }
+impl SampleType for f32 { fn sample_format() -> SampleFormat { SampleFormat::FLOAT32 } }
+impl SampleType for i32 { fn sample_format() -> SampleFormat { SampleFormat::INT32 } }
+impl SampleType for i16 { fn sample_format() -> SampleFormat { SampleFormat::INT16 } }
+impl SampleType for i8 { fn sample_format() -> SampleFormat { SampleFormat::INT8 } }
+impl SampleType for u8 { fn sample_format() -> SampleFormat { SampleFormat::UINT8 } }
 
-#[cfg(test)]
-fn get_sample_size<T: SampleType>() -> Result<u32, PaError>
+/// Get the size in bytes of a single sample in the given format
+///
+/// Returns Err(SampleFormatNotSupported) when the format is not supported.
+pub fn sample_size(format: SampleFormat) -> Result<u32, PaError>
 {
-    match unsafe { ll::Pa_GetSampleSize(<T as SampleType>::sample_format() as c_ulong) }
+    match unsafe { ll::Pa_GetSampleSize(format.bits as c_ulong) }
     {
         n if n >= 0 => Ok(n as u32),
         m => to_pa_result(m).map(|_| 0),
@@ -181,6 +299,42 @@ fn get_sample_size<T: SampleType>() -> Result<u32, PaError>
 /// optimal number of frames per buffer. This number may differ each time the callback is called.
 pub const FRAMES_PER_BUFFER_UNSPECIFIED: u64 = 0;
 
+/// Sample formats the device is tried with, in order of preference, when the stream may convert
+const DEVICE_FORMATS: [SampleFormat; 6] = [SampleFormat::FLOAT32, SampleFormat::INT32, SampleFormat::INT24,
+                                           SampleFormat::INT16, SampleFormat::INT8, SampleFormat::UINT8];
+
+/// Sample rates the device is tried with when the stream may resample, after the default sample
+/// rates of the devices
+const DEVICE_RATES: [f64; 9] = [48_000.0, 44_100.0, 96_000.0, 88_200.0, 192_000.0, 32_000.0, 22_050.0, 16_000.0, 8_000.0];
+
+/// Additional processing between the device and the application, used with
+/// Stream::open_with_options()
+///
+/// The default value does not add any processing.
+#[derive(Clone, Default)]
+pub struct StreamOptions
+{
+    /// When set, the device may be opened with another sample format than the one presented to
+    /// the application. The best format the device supports is used, and the samples are
+    /// converted with the given options.
+    pub conversion: Option<Conversion>,
+
+    /// When set and the device does not support the requested sample rate, the device is opened
+    /// at a supported rate instead, preferring its default sample rate. The samples are then
+    /// resampled with the given quality.
+    pub resample: Option<ResampleQuality>,
+
+    /// When set, the device is opened with the source channels of the routing, and the
+    /// application sees its destination channels. The channel count of the input parameters
+    /// has to match the destinations.
+    pub input_routing: Option<Routing>,
+
+    /// When set, the application provides the source channels of the routing, and the device is
+    /// opened with its destination channels. The channel count of the output parameters has to
+    /// match the sources.
+    pub output_routing: Option<Routing>,
+}
+
 /// An object for an PortAudio stream
 ///
 /// Streams can have an input type I and output type O.
@@ -189,6 +343,12 @@ pub struct Stream<'a, I: SampleType, O: SampleType>
     pa_stream: *mut ll::PaStream,
     inputs: u32,
     outputs: u32,
+    input_format: Option<SampleFormat>,
+    output_format: Option<SampleFormat>,
+    sample_rate: f64,
+    device_sample_rate: f64,
+    input_delay: Duration,
+    output_delay: Duration,
     user_data: Box<StreamUserData<'a, I, O>>,
 }
 
@@ -196,58 +356,135 @@ impl<'a, T: SampleType> Stream<'a, T, T>
 {
     /// Constructs a stream using the default input and output devices
     ///
+    /// When overrides::enable() was called, environment variables can change the devices and the
+    /// settings, see the overrides module.
+    ///
     /// ## Arguments
     /// * num_input_channels: Desired number of input channels
     /// * num_output_channels: Desired number of output channels
     /// * sample_rate: Sample rate of the stream
     /// * frames_per_buffer: Number of frames per buffer. Use FRAMES_PER_BUFFER_UNSPECIFIED to let
-    /// portaudio determine the optimal number.
+    ///   portaudio determine the optimal number.
     /// * callback: Some(callback) which PortAudio will call to read/write the buffers, or None
-    /// when using the read and write methods
+    ///   when using the read and write methods
     pub fn open_default(num_input_channels: u32,
                         num_output_channels: u32,
                         sample_rate: f64,
                         frames_per_buffer: u64,
                         callback: Option<Box<StreamCallback<'a, T, T>>>)
-                       -> Result<Stream<'a, T, T>, PaError>
+                       -> Result<Stream<'a, T, T>, Error>
     {
-        let callback_pointer = match callback
+        let settings = match overrides::active()
         {
-            Some(_) => Some(stream_callback::<T, T> as StreamCallbackType),
-            None => None,
+            Some(o) => o.apply(num_input_channels, num_output_channels, sample_rate, frames_per_buffer),
+            None => Settings
+            {
+                input_device: None,
+                output_device: None,
+                sample_rate,
+                frames_per_buffer,
+                suggested_latency: None,
+            },
         };
+        Stream::open_default_with(&settings, num_input_channels, num_output_channels, callback)
+    }
+
+    pub(crate) fn open_default_with(settings: &Settings,
+                                    num_input_channels: u32,
+                                    num_output_channels: u32,
+                                    callback: Option<Box<StreamCallback<'a, T, T>>>)
+                                   -> Result<Stream<'a, T, T>, Error>
+    {
+        let Settings { sample_rate, frames_per_buffer, .. } = *settings;
+        let callback_pointer = callback.as_ref().map(|_| stream_callback::<T, T> as StreamCallbackType);
         let mut userdata = Box::new(StreamUserData
         {
             num_input: num_input_channels,
             num_output: num_output_channels,
             callback,
             finished_callback: None,
+            adapter: None,
         });
         let mut pa_stream = ::std::ptr::null_mut();
 
         let pointer_for_callback: *mut c_void = &mut *userdata as *mut StreamUserData<T, T> as *mut c_void;
 
-        let code = unsafe
+        let operation = Operation::Open
         {
-            ll::Pa_OpenDefaultStream(&mut pa_stream,
-                                     num_input_channels as i32,
-                                     num_output_channels as i32,
-                                     <T as SampleType>::sample_format() as c_ulong,
-                                     sample_rate,
-                                     frames_per_buffer as c_ulong,
-                                     callback_pointer,
-                                     pointer_for_callback)
+            input: Some(Endpoint { device: settings.input_device, channel_count: num_input_channels }).filter(|_| num_input_channels > 0),
+            output: Some(Endpoint { device: settings.output_device, channel_count: num_output_channels }).filter(|_| num_output_channels > 0),
+            sample_rate,
+            frames_per_buffer,
         };
-
-        match to_pa_result(code)
+        let _opening = opening();
+        let code = if settings.needs_parameters()
         {
-            Ok(()) => Ok(Stream { pa_stream,
-                                  user_data: userdata,
-                                  inputs: num_input_channels,
-                                  outputs: num_output_channels,
-                         }),
-            Err(v) => Err(v),
+            // Pa_OpenDefaultStream takes no devices or latencies, so pass what it would use
+            let parameters = |device: Option<DeviceIndex>, default: Option<DeviceIndex>, channel_count: u32, input: bool|
+            {
+                let device = device.or(default)?;
+                let info = device::get_info(device)?;
+                let latency = if input { info.default_high_input_latency } else { info.default_high_output_latency };
+                Some(ll::Struct_PaStreamParameters
+                {
+                    device: device as i32,
+                    channelCount: channel_count as i32,
+                    sampleFormat: <T as SampleType>::sample_format().bits as c_ulong,
+                    suggestedLatency: duration_to_pa_time(settings.suggested_latency.unwrap_or(latency)),
+                    hostApiSpecificStreamInfo: ptr::null_mut(),
+                })
+            };
+            let unavailable = || Error::new(PaError::DeviceUnavailable, operation.clone());
+            let input = match num_input_channels
+            {
+                0 => None,
+                n => Some(parameters(settings.input_device, device::get_default_input_index(), n, true).ok_or_else(unavailable)?),
+            };
+            let output = match num_output_channels
+            {
+                0 => None,
+                n => Some(parameters(settings.output_device, device::get_default_output_index(), n, false).ok_or_else(unavailable)?),
+            };
+            unsafe
+            {
+                ll::Pa_OpenStream(&mut pa_stream,
+                                  input.as_ref().map_or(ptr::null(), |p| p as *const _),
+                                  output.as_ref().map_or(ptr::null(), |p| p as *const _),
+                                  sample_rate,
+                                  frames_per_buffer as c_ulong,
+                                  StreamFlags::empty().bits as c_ulong,
+                                  callback_pointer,
+                                  pointer_for_callback)
+            }
         }
+        else
+        {
+            unsafe
+            {
+                ll::Pa_OpenDefaultStream(&mut pa_stream,
+                                         num_input_channels as i32,
+                                         num_output_channels as i32,
+                                         <T as SampleType>::sample_format().bits as c_ulong,
+                                         sample_rate,
+                                         frames_per_buffer as c_ulong,
+                                         callback_pointer,
+                                         pointer_for_callback)
+            }
+        };
+
+        to_error(code, operation)?;
+        OPEN_STREAMS.fetch_add(1, Ordering::SeqCst);
+        Ok(Stream { pa_stream,
+                    user_data: userdata,
+                    inputs: num_input_channels,
+                    outputs: num_output_channels,
+                    input_format: Some(<T as SampleType>::sample_format()).filter(|_| num_input_channels > 0),
+                    output_format: Some(<T as SampleType>::sample_format()).filter(|_| num_output_channels > 0),
+                    sample_rate,
+                    device_sample_rate: sample_rate,
+                    input_delay: Duration::from_secs(0),
+                    output_delay: Duration::from_secs(0),
+        })
     }
 }
 
@@ -260,85 +497,175 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O>
     /// * output: Specification for the output channel, or None for an input-only stream
     /// * sample_rate: Sample rate of the stream
     /// * frames_per_buffer: Number of frames per buffer. Use FRAMES_PER_BUFFER_UNSPECIFIED to let
-    /// portaudio determine the optimal number.
+    ///   portaudio determine the optimal number.
     /// * flags: Additional flags for the behaviour of the stream
     /// * callback: Some(callback) which PortAudio will call to read/write the buffers, or None
-    /// when using the read and write methods
+    ///   when using the read and write methods
     pub fn open(input: Option<StreamParameters<I>>,
                 output: Option<StreamParameters<O>>,
                 sample_rate: f64,
                 frames_per_buffer: u64,
                 flags: StreamFlags,
                 callback: Option<Box<StreamCallback<'a, I, O>>>)
-               -> Result<Stream<'a, I, O>, PaError>
+               -> Result<Stream<'a, I, O>, Error>
     {
-        let callback_pointer = match callback
+        Stream::open_with_options(input, output, sample_rate, frames_per_buffer, flags, StreamOptions::default(), callback)
+    }
+
+    /// Constructs a stream like Stream::open(), with additional processing between the device
+    /// and the application
+    ///
+    /// ## Arguments
+    /// * input: Specification for the input channel, or None for an output-only stream
+    /// * output: Specification for the output channel, or None for an input-only stream
+    /// * sample_rate: Sample rate of the stream
+    /// * frames_per_buffer: Number of frames per buffer. Use FRAMES_PER_BUFFER_UNSPECIFIED to let
+    ///   portaudio determine the optimal number.
+    /// * flags: Additional flags for the behaviour of the stream
+    /// * options: The processing to apply to the buffers
+    /// * callback: Some(callback) which PortAudio will call to read/write the buffers, or None
+    ///   when using the read and write methods
+    pub fn open_with_options(input: Option<StreamParameters<I>>,
+                             output: Option<StreamParameters<O>>,
+                             sample_rate: f64,
+                             frames_per_buffer: u64,
+                             flags: StreamFlags,
+                             options: StreamOptions,
+                             callback: Option<Box<StreamCallback<'a, I, O>>>)
+                            -> Result<Stream<'a, I, O>, Error>
+    {
+        let callback_pointer = callback.as_ref().map(|_| stream_callback::<I, O> as StreamCallbackType);
+        let operation = Operation::Open
+        {
+            input: input.as_ref().map(StreamParameters::endpoint),
+            output: output.as_ref().map(StreamParameters::endpoint),
+            sample_rate,
+            frames_per_buffer,
+        };
+        let fail = |code| Error::new(code, operation.clone());
+
+        // With routing, the device is opened with other channels than the application sees
+        let (app_inputs, input) = route_parameters(input, options.input_routing.as_ref().map(|r| (r.destinations(), r.sources()))).map_err(fail)?;
+        let (app_outputs, output) = route_parameters(output, options.output_routing.as_ref().map(|r| (r.sources(), r.destinations()))).map_err(fail)?;
+
+        // Find the formats and the sample rate to open the device with
+        let (device_rate, (input_format, output_format)) = match (options.conversion, options.resample)
         {
-            Some(_) => Some(stream_callback::<I, O> as StreamCallbackType),
-            None => None,
+            (None, None) => (sample_rate, (input.as_ref().map(|_| <I as SampleType>::sample_format()),
+                                           output.as_ref().map(|_| <O as SampleType>::sample_format()))),
+            _ =>
+            {
+                let mut result = Err(PaError::InvalidSampleRate);
+                for rate in candidate_rates(input.as_ref(), output.as_ref(), sample_rate, options.resample.is_some())
+                {
+                    match negotiate_formats(input.as_ref(), output.as_ref(), rate, options.conversion.is_some())
+                    {
+                        Ok(formats) => { result = Ok((rate, formats)); break },
+                        // Report the error for the requested sample rate
+                        Err(e) => if rate == sample_rate { result = Err(e) },
+                    }
+                }
+                result.map_err(fail)?
+            },
         };
 
-        let input_cnt; let input_obj; let input_ptr;
-        let output_cnt; let output_obj; let output_ptr;
-        match input {
-            Some(sp) => { input_cnt = sp.channel_count; input_obj = sp.to_ll(); input_ptr = &input_obj as *const _ },
-            None => { input_cnt = 0; input_ptr = ptr::null() },
+        let input_obj; let output_obj;
+        let (input_cnt, input_ptr) = match (input, input_format) {
+            (Some(sp), Some(format)) => { input_obj = sp.to_ll_with_format(format); (sp.channel_count, &input_obj as *const _) },
+            _ => (0, ptr::null()),
+        };
+        let (output_cnt, output_ptr) = match (output, output_format) {
+            (Some(sp), Some(format)) => { output_obj = sp.to_ll_with_format(format); (sp.channel_count, &output_obj as *const _) },
+            _ => (0, ptr::null()),
         };
-        match output {
-            Some(sp) => { output_cnt = sp.channel_count; output_obj = sp.to_ll(); output_ptr = &output_obj as *const _ },
-            None => { output_cnt = 0; output_ptr = ptr::null() },
+
+        let input_direction = input_format.map(|format| Direction {
+            channels: app_inputs,
+            device_channels: input_cnt,
+            app_format: <I as SampleType>::sample_format(),
+            device_format: format,
+            routing: options.input_routing.clone(),
+        });
+        let output_direction = output_format.map(|format| Direction {
+            channels: app_outputs,
+            device_channels: output_cnt,
+            app_format: <O as SampleType>::sample_format(),
+            device_format: format,
+            routing: options.output_routing.clone(),
+        });
+        let rates = match options.resample
+        {
+            Some(quality) if device_rate != sample_rate => Some(Rates { app: sample_rate, device: device_rate, quality }),
+            _ => None,
         };
+        let adapter = Adapter::new(input_direction,
+                                   output_direction,
+                                   options.conversion.unwrap_or_default(),
+                                   device_rate,
+                                   rates,
+                                   frames_per_buffer);
+        let input_delay = adapter.as_ref().map_or(Duration::from_secs(0), |a| a.input_delay());
+        let output_delay = adapter.as_ref().map_or(Duration::from_secs(0), |a| a.output_delay());
+
+        // Keep the device buffers about as long as those of the application
+        let device_frames_per_buffer = (frames_per_buffer as f64 * device_rate / sample_rate).round() as u64;
 
         let mut user_data = Box::new(StreamUserData
         {
-            num_input: input_cnt,
-            num_output: output_cnt,
+            num_input: app_inputs,
+            num_output: app_outputs,
             callback,
             finished_callback: None,
+            adapter: adapter.map(RefCell::new),
         });
 
         let mut pa_stream = ::std::ptr::null_mut();
         let pointer_for_callback: *mut c_void = &mut *user_data as *mut StreamUserData<I, O> as *mut c_void;
 
+        let _opening = opening();
         let result = unsafe
         {
             ll::Pa_OpenStream(&mut pa_stream,
                               input_ptr,
                               output_ptr,
-                              sample_rate,
-                              frames_per_buffer as c_ulong,
+                              device_rate,
+                              device_frames_per_buffer as c_ulong,
                               flags.bits as c_ulong,
                               callback_pointer,
                               pointer_for_callback)
         };
 
-        match to_pa_result(result)
-        {
-            Ok(()) => Ok(Stream { pa_stream,
-                                  user_data,
-                                  inputs: input_cnt,
-                                  outputs: output_cnt,
-                      }),
-            Err(v) => Err(v),
-        }
+        to_error(result, operation)?;
+        OPEN_STREAMS.fetch_add(1, Ordering::SeqCst);
+        Ok(Stream { pa_stream,
+                    user_data,
+                    inputs: app_inputs,
+                    outputs: app_outputs,
+                    input_format,
+                    output_format,
+                    sample_rate,
+                    device_sample_rate: device_rate,
+                    input_delay,
+                    output_delay,
+        })
     }
 
     /// Starts the stream
-    pub fn start(&self) -> PaResult
+    pub fn start(&self) -> Result<(), Error>
     {
-        to_pa_result(unsafe { ll::Pa_StartStream(self.pa_stream) })
+        to_error(unsafe { ll::Pa_StartStream(self.pa_stream) }, Operation::Start)
     }
 
     /// Stops the stream. It will block untill all audio has finished playing
-    pub fn stop(&self) -> PaResult
+    pub fn stop(&self) -> Result<(), Error>
     {
-        to_pa_result(unsafe { ll::Pa_StopStream(self.pa_stream) })
+        to_error(unsafe { ll::Pa_StopStream(self.pa_stream) }, Operation::Stop)
     }
 
     /// Stop stream immediately without waiting for the buffers to complete
-    pub fn abort(&self) -> PaResult
+    pub fn abort(&self) -> Result<(), Error>
     {
-        to_pa_result(unsafe { ll::Pa_AbortStream(self.pa_stream) })
+        to_error(unsafe { ll::Pa_AbortStream(self.pa_stream) }, Operation::Abort)
     }
 
     fn close(&self) -> PaResult
@@ -347,42 +674,42 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O>
     }
 
     /// Returns wether the stream is stopped
-    pub fn is_stopped(&self) -> Result<bool, PaError>
+    pub fn is_stopped(&self) -> Result<bool, Error>
     {
         match unsafe { ll::Pa_IsStreamStopped(self.pa_stream) }
         {
             1 => Ok(true),
-            n => to_pa_result(n).map(|_| false),
+            n => to_error(n, Operation::Status).map(|_| false),
         }
     }
 
     /// Returns wether the stream is active
-    pub fn is_active(&self) -> Result<bool, PaError>
+    pub fn is_active(&self) -> Result<bool, Error>
     {
         match unsafe { ll::Pa_IsStreamActive(self.pa_stream) }
         {
             1 => Ok(true),
-            n => to_pa_result(n).map(|_| false),
+            n => to_error(n, Operation::Status).map(|_| false),
         }
     }
 
     /// Get the number of frames that can be read from the stream without waiting
-    pub fn num_read_available(&self) -> Result<u32, PaError>
+    pub fn num_read_available(&self) -> Result<u32, Error>
     {
         match unsafe { ll::Pa_GetStreamReadAvailable(self.pa_stream) }
         {
             n if n >= 0 => { Ok(n as u32) },
-            n => to_pa_result(n as i32).map(|_| 0),
+            n => to_error(n as i32, Operation::Status).map(|_| 0),
         }
     }
 
     /// Get the number of frames that can be written to the stream without waiting
-    pub fn num_write_available(&self) -> Result<u32, PaError>
+    pub fn num_write_available(&self) -> Result<u32, Error>
     {
         match unsafe { ll::Pa_GetStreamWriteAvailable(self.pa_stream) }
         {
             n if n >= 0 => { Ok(n as u32) },
-            n => to_pa_result(n as i32).map(|_| 0),
+            n => to_error(n as i32, Operation::Status).map(|_| 0),
         }
     }
 
@@ -393,32 +720,61 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O>
     /// * `CanNotWriteToAnInputOnlyStream`: when num_output_channels = 0
     /// * `BadBufferPtr`: when buffer.len() is not a multiple of num_output_channels
     /// * Some other error given by PortAudio
-    pub fn write(&self, buffer: &[O]) -> PaResult
+    pub fn write(&self, buffer: &[O]) -> Result<(), Error>
     {
+        let frames = buffer.len() / self.outputs.max(1) as usize;
+        let operation = Operation::Write { frames: frames as u32 };
+
         if self.outputs == 0
         {
-            return Err(PaError::CanNotWriteToAnInputOnlyStream)
+            return Err(Error::new(PaError::CanNotWriteToAnInputOnlyStream, operation))
         }
 
         // Ensure the buffer is the correct size.
         if buffer.len() % self.outputs as usize != 0
         {
-            return Err(PaError::BadBufferPtr)
+            return Err(Error::new(PaError::BadBufferPtr, operation))
         }
 
-        let pointer = buffer.as_ptr() as *const c_void;
-        let frames = (buffer.len() / self.outputs as usize) as c_ulong;
+        if let Some(ref adapter) = self.user_data.adapter
+        {
+            // The adapter is in use by the callback of a callback stream
+            if self.user_data.callback.is_some() { return Err(Error::new(PaError::CanNotWriteToACallbackStream, operation)) }
 
-        to_pa_result(unsafe { ll::Pa_WriteStream(self.pa_stream, pointer, frames) })
+            let mut adapter = adapter.borrow_mut();
+            if adapter.converts_output()
+            {
+                let pa_stream = self.pa_stream;
+                return adapter.render(convert::as_bytes(buffer), frames, |device, device_frames|
+                {
+                    to_error(unsafe { ll::Pa_WriteStream(pa_stream, device.as_ptr() as *const c_void, device_frames as c_ulong) }, operation.clone())
+                });
+            }
+        }
+
+        let pointer = buffer.as_ptr() as *const c_void;
+        to_error(unsafe { ll::Pa_WriteStream(self.pa_stream, pointer, frames as c_ulong) }, operation)
     }
 
     /// Reads the requested number of frames from the input devices. This function blocks until
     /// the whole buffer has been filled.
     ///
     /// Will return `CanNotReadFromAnOutputOnlyStream` if num_input_channels = 0.
-    pub fn read(&self, frames: u32) -> Result<Vec<I>, PaError>
+    pub fn read(&self, frames: u32) -> Result<Vec<I>, Error>
     {
-        if self.inputs == 0 { return Err(PaError::CanNotReadFromAnOutputOnlyStream) }
+        let operation = Operation::Read { frames };
+        if self.inputs == 0 { return Err(Error::new(PaError::CanNotReadFromAnOutputOnlyStream, operation)) }
+
+        if let Some(ref adapter) = self.user_data.adapter
+        {
+            if self.user_data.callback.is_some() { return Err(Error::new(PaError::CanNotReadFromACallbackStream, operation)) }
+
+            let mut adapter = adapter.borrow_mut();
+            if adapter.converts_input()
+            {
+                return self.read_adapted(&mut adapter, frames);
+            }
+        }
 
         // We create a buffer with the needed capacity. Then we feed that to the library, which
         // will fill the buffer accordingly. Afterwards, we set the length of the vector as all its
@@ -427,7 +783,7 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O>
         let mut buffer = Vec::with_capacity(vec_len as usize);
 
         let buffer_ptr = buffer.as_mut_ptr() as *mut c_void;
-        match to_pa_result(unsafe { ll::Pa_ReadStream(self.pa_stream, buffer_ptr, frames as c_ulong) })
+        match to_error(unsafe { ll::Pa_ReadStream(self.pa_stream, buffer_ptr, frames as c_ulong) }, operation)
         {
             Ok(()) =>
             {
@@ -438,6 +794,49 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O>
         }
     }
 
+    fn read_adapted(&self, adapter: &mut Adapter, frames: u32) -> Result<Vec<I>, Error>
+    {
+        let pa_stream = self.pa_stream;
+        let app = adapter.capture(frames as usize, |device, device_frames|
+        {
+            to_error(unsafe { ll::Pa_ReadStream(pa_stream, device.as_mut_ptr() as *mut c_void, device_frames as c_ulong) },
+                     Operation::Read { frames })
+        })?;
+        let app_samples: &[I] = unsafe { from_bytes(app) };
+        let mut buffer = Vec::with_capacity(app_samples.len());
+        unsafe
+        {
+            ptr::copy_nonoverlapping(app_samples.as_ptr(), buffer.as_mut_ptr(), app_samples.len());
+            buffer.set_len(app_samples.len());
+        }
+        Ok(buffer)
+    }
+
+    /// The sample format the input device was opened with. This differs from the input sample
+    /// type when a conversion was requested with Stream::open_with_options().
+    ///
+    /// Returns None for an output-only stream.
+    pub fn input_device_format(&self) -> Option<SampleFormat>
+    {
+        self.input_format
+    }
+
+    /// The sample format the output device was opened with. This differs from the output sample
+    /// type when a conversion was requested with Stream::open_with_options().
+    ///
+    /// Returns None for an input-only stream.
+    pub fn output_device_format(&self) -> Option<SampleFormat>
+    {
+        self.output_format
+    }
+
+    /// The sample rate the device was opened with. This differs from the sample rate of the
+    /// stream when resampling was requested with Stream::open_with_options().
+    pub fn device_sample_rate(&self) -> f64
+    {
+        self.device_sample_rate
+    }
+
     /// Returns the cpu load the stream callback consumes. This will return 0.0 if the stream uses
     /// blocking read/write, or if an error occured.
     pub fn cpu_load(&self) -> f64
@@ -454,31 +853,43 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O>
 
     /// Get the actual latencies and sample rate
     ///
+    /// When the stream resamples, the latencies include the delay of the resampler and the
+    /// sample rate is the one presented to the application. See also device_sample_rate().
+    ///
     /// Returns None when the stream is invalid or an error occured
     pub fn info(&self) -> Option<StreamInfo>
     {
-        unsafe
+        let info = unsafe
         {
             match ll::Pa_GetStreamInfo(self.pa_stream) {
-                p if p.is_null() => None,
-                p => Some(StreamInfo::from_ll(&*p)),
+                p if p.is_null() => return None,
+                p => StreamInfo::from_ll(&*p),
             }
-        }
+        };
+
+        if self.device_sample_rate == self.sample_rate { return Some(info) }
+
+        Some(StreamInfo
+        {
+            input_latency: info.input_latency + self.input_delay,
+            output_latency: info.output_latency + self.output_delay,
+            sample_rate: self.sample_rate,
+        })
     }
 
     /// Set a callback which is to be called when the StreamCallback finishes
-    pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) -> PaResult
+    pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) -> Result<(), Error>
     {
         self.user_data.finished_callback = Some(finished_callback);
         let callback_pointer = Some(stream_finished_callback::<I, O> as StreamFinishedCallbackType);
-        to_pa_result(unsafe { ll::Pa_SetStreamFinishedCallback(self.pa_stream, callback_pointer) })
+        to_error(unsafe { ll::Pa_SetStreamFinishedCallback(self.pa_stream, callback_pointer) }, Operation::SetFinishedCallback)
     }
 
     /// Remove any previously attached finish callback
-    pub fn unset_finished_callback(&mut self) -> PaResult
+    pub fn unset_finished_callback(&mut self) -> Result<(), Error>
     {
         self.user_data.finished_callback = None;
-        to_pa_result(unsafe { ll::Pa_SetStreamFinishedCallback(self.pa_stream, None) })
+        to_error(unsafe { ll::Pa_SetStreamFinishedCallback(self.pa_stream, None) }, Operation::SetFinishedCallback)
     }
 }
 
@@ -487,15 +898,18 @@ impl<'a, I: SampleType, O: SampleType> Drop for Stream<'a, I, O>
     fn drop(&mut self)
     {
         debug_assert!(self.user_data.num_output == self.outputs); //userdata should not be garbled
+        let _closing = opening();
         if let Err(v) = self.close()
         {
             let _ = writeln!(&mut ::std::io::stderr(), "Stream drop error: {:?}", v);
         };
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
 /// Stream parameters to be used with Stream::open()
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StreamParameters<T>
 {
     /// Index of the device to use
@@ -505,52 +919,156 @@ pub struct StreamParameters<T>
     pub channel_count: u32,
 
     /// Desired latency of the stream
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub suggested_latency: Duration,
 
     /// Sample data to be used in the stream
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub data: T,
 }
 
 impl<T: SampleType> StreamParameters<T>
 {
     fn to_ll(&self) -> ll::Struct_PaStreamParameters
+    {
+        self.to_ll_with_format(<T as SampleType>::sample_format())
+    }
+
+    fn to_ll_with_format(&self, format: SampleFormat) -> ll::Struct_PaStreamParameters
     {
         ll::Struct_PaStreamParameters
         {
             device: self.device as i32,
             channelCount: self.channel_count as i32,
-            sampleFormat: <T as SampleType>::sample_format() as c_ulong,
+            sampleFormat: format.bits as c_ulong,
             suggestedLatency: duration_to_pa_time(self.suggested_latency),
             hostApiSpecificStreamInfo: ::std::ptr::null_mut(),
         }
     }
+
+    fn endpoint(&self) -> Endpoint
+    {
+        Endpoint { device: Some(self.device), channel_count: self.channel_count }
+    }
+}
+
+// Returns the channel count of the application and the parameters to open the device with. The
+// routing is given as its number of logical and physical channels.
+fn route_parameters<T: SampleType>(params: Option<StreamParameters<T>>,
+                                   routing: Option<(u32, u32)>) -> Result<(u32, Option<StreamParameters<T>>), PaError>
+{
+    match (params, routing)
+    {
+        (Some(sp), Some((logical, physical))) =>
+        {
+            if sp.channel_count != logical { return Err(PaError::InvalidChannelCount) }
+            Ok((logical, Some(StreamParameters { channel_count: physical, .. sp })))
+        },
+        (Some(sp), None) => Ok((sp.channel_count, Some(sp))),
+        (None, _) => Ok((0, None)),
+    }
+}
+
+// The requested sample rate, followed by the rates to try when resampling is allowed
+fn candidate_rates<I: SampleType, O: SampleType>(input: Option<&StreamParameters<I>>,
+                                                 output: Option<&StreamParameters<O>>,
+                                                 sample_rate: f64,
+                                                 resample: bool) -> Vec<f64>
+{
+    let mut rates = vec![sample_rate];
+    if !resample { return rates }
+
+    let defaults = output.map(|sp| sp.device).into_iter()
+        .chain(input.map(|sp| sp.device))
+        .filter_map(device::get_info)
+        .map(|info| info.default_sample_rate);
+    for rate in defaults.chain(DEVICE_RATES.iter().cloned())
+    {
+        if !rates.contains(&rate) { rates.push(rate); }
+    }
+    rates
+}
+
+// Find the device formats for both directions at the given sample rate
+fn negotiate_formats<I: SampleType, O: SampleType>(input: Option<&StreamParameters<I>>,
+                                                   output: Option<&StreamParameters<O>>,
+                                                   sample_rate: f64,
+                                                   convert: bool) -> Result<(Option<SampleFormat>, Option<SampleFormat>), PaError>
+{
+    let input_format = match input
+    {
+        Some(sp) => Some(negotiate_format(sp, true, sample_rate, convert)?),
+        None => None,
+    };
+    let output_format = match output
+    {
+        Some(sp) => Some(negotiate_format(sp, false, sample_rate, convert)?),
+        None => None,
+    };
+    Ok((input_format, output_format))
+}
+
+// Find the best format the device supports for the parameters, starting with the sample type
+// itself. Other formats are only tried when convert is set. When no format is supported, the
+// error for the sample type itself is returned.
+fn negotiate_format<T: SampleType>(params: &StreamParameters<T>, is_input: bool, sample_rate: f64, convert: bool) -> Result<SampleFormat, PaError>
+{
+    let app_format = <T as SampleType>::sample_format();
+    let mut result = Err(PaError::SampleFormatNotSupported);
+    let others: &[SampleFormat] = if convert { &DEVICE_FORMATS } else { &[] };
+
+    for &format in Some(app_format).iter().chain(others.iter())
+    {
+        let params_ll = params.to_ll_with_format(format);
+        let (input_ptr, output_ptr) = match is_input
+        {
+            true => (&params_ll as *const _, ptr::null()),
+            false => (ptr::null(), &params_ll as *const _),
+        };
+        match to_pa_result(unsafe { ll::Pa_IsFormatSupported(input_ptr, output_ptr, sample_rate) })
+        {
+            Ok(()) => return Ok(format),
+            Err(e) => if format == app_format { result = Err(e) },
+        }
+    }
+
+    result
 }
 
 /// Returns Ok when the StreamParameters are supported. This ignores the latency field.
-pub fn is_format_supported<I: SampleType, O: SampleType>(input: Option<StreamParameters<I>>, output: Option<StreamParameters<O>>, sample_rate: f64) -> PaResult
+pub fn is_format_supported<I: SampleType, O: SampleType>(input: Option<StreamParameters<I>>, output: Option<StreamParameters<O>>, sample_rate: f64) -> Result<(), Error>
 {
-    let input_obj; let input_ptr;
-    let output_obj; let output_ptr;
-    match input {
-        Some(sp) => { input_obj = sp.to_ll(); input_ptr = &input_obj as *const _ },
-        None => input_ptr = ptr::null(),
+    let operation = Operation::IsFormatSupported
+    {
+        input: input.as_ref().map(StreamParameters::endpoint),
+        output: output.as_ref().map(StreamParameters::endpoint),
+        sample_rate,
+    };
+
+    let input_obj; let output_obj;
+    let input_ptr = match input {
+        Some(sp) => { input_obj = sp.to_ll(); &input_obj as *const _ },
+        None => ptr::null(),
     };
-    match output {
-        Some(sp) => { output_obj = sp.to_ll(); output_ptr = &output_obj as *const _ },
-        None => output_ptr = ptr::null(),
+    let output_ptr = match output {
+        Some(sp) => { output_obj = sp.to_ll(); &output_obj as *const _ },
+        None => ptr::null(),
     };
 
-    to_pa_result(unsafe { ll::Pa_IsFormatSupported(input_ptr, output_ptr, sample_rate) })
+    to_error(unsafe { ll::Pa_IsFormatSupported(input_ptr, output_ptr, sample_rate) }, operation)
 }
 
 /// Information about the actual latency and sample rate values the stream uses
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StreamInfo
 {
     /// Input latency
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub input_latency: Duration,
 
     /// Output latency
+    #[cfg_attr(feature = "serde", serde(with = "::serialize::seconds"))]
     pub output_latency: Duration,
 
     /// Sample rate
@@ -591,11 +1109,20 @@ mod test
     {
         use std::mem;
 
-        let pa_size = super::get_sample_size::<T>().unwrap() as usize;
+        let pa_size = super::sample_size(<T as SampleType>::sample_format()).unwrap() as usize;
         let rs_size = mem::size_of::<T>();
         assert_eq!(rs_size, pa_size);
     }
 
+    #[test]
+    fn packed_sample_size()
+    {
+        use super::SampleFormat;
+
+        assert_eq!(super::sample_size(SampleFormat::INT24).unwrap(), 3);
+        assert_eq!(super::sample_size(SampleFormat::INT16 | SampleFormat::NON_INTERLEAVED).unwrap(), 2);
+    }
+
     // In the FFI some assumptions are made as to how Some(p) and None are
     // represented when used as function pointers. This test asserts these
     // assumptions.
@@ -613,4 +1140,116 @@ mod test
     }
 
     extern "C" fn external_function() {}
+
+    #[cfg(feature = "mock")]
+    mod mock
+    {
+        use super::super::*;
+        use mock::{self, MockDevice, MockSystem, Operation};
+        use error::ErrorKind;
+        use std::cell::Cell;
+        use std::time::Duration;
+
+        fn params<T: SampleType>(device: DeviceIndex, channel_count: u32, data: T) -> StreamParameters<T>
+        {
+            StreamParameters { device, channel_count, suggested_latency: Duration::from_millis(10), data }
+        }
+
+        #[test]
+        fn callback_stream()
+        {
+            ::initialize().unwrap();
+            mock::push_input(2, &[0.5; 2 * 512]);
+
+            let calls = Cell::new(0);
+            let callback = Box::new(|input: &[f32], output: &mut [f32], _: StreamTimeInfo, flags: StreamCallbackFlags|
+            {
+                assert_eq!(input.len(), 2 * 256);
+                output.copy_from_slice(input);
+                calls.set(calls.get() + 1);
+                if flags.contains(StreamCallbackFlags::INPUT_OVERFLOW) { StreamCallbackResult::Complete }
+                else { StreamCallbackResult::Continue }
+            });
+            let stream = Stream::open(Some(params(2, 2, 0.0f32)), Some(params(2, 2, 0.0f32)), 48_000.0, 256,
+                                      StreamFlags::empty(), Some(callback)).unwrap();
+            stream.start().unwrap();
+
+            mock::advance(0.01);
+            assert_eq!(calls.get(), 1);
+            assert!(mock::take_output(2).iter().all(|&v| v == 0.5));
+
+            mock::inject_xrun(StreamCallbackFlags::INPUT_OVERFLOW.bits);
+            mock::advance(0.1);
+            assert_eq!(calls.get(), 2);
+            assert!(!stream.is_active().unwrap());
+            assert!(!stream.is_stopped().unwrap());
+            stream.stop().unwrap();
+
+            drop(stream);
+            assert_eq!(mock::open_streams(), 0);
+            ::terminate().unwrap();
+        }
+
+        #[test]
+        fn blocking_stream()
+        {
+            ::initialize().unwrap();
+            mock::push_input(0, &[0.25; 100]);
+
+            let stream = Stream::<i16, i16>::open_default(1, 1, 44_100.0, FRAMES_PER_BUFFER_UNSPECIFIED, None).unwrap();
+            stream.start().unwrap();
+            assert_eq!(stream.read(100).unwrap(), vec![8192i16; 100]);
+            stream.write(&[16_384i16; 441]).unwrap();
+            assert_eq!(mock::take_output(1), vec![0.5; 441]);
+            assert!((mock::time() - 0.01).abs() < 1e-9);
+
+            mock::fail_next(Operation::WriteStream, ll::paDeviceUnavailable);
+            let error = stream.write(&[0i16; 10]).unwrap_err();
+            assert_eq!(error, PaError::DeviceUnavailable);
+            assert_eq!(error.kind(), ErrorKind::Fatal);
+            assert_eq!(error.operation(), Some(&::Operation::Write { frames: 10 }));
+
+            mock::set_host_error(ll::paALSA, -32, "Broken pipe");
+            mock::fail_next(Operation::WriteStream, ll::paUnanticipatedHostError);
+            let error = stream.write(&[0i16; 10]).unwrap_err();
+            assert_eq!(error.host_error().map(|e| (e.code, e.text.as_str())), Some((-32, "Broken pipe")));
+            assert_eq!(error.to_string(), format!("{} during write (10 frames): alsa error -32: Broken pipe", PaError::UnanticipatedHostError));
+            drop(stream);
+            ::terminate().unwrap();
+        }
+
+        #[test]
+        fn negotiated_format_and_rate()
+        {
+            let mut system = MockSystem::default();
+            system.devices[1] = MockDevice { formats: SampleFormat::INT16.bits as _, sample_rates: vec![48_000.0],
+                                             default_sample_rate: 48_000.0, .. MockDevice::new("Output", 0, 0, 2) };
+            mock::configure(system);
+            ::initialize().unwrap();
+
+            let plain = Stream::<i16, i16>::open(None, Some(params(1, 2, 0i16)), 44_100.0, 0, StreamFlags::empty(), None);
+            let error = plain.err().unwrap();
+            assert_eq!(error, PaError::InvalidSampleRate);
+            assert_eq!(error.kind(), ErrorKind::Configuration);
+            match error.operation()
+            {
+                Some(&::Operation::Open { output: Some(output), sample_rate, .. }) => assert_eq!((output.channel_count, sample_rate), (2, 44_100.0)),
+                other => panic!("unexpected operation {:?}", other),
+            }
+
+            let options = StreamOptions
+            {
+                conversion: Some(Conversion::default()),
+                resample: Some(ResampleQuality::Linear),
+                .. StreamOptions::default()
+            };
+            let stream = Stream::<f32, f32>::open_with_options(None, Some(params(1, 2, 0.0f32)), 44_100.0, 0,
+                                                              StreamFlags::empty(), options, None).unwrap();
+            assert_eq!(stream.output_device_format(), Some(SampleFormat::INT16));
+            assert_eq!(stream.device_sample_rate(), 48_000.0);
+            drop(stream);
+            ::terminate().unwrap();
+        }
+    }
+
 }
@@ -0,0 +1,464 @@
+//! Callback streams that reopen themselves when their device goes away
+//!
+//! A SupervisedStream is checked periodically by the application. When its callback stalls, the
+//! stream fails or becomes inactive on its own, the stream is closed, PortAudio is terminated and
+//! initialized again to rescan the devices, and the stream is reopened on the same device, found
+//! by its name and host API, or on the fallback. The callback carries on where it left off.
+//!
+//! Terminating PortAudio would close every stream of the process, and only rescans when it was
+//! initialized once. So while other streams are open or initialize() was called more than once,
+//! the recovery fails with StreamsOpen or InitializedMoreThanOnce instead, and the next check
+//! tries again. Turn the rescan off with set_refresh() to reopen the stream without it.
+
+use device::{self, DeviceIndex};
+use error::{Endpoint, Error, Operation};
+use hostapi::{self, HostApiType};
+use pa::PaError;
+use stream::{self, Stream, StreamCallback, StreamCallbackResult, StreamFlags, StreamParameters, SampleType};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Time without callbacks after which a running stream is considered stalled
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where to reopen a stream when its own device is gone
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fallback
+{
+    /// Keep failing until the device is back
+    None,
+
+    /// The default device of each direction
+    Default,
+
+    /// The first device with the given name and enough channels
+    Name(String),
+}
+
+/// The reason a stream was recovered
+#[derive(Clone, Debug)]
+pub enum Cause
+{
+    /// The callback was not called for longer than the stall timeout
+    Stalled,
+
+    /// The stream reported an error
+    Failed(Error),
+
+    /// The stream became inactive without being stopped and without its callback finishing
+    Inactive,
+}
+
+/// A stream that was reopened by SupervisedStream::check()
+#[derive(Clone, Debug)]
+pub struct Recovery
+{
+    /// Why the stream was reopened
+    pub cause: Cause,
+
+    /// The input device the stream was reopened on
+    pub input: Option<DeviceIndex>,
+
+    /// The output device the stream was reopened on
+    pub output: Option<DeviceIndex>,
+
+    /// Wether a fallback device was used for either direction
+    pub fallback: bool,
+
+    /// The number of attempts the recovery took, including the one that succeeded
+    pub attempts: u32,
+}
+
+struct Side<T>
+{
+    parameters: StreamParameters<T>,
+    name: String,
+    api_type: Option<HostApiType>,
+}
+
+impl<T: Copy> Side<T>
+{
+    fn new(parameters: StreamParameters<T>) -> Option<Side<T>>
+    {
+        let info = device::get_info(parameters.device)?;
+        let api_type = hostapi::get_info(info.host_api).map(|api| api.api_type);
+        Some(Side { parameters, name: info.name, api_type })
+    }
+
+    // Find the device again, or else the fallback. Returns the parameters with the device that
+    // was found and wether it is the fallback.
+    fn locate(&self, fallback: &Fallback, input: bool) -> Option<(StreamParameters<T>, bool)>
+    {
+        let channels = self.parameters.channel_count;
+        let fits = |index: DeviceIndex| device::get_info(index).is_some_and(|info|
+        {
+            channels <= if input { info.max_input_channels } else { info.max_output_channels }
+        });
+        let count = device::get_count().unwrap_or(0);
+        let find = |name: &str, api_type: Option<HostApiType>| (0..count).find(|&index|
+        {
+            device::get_info(index).is_some_and(|info| info.name == name) && fits(index)
+                && (api_type.is_none() || hostapi::host_api_for(index).map(|api| api.api_type) == api_type)
+        });
+
+        let (device, is_fallback) = match find(&self.name, self.api_type)
+        {
+            Some(index) => (index, false),
+            None => match *fallback
+            {
+                Fallback::None => return None,
+                Fallback::Default =>
+                {
+                    let default = if input { device::get_default_input_index() } else { device::get_default_output_index() };
+                    (default.filter(|&index| fits(index))?, true)
+                },
+                Fallback::Name(ref name) => (find(name, None)?, true),
+            },
+        };
+        Some((StreamParameters { device, .. self.parameters }, is_fallback))
+    }
+}
+
+/// A callback stream that is reopened when its device fails or disappears
+pub struct SupervisedStream<I: SampleType + 'static, O: SampleType + 'static>
+{
+    stream: Option<Stream<'static, I, O>>,
+    input: Option<Side<I>>,
+    output: Option<Side<O>>,
+    sample_rate: f64,
+    frames_per_buffer: u64,
+    flags: StreamFlags,
+    callback: Arc<Mutex<Box<StreamCallback<'static, I, O>>>>,
+    calls: Arc<AtomicUsize>,
+    finished: Arc<AtomicBool>,
+    last_calls: usize,
+    last_progress: Instant,
+    running: bool,
+    pending: Option<Cause>,
+    attempts: u32,
+    stall_timeout: Duration,
+    fallback: Fallback,
+    refresh: bool,
+}
+
+impl<I: SampleType + Copy + 'static, O: SampleType + Copy + 'static> SupervisedStream<I, O>
+{
+    /// Open a supervised stream, with the same arguments as Stream::open()
+    ///
+    /// The devices are remembered by their name and host API.
+    pub fn open(input: Option<StreamParameters<I>>,
+                output: Option<StreamParameters<O>>,
+                sample_rate: f64,
+                frames_per_buffer: u64,
+                flags: StreamFlags,
+                callback: Box<StreamCallback<'static, I, O>>)
+               -> Result<SupervisedStream<I, O>, Error>
+    {
+        let operation = Operation::Open
+        {
+            input: input.map(|p| Endpoint { device: Some(p.device), channel_count: p.channel_count }),
+            output: output.map(|p| Endpoint { device: Some(p.device), channel_count: p.channel_count }),
+            sample_rate,
+            frames_per_buffer,
+        };
+        let input_side = match input
+        {
+            Some(p) => Some(Side::new(p).ok_or_else(|| Error::new(PaError::InvalidDevice, operation.clone()))?),
+            None => None,
+        };
+        let output_side = match output
+        {
+            Some(p) => Some(Side::new(p).ok_or_else(|| Error::new(PaError::InvalidDevice, operation.clone()))?),
+            None => None,
+        };
+
+        let mut supervised = SupervisedStream
+        {
+            stream: None,
+            input: input_side,
+            output: output_side,
+            sample_rate,
+            frames_per_buffer,
+            flags,
+            callback: Arc::new(Mutex::new(callback)),
+            calls: Arc::new(AtomicUsize::new(0)),
+            finished: Arc::new(AtomicBool::new(false)),
+            last_calls: 0,
+            last_progress: Instant::now(),
+            running: false,
+            pending: None,
+            attempts: 0,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            fallback: Fallback::None,
+            refresh: true,
+        };
+        supervised.stream = Some(supervised.open_stream(input, output)?);
+        Ok(supervised)
+    }
+
+    /// Set the time without callbacks after which a running stream is reopened
+    pub fn set_stall_timeout(&mut self, timeout: Duration)
+    {
+        self.stall_timeout = timeout;
+    }
+
+    /// Set where to reopen the stream when its devices are gone. The default is Fallback::None.
+    pub fn set_fallback(&mut self, fallback: Fallback)
+    {
+        self.fallback = fallback;
+    }
+
+    /// Set wether PortAudio is terminated and initialized again to rescan the devices before
+    /// the stream is reopened. This is on by default.
+    pub fn set_refresh(&mut self, refresh: bool)
+    {
+        self.refresh = refresh;
+    }
+
+    /// The current stream, or None while a recovery is failing
+    pub fn stream(&self) -> Option<&Stream<'static, I, O>>
+    {
+        self.stream.as_ref()
+    }
+
+    /// Starts the stream, after which it is supervised
+    pub fn start(&mut self) -> Result<(), Error>
+    {
+        if let Some(ref stream) = self.stream { stream.start()? }
+        self.running = true;
+        self.finished.store(false, Ordering::Release);
+        self.last_progress = Instant::now();
+        Ok(())
+    }
+
+    /// Stops the stream, and its supervision
+    pub fn stop(&mut self) -> Result<(), Error>
+    {
+        self.running = false;
+        self.pending = None;
+        match self.stream
+        {
+            Some(ref stream) => stream.stop(),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the stream, and reopen it when it has failed
+    ///
+    /// Returns the recovery when the stream was reopened. When reopening fails, the error is
+    /// returned and the next check tries again.
+    pub fn check(&mut self) -> Result<Option<Recovery>, Error>
+    {
+        if !self.running { return Ok(None) }
+        let cause = match self.pending.take().or_else(|| self.diagnose())
+        {
+            Some(cause) => cause,
+            None => return Ok(None),
+        };
+
+        self.attempts += 1;
+        match self.recover()
+        {
+            Ok((input, output, fallback)) =>
+            {
+                let attempts = self.attempts;
+                self.attempts = 0;
+                Ok(Some(Recovery { cause, input, output, fallback, attempts }))
+            },
+            Err(e) =>
+            {
+                self.pending = Some(cause);
+                Err(e)
+            },
+        }
+    }
+
+    fn diagnose(&mut self) -> Option<Cause>
+    {
+        let active = match self.stream
+        {
+            Some(ref stream) => stream.is_active(),
+            None => return Some(Cause::Inactive),
+        };
+        if self.finished.load(Ordering::Acquire) { return None }
+        match active
+        {
+            Err(e) => return Some(Cause::Failed(e)),
+            Ok(false) => return Some(Cause::Inactive),
+            Ok(true) => {},
+        }
+
+        let calls = self.calls.load(Ordering::Relaxed);
+        let now = Instant::now();
+        if calls != self.last_calls
+        {
+            self.last_calls = calls;
+            self.last_progress = now;
+            return None;
+        }
+        if now.duration_since(self.last_progress) >= self.stall_timeout { Some(Cause::Stalled) } else { None }
+    }
+
+    fn recover(&mut self) -> Result<(Option<DeviceIndex>, Option<DeviceIndex>, bool), Error>
+    {
+        if let Some(stream) = self.stream.take()
+        {
+            let _ = stream.abort();
+        }
+        if self.refresh
+        {
+            stream::reinitialize()?;
+        }
+
+        let input = self.input.as_ref().map(|side| side.locate(&self.fallback, true));
+        let output = self.output.as_ref().map(|side| side.locate(&self.fallback, false));
+        let (input, output) = match (input, output)
+        {
+            (Some(None), _) | (_, Some(None)) => return Err(Error::new(PaError::DeviceUnavailable, self.operation())),
+            (input, output) => (input.and_then(|x| x), output.and_then(|x| x)),
+        };
+        let fallback = input.is_some_and(|x| x.1) || output.is_some_and(|x| x.1);
+        let (input, output) = (input.map(|x| x.0), output.map(|x| x.0));
+
+        let stream = self.open_stream(input, output)?;
+        stream.start()?;
+        self.stream = Some(stream);
+        self.last_progress = Instant::now();
+        Ok((input.map(|p| p.device), output.map(|p| p.device), fallback))
+    }
+
+    // The open operation, with the devices that are gone
+    fn operation(&self) -> Operation
+    {
+        let endpoint = |channel_count| Endpoint { device: None, channel_count };
+        Operation::Open
+        {
+            input: self.input.as_ref().map(|side| endpoint(side.parameters.channel_count)),
+            output: self.output.as_ref().map(|side| endpoint(side.parameters.channel_count)),
+            sample_rate: self.sample_rate,
+            frames_per_buffer: self.frames_per_buffer,
+        }
+    }
+
+    fn open_stream(&self, input: Option<StreamParameters<I>>, output: Option<StreamParameters<O>>) -> Result<Stream<'static, I, O>, Error>
+    {
+        let callback = self.callback.clone();
+        let calls = self.calls.clone();
+        let finished = self.finished.clone();
+        let forward = Box::new(move |input: &[I], output: &mut [O], time, flags|
+        {
+            calls.fetch_add(1, Ordering::Relaxed);
+            let mut callback = callback.lock().unwrap_or_else(|e| e.into_inner());
+            let result = (*callback)(input, output, time, flags);
+            if result != StreamCallbackResult::Continue { finished.store(true, Ordering::Release) }
+            result
+        });
+        Stream::open(input, output, self.sample_rate, self.frames_per_buffer, self.flags, Some(forward))
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod test
+{
+    use super::{Cause, Fallback, SupervisedStream};
+    use stream::{Stream, StreamParameters, StreamCallbackResult, StreamFlags};
+    use mock::{self, MockDevice, MockSystem};
+    use pa::PaError;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn output(device: u32) -> Option<StreamParameters<f32>>
+    {
+        Some(StreamParameters { device, channel_count: 2, suggested_latency: Duration::from_secs(0), data: 0.0 })
+    }
+
+    #[test]
+    fn reopens_moved_device()
+    {
+        mock::configure(MockSystem::default());
+        ::initialize().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let mut supervised = SupervisedStream::<f32, f32>::open(None, output(1), 48_000.0, 480, StreamFlags::empty(),
+                                                                Box::new(move |_, _, _, _|
+                                                                {
+                                                                    counter.fetch_add(1, Ordering::Relaxed);
+                                                                    StreamCallbackResult::Continue
+                                                                })).unwrap();
+        supervised.start().unwrap();
+        mock::advance(0.02);
+        assert!(supervised.check().unwrap().is_none());
+
+        // The device comes back at another index
+        let mut system = MockSystem::default();
+        system.devices.insert(0, MockDevice::new("USB Headset", 0, 1, 2));
+        mock::configure(system);
+        mock::disconnect(1);
+
+        let recovery = supervised.check().unwrap().unwrap();
+        assert!(matches!(recovery.cause, Cause::Inactive));
+        assert_eq!((recovery.output, recovery.fallback, recovery.attempts), (Some(2), false, 1));
+        mock::advance(0.02);
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        supervised.set_stall_timeout(Duration::from_secs(0));
+        assert!(supervised.check().unwrap().is_none());
+        assert!(matches!(supervised.check().unwrap().map(|r| r.cause), Some(Cause::Stalled)));
+
+        supervised.stop().unwrap();
+        drop(supervised);
+        ::terminate().unwrap();
+    }
+
+    #[test]
+    fn uses_fallback()
+    {
+        mock::configure(MockSystem::default());
+        ::initialize().unwrap();
+        let mut supervised = SupervisedStream::<f32, f32>::open(None, output(1), 48_000.0, 480, StreamFlags::empty(),
+                                                                Box::new(|_, _, _, _| StreamCallbackResult::Continue)).unwrap();
+        supervised.start().unwrap();
+
+        let mut system = MockSystem::default();
+        system.devices[1].name = "HDMI".to_string();
+        mock::configure(system);
+        mock::disconnect(1);
+
+        assert_eq!(supervised.check().unwrap_err(), PaError::DeviceUnavailable);
+        assert!(supervised.stream().is_none());
+
+        supervised.set_fallback(Fallback::Name("Mock Duplex".to_string()));
+        let recovery = supervised.check().unwrap().unwrap();
+        assert_eq!((recovery.output, recovery.fallback, recovery.attempts), (Some(2), true, 2));
+
+        supervised.stop().unwrap();
+        drop(supervised);
+        ::terminate().unwrap();
+    }
+
+    #[test]
+    fn waits_for_other_streams()
+    {
+        mock::configure(MockSystem::default());
+        ::initialize().unwrap();
+        let mut supervised = SupervisedStream::<f32, f32>::open(None, output(1), 48_000.0, 480, StreamFlags::empty(),
+                                                                Box::new(|_, _, _, _| StreamCallbackResult::Continue)).unwrap();
+        supervised.start().unwrap();
+        let other = Stream::<f32, f32>::open_default(0, 2, 48_000.0, 0, None).unwrap();
+        mock::disconnect(1);
+
+        // Terminating PortAudio would close the other stream under its owner
+        assert_eq!(supervised.check().unwrap_err(), PaError::StreamsOpen);
+        assert!(other.is_stopped().is_ok());
+        drop(other);
+
+        let recovery = supervised.check().unwrap().unwrap();
+        assert_eq!((recovery.output, recovery.attempts), (Some(1), 2));
+
+        supervised.stop().unwrap();
+        drop(supervised);
+        ::terminate().unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use pa::{PaResult, PaError};
+use error::{Error, Operation};
 use ll;
 
 use std::time::Duration;
@@ -12,6 +13,15 @@ pub fn to_pa_result(code: i32) -> PaResult
     Err(PaError::from_i32(code))
 }
 
+pub fn to_error(code: i32, operation: Operation) -> Result<(), Error>
+{
+    if code == ll::paNoError
+    {
+        return Ok(());
+    }
+    Err(Error::from_raw(code, Some(operation)))
+}
+
 pub fn pa_time_to_duration(input: f64) -> Duration
 {
     let valid_input = if input < 0.0 { 0.0 } else { input };
@@ -29,7 +39,7 @@ pub fn duration_to_pa_time(duration: Duration) -> f64
 mod test {
     #[test]
     fn test_conversion() {
-        let seconds = 2.512389131321938123681627;
+        let seconds = 2.512389131321938;
         let duration = super::pa_time_to_duration(seconds);
         let seconds2 = super::duration_to_pa_time(duration);
 
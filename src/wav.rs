@@ -0,0 +1,889 @@
+//! Recording to and playing from WAV files
+//!
+//! Files are written as RIFF/WAVE, and switch to RF64 when they outgrow the 4 GiB limit of
+//! RIFF. Reading supports integer PCM of 8, 16, 24 and 32 bits and 32 bit float, in RIFF/WAVE
+//! and RF64 files. Samples are converted to and from the sample type of the stream.
+
+use convert::{self, Conversion, Converter, Dither};
+use pa::PaError;
+use error::Error as StreamError;
+use stream::{SampleFormat, SampleType, Stream, StreamCallback, StreamFinishedCallback, StreamCallbackResult};
+use std::{error, fmt, io, mem};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// Tail of the KSDATAFORMAT_SUBTYPE GUIDs, which start with the format tag
+const SUBTYPE_TAIL: [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+// Size of the ds64 chunk of RF64 without a table, reserved up front with a JUNK chunk
+const DS64_SIZE: u32 = 28;
+
+// Samples converted at a time
+const CHUNK_SAMPLES: usize = 1024;
+
+// Buffers of CHUNK_SAMPLES frames between the callback of a Recorder and its writer thread
+const RECORDER_BUFFERS: usize = 16;
+
+// How often the writer thread of a Recorder checks whether the stream finished
+const RECORDER_POLL: Duration = Duration::from_millis(10);
+
+/// Errors from recording or playing WAV files
+#[derive(Debug)]
+pub enum Error
+{
+    /// Reading or writing the file failed, or the file is not a supported WAV file
+    Io(io::Error),
+
+    /// The stream failed
+    Pa(StreamError),
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            Error::Io(ref e) => write!(f, "WAV file error: {}", e),
+            Error::Pa(ref e) => write!(f, "Stream error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error
+{
+    fn from(e: io::Error) -> Error { Error::Io(e) }
+}
+
+impl From<StreamError> for Error
+{
+    fn from(e: StreamError) -> Error { Error::Pa(e) }
+}
+
+impl From<PaError> for Error
+{
+    fn from(e: PaError) -> Error { Error::Pa(e.into()) }
+}
+
+/// The layout of the samples in a WAV file
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct WavSpec
+{
+    /// Number of interleaved channels
+    pub channels: u16,
+
+    /// Frames per second
+    pub sample_rate: u32,
+
+    /// Format of the samples. One of UINT8, INT16, INT24, INT32 or FLOAT32.
+    pub format: SampleFormat,
+}
+
+impl WavSpec
+{
+    fn tag_and_bits(&self) -> io::Result<(u16, u16)>
+    {
+        match self.format
+        {
+            SampleFormat::UINT8 => Ok((FORMAT_PCM, 8)),
+            SampleFormat::INT16 => Ok((FORMAT_PCM, 16)),
+            SampleFormat::INT24 => Ok((FORMAT_PCM, 24)),
+            SampleFormat::INT32 => Ok((FORMAT_PCM, 32)),
+            SampleFormat::FLOAT32 => Ok((FORMAT_FLOAT, 32)),
+            _ => Err(invalid_input("sample format can not be stored in a WAV file")),
+        }
+    }
+
+    fn sample_bytes(&self) -> usize
+    {
+        convert::format_size(self.format).unwrap()
+    }
+}
+
+/// Writes samples to a WAV file
+///
+/// The header is completed by finalize(), or when the writer is dropped.
+pub struct WavWriter<W: Write + Seek>
+{
+    writer: W,
+    spec: WavSpec,
+    converter: Converter,
+    data_offset: u64,
+    data_bytes: u64,
+    bytes: Vec<u8>,
+    finalized: bool,
+}
+
+impl WavWriter<BufWriter<File>>
+{
+    /// Create a WAV file at the given path
+    pub fn create<P: AsRef<Path>>(path: P, spec: WavSpec) -> io::Result<WavWriter<BufWriter<File>>>
+    {
+        WavWriter::new(BufWriter::new(File::create(path)?), spec)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W>
+{
+    /// Write a WAV header to the writer, and prepare for writing samples
+    pub fn new(mut writer: W, spec: WavSpec) -> io::Result<WavWriter<W>>
+    {
+        let (tag, bits) = spec.tag_and_bits()?;
+        if spec.channels == 0 { return Err(invalid_input("a WAV file needs at least one channel")) }
+
+        let block_align = spec.channels * bits / 8;
+        let extensible = spec.channels > 2 || (bits > 16 && tag == FORMAT_PCM);
+
+        let mut header = Vec::with_capacity(96);
+        header.extend_from_slice(b"RIFF\0\0\0\0WAVE");
+        header.extend_from_slice(b"JUNK");
+        header.extend_from_slice(&DS64_SIZE.to_le_bytes());
+        header.extend_from_slice(&[0; DS64_SIZE as usize]);
+
+        header.extend_from_slice(b"fmt ");
+        let fmt_size: u32 = if extensible { 40 } else if tag == FORMAT_FLOAT { 18 } else { 16 };
+        header.extend_from_slice(&fmt_size.to_le_bytes());
+        header.extend_from_slice(&(if extensible { FORMAT_EXTENSIBLE } else { tag }).to_le_bytes());
+        header.extend_from_slice(&spec.channels.to_le_bytes());
+        header.extend_from_slice(&spec.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(spec.sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        if extensible
+        {
+            header.extend_from_slice(&22u16.to_le_bytes());
+            header.extend_from_slice(&bits.to_le_bytes());
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header.extend_from_slice(&tag.to_le_bytes());
+            header.extend_from_slice(&SUBTYPE_TAIL);
+        }
+        else if tag == FORMAT_FLOAT
+        {
+            header.extend_from_slice(&0u16.to_le_bytes());
+        }
+
+        header.extend_from_slice(b"data\0\0\0\0");
+        writer.write_all(&header)?;
+
+        Ok(WavWriter
+        {
+            writer,
+            spec,
+            converter: Converter::new(Conversion::default()),
+            data_offset: header.len() as u64,
+            data_bytes: 0,
+            bytes: Vec::new(),
+            finalized: false,
+        })
+    }
+
+    /// The layout of the file
+    pub fn spec(&self) -> WavSpec
+    {
+        self.spec
+    }
+
+    /// The number of complete frames written so far
+    pub fn frames(&self) -> u64
+    {
+        self.data_bytes / (self.spec.channels as u64 * self.spec.sample_bytes() as u64)
+    }
+
+    /// Write interleaved samples, converting them to the format of the file
+    pub fn write_samples<T: SampleType>(&mut self, samples: &[T]) -> io::Result<()>
+    {
+        if samples.is_empty() { return Ok(()) }
+
+        let size = self.spec.sample_bytes();
+        let input = convert::as_bytes(samples);
+        if <T as SampleType>::sample_format() == self.spec.format
+        {
+            self.bytes.clear();
+            self.bytes.extend_from_slice(input);
+        }
+        else
+        {
+            let in_size = mem::size_of::<T>();
+            let mut float = [0.0f32; CHUNK_SAMPLES];
+            self.bytes.resize(samples.len() * size, 0);
+            for (i, o) in input.chunks(CHUNK_SAMPLES * in_size).zip(self.bytes.chunks_mut(CHUNK_SAMPLES * size))
+            {
+                let float = &mut float[.. i.len() / in_size];
+                convert::decode(<T as SampleType>::sample_format(), i, float);
+                self.converter.encode(float, self.spec.format, o);
+            }
+        }
+
+        swap_little_endian(&mut self.bytes, size);
+        self.writer.write_all(&self.bytes)?;
+        self.data_bytes += self.bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Complete the header and flush the file
+    pub fn finalize(mut self) -> io::Result<()>
+    {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> io::Result<()>
+    {
+        if self.finalized { return Ok(()) }
+        self.finalized = true;
+
+        if self.data_bytes % 2 == 1
+        {
+            self.writer.write_all(&[0])?;
+        }
+
+        let riff_size = self.data_offset - 8 + self.data_bytes + self.data_bytes % 2;
+        if riff_size <= u32::MAX as u64
+        {
+            self.writer.seek(SeekFrom::Start(4))?;
+            self.writer.write_all(&(riff_size as u32).to_le_bytes())?;
+            self.writer.seek(SeekFrom::Start(self.data_offset - 4))?;
+            self.writer.write_all(&(self.data_bytes as u32).to_le_bytes())?;
+        }
+        else
+        {
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.writer.write_all(b"RF64")?;
+            self.writer.write_all(&u32::MAX.to_le_bytes())?;
+            self.writer.seek(SeekFrom::Start(12))?;
+            self.writer.write_all(b"ds64")?;
+            self.writer.seek(SeekFrom::Start(20))?;
+            self.writer.write_all(&riff_size.to_le_bytes())?;
+            self.writer.write_all(&self.data_bytes.to_le_bytes())?;
+            self.writer.write_all(&self.frames().to_le_bytes())?;
+            self.writer.seek(SeekFrom::Start(self.data_offset - 4))?;
+            self.writer.write_all(&u32::MAX.to_le_bytes())?;
+        }
+
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W>
+{
+    fn drop(&mut self)
+    {
+        let _ = self.finish();
+    }
+}
+
+/// Reads samples from a WAV file
+pub struct WavReader<R: Read + Seek>
+{
+    reader: R,
+    spec: WavSpec,
+    converter: Converter,
+    data_bytes: u64,
+    remaining: u64,
+    bytes: Vec<u8>,
+}
+
+impl WavReader<BufReader<File>>
+{
+    /// Open the WAV file at the given path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<WavReader<BufReader<File>>>
+    {
+        WavReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> WavReader<R>
+{
+    /// Read the header, leaving the reader at the first sample
+    pub fn new(mut reader: R) -> io::Result<WavReader<R>>
+    {
+        let mut riff = [0u8; 12];
+        reader.read_exact(&mut riff)?;
+        if &riff[0 .. 4] != b"RIFF" && &riff[0 .. 4] != b"RF64" || &riff[8 .. 12] != b"WAVE"
+        {
+            return Err(invalid_data("not a WAV file"));
+        }
+
+        let mut ds64_data_size = None;
+        let mut spec = None;
+        loop
+        {
+            let mut chunk = [0u8; 8];
+            reader.read_exact(&mut chunk)?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            let pad = size as i64 % 2;
+
+            match &chunk[0 .. 4]
+            {
+                b"ds64" | b"fmt " =>
+                {
+                    let mut body = vec![0u8; size as usize];
+                    reader.read_exact(&mut body)?;
+                    reader.seek(SeekFrom::Current(pad))?;
+                    if &chunk[0 .. 4] == b"fmt " { spec = Some(parse_fmt(&body)?) }
+                    else if body.len() >= 16 { ds64_data_size = Some(le_u64(&body[8 .. 16])) }
+                },
+                b"data" =>
+                {
+                    let spec = spec.ok_or_else(|| invalid_data("data chunk before fmt chunk"))?;
+                    let data_bytes = match (size, ds64_data_size)
+                    {
+                        (u32::MAX, Some(n)) => n,
+                        (n, _) => n as u64,
+                    };
+                    return Ok(WavReader
+                    {
+                        reader,
+                        spec,
+                        converter: Converter::new(Conversion::default()),
+                        data_bytes,
+                        remaining: data_bytes,
+                        bytes: Vec::new(),
+                    });
+                },
+                // Other chunks, such as LIST and JUNK, are skipped without reading them
+                _ => { reader.seek(SeekFrom::Current(size as i64 + pad))?; },
+            }
+        }
+    }
+
+    /// The layout of the file
+    pub fn spec(&self) -> WavSpec
+    {
+        self.spec
+    }
+
+    /// The number of frames in the file
+    pub fn frames(&self) -> u64
+    {
+        self.data_bytes / (self.spec.channels as u64 * self.spec.sample_bytes() as u64)
+    }
+
+    /// Read interleaved samples, converted to the sample type of the buffer
+    ///
+    /// Only reads complete frames. Returns the number of samples read, which is 0 at the end of
+    /// the file. Fails with InvalidInput when the buffer is not empty but too small for a frame.
+    pub fn read_samples<T: SampleType>(&mut self, buffer: &mut [T]) -> io::Result<usize>
+    {
+        if !buffer.is_empty() && buffer.len() < self.spec.channels as usize
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer too small for a frame of the WAV file"));
+        }
+        let size = self.spec.sample_bytes();
+        let frame_bytes = size * self.spec.channels as usize;
+        let frames = (buffer.len() / self.spec.channels as usize).min((self.remaining / frame_bytes as u64) as usize);
+        let samples = frames * self.spec.channels as usize;
+
+        self.bytes.resize(frames * frame_bytes, 0);
+        self.reader.read_exact(&mut self.bytes)?;
+        self.remaining -= self.bytes.len() as u64;
+        swap_little_endian(&mut self.bytes, size);
+
+        let output = &mut buffer[.. samples];
+        if <T as SampleType>::sample_format() == self.spec.format
+        {
+            convert::as_bytes_mut(output).copy_from_slice(&self.bytes);
+        }
+        else
+        {
+            let out_format = <T as SampleType>::sample_format();
+            let out_size = convert::format_size(out_format).unwrap();
+            let mut float = [0.0f32; CHUNK_SAMPLES];
+            for (i, o) in self.bytes.chunks(CHUNK_SAMPLES * size).zip(convert::as_bytes_mut(output).chunks_mut(CHUNK_SAMPLES * out_size))
+            {
+                let float = &mut float[.. i.len() / size];
+                convert::decode(self.spec.format, i, float);
+                self.converter.encode(float, out_format, o);
+            }
+        }
+
+        Ok(samples)
+    }
+}
+
+/// Records the input of a callback stream into a WAV file
+///
+/// The stream callback never blocks, allocates or touches the file. It copies the input into a
+/// fixed set of buffers and hands them over a bounded channel to a writer thread, which writes
+/// them to the file. When the writer thread falls behind and no buffer is free, the input is
+/// dropped and counted in dropped_frames(). The writer thread finalizes the file after the
+/// finished callback ran or the stream was closed, and finish() waits for that.
+pub struct Recorder<W: Write + Seek + Send + 'static>
+{
+    writer: Mutex<Option<WavWriter<W>>>,
+    thread: Mutex<Option<JoinHandle<io::Result<()>>>>,
+    state: Arc<RecorderState>,
+}
+
+// Shared between a Recorder, its callbacks and its writer thread
+struct RecorderState
+{
+    finished: AtomicBool,
+    failed: AtomicBool,
+    dropped_frames: AtomicU64,
+}
+
+impl<W: Write + Seek + Send + 'static> Recorder<W>
+{
+    /// Create a recorder writing to the given writer
+    pub fn new(writer: WavWriter<W>) -> Recorder<W>
+    {
+        Recorder
+        {
+            writer: Mutex::new(Some(writer)),
+            thread: Mutex::new(None),
+            state: Arc::new(RecorderState
+            {
+                finished: AtomicBool::new(false),
+                failed: AtomicBool::new(false),
+                dropped_frames: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// A stream callback handing the input to the writer thread, and filling any output with
+    /// silence
+    ///
+    /// This starts the writer thread, so it is called once per recording. The callback aborts
+    /// the stream when writing fails, and completes it once the recording finished.
+    pub fn callback<I, O>(&self) -> Box<StreamCallback<'static, I, O>>
+        where I: SampleType + Copy + Send + 'static, O: SampleType + 'static
+    {
+        let writer = self.writer.lock().unwrap().take();
+        let channels = writer.as_ref().map_or(1, |w| w.spec().channels as usize);
+        let block = CHUNK_SAMPLES * channels;
+        let (full_tx, full_rx) = mpsc::sync_channel::<Vec<I>>(RECORDER_BUFFERS);
+        let (free_tx, free_rx) = mpsc::sync_channel(RECORDER_BUFFERS);
+        for _ in 0 .. RECORDER_BUFFERS
+        {
+            let _ = free_tx.try_send(Vec::with_capacity(block));
+        }
+        // Without a writer the receiver is dropped here, and the callback completes the stream
+        if let Some(writer) = writer
+        {
+            let state = self.state.clone();
+            *self.thread.lock().unwrap() = Some(thread::spawn(move || write_recording(writer, full_rx, free_tx, &state)));
+        }
+
+        let state = self.state.clone();
+        let mut silence = Converter::new(Conversion { dither: Dither::None, clip: true });
+        Box::new(move |input, output, _, _|
+        {
+            for chunk in output.chunks_mut(CHUNK_SAMPLES)
+            {
+                silence.convert(&[0.0f32; CHUNK_SAMPLES][.. chunk.len()], chunk);
+            }
+
+            if state.failed.load(Ordering::Acquire) { return StreamCallbackResult::Abort }
+            if state.finished.load(Ordering::Acquire) { return StreamCallbackResult::Complete }
+            for (i, samples) in input.chunks(block).enumerate()
+            {
+                let mut buffer = match free_rx.try_recv()
+                {
+                    Ok(buffer) => buffer,
+                    Err(_) =>
+                    {
+                        let dropped = (input.len() - i * block) / channels;
+                        state.dropped_frames.fetch_add(dropped as u64, Ordering::Relaxed);
+                        break;
+                    },
+                };
+                // The buffers hold a block, so this does not allocate
+                buffer.extend_from_slice(samples);
+                // There are no more buffers than the channel holds, so it is never full
+                if let Err(TrySendError::Disconnected(_)) = full_tx.try_send(buffer)
+                {
+                    return StreamCallbackResult::Complete;
+                }
+            }
+            StreamCallbackResult::Continue
+        })
+    }
+
+    /// A finished callback for Stream::set_finished_callback(), letting the writer thread
+    /// finalize the file
+    pub fn finished_callback(&self) -> Box<StreamFinishedCallback<'static>>
+    {
+        let state = self.state.clone();
+        Box::new(move || state.finished.store(true, Ordering::Release))
+    }
+
+    /// The number of frames that were dropped because the writer thread fell behind
+    pub fn dropped_frames(&self) -> u64
+    {
+        self.state.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// Finish the recording, and wait until the file is finalized
+    ///
+    /// Returns the error that stopped the writer thread, if any. Samples the callback passes on
+    /// after this are not recorded, so stop the stream first.
+    pub fn finish(&self) -> io::Result<()>
+    {
+        self.state.finished.store(true, Ordering::Release);
+        if let Some(w) = self.writer.lock().unwrap().take()
+        {
+            return w.finalize();
+        }
+        match self.thread.lock().unwrap().take()
+        {
+            Some(thread) => thread.join().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "the WAV writer thread panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+// The writer thread of a Recorder. Ends when the stream finished and the queue is empty, or when
+// the callback is dropped with the stream.
+fn write_recording<W, I>(mut writer: WavWriter<W>, full: Receiver<Vec<I>>, free: SyncSender<Vec<I>>, state: &RecorderState) -> io::Result<()>
+    where W: Write + Seek, I: SampleType
+{
+    loop
+    {
+        let mut samples = match full.recv_timeout(RECORDER_POLL)
+        {
+            Ok(samples) => samples,
+            Err(RecvTimeoutError::Timeout) if state.finished.load(Ordering::Acquire) => match full.try_recv()
+            {
+                Ok(samples) => samples,
+                Err(_) => break,
+            },
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = writer.write_samples(&samples)
+        {
+            state.failed.store(true, Ordering::Release);
+            return Err(e);
+        }
+        samples.clear();
+        let _ = free.try_send(samples);
+    }
+    writer.finalize()
+}
+
+/// A stream callback playing a WAV file, completing the stream at the end of the file
+///
+/// The file needs to have as many channels as the output of the stream. Reading errors abort
+/// the stream.
+pub fn play_callback<R, I, O>(mut reader: WavReader<R>) -> Box<StreamCallback<'static, I, O>>
+    where R: Read + Seek + 'static, I: SampleType + 'static, O: SampleType + 'static
+{
+    let mut silence = Converter::new(Conversion { dither: Dither::None, clip: true });
+    Box::new(move |_, output, _, _|
+    {
+        let result = reader.read_samples(output);
+        let read = *result.as_ref().unwrap_or(&0);
+        for chunk in output[read ..].chunks_mut(CHUNK_SAMPLES)
+        {
+            silence.convert(&[0.0f32; CHUNK_SAMPLES][.. chunk.len()], chunk);
+        }
+
+        match result
+        {
+            Err(_) => StreamCallbackResult::Abort,
+            Ok(read) if read < output.len() => StreamCallbackResult::Complete,
+            Ok(_) => StreamCallbackResult::Continue,
+        }
+    })
+}
+
+/// Read the given number of frames from a blocking input stream into a WAV file
+///
+/// The file needs to have as many channels as the input of the stream. The writer is not
+/// finalized, so more may be recorded.
+pub fn record<I, O, W>(stream: &Stream<I, O>, writer: &mut WavWriter<W>, frames: u64) -> Result<(), Error>
+    where I: SampleType, O: SampleType, W: Write + Seek
+{
+    let mut remaining = frames;
+    while remaining > 0
+    {
+        let count = remaining.min(CHUNK_SAMPLES as u64);
+        writer.write_samples(&stream.read(count as u32)?)?;
+        remaining -= count;
+    }
+    Ok(())
+}
+
+/// Write a WAV file to a blocking output stream, until the end of the file
+///
+/// The file needs to have as many channels as the output of the stream.
+pub fn play<I, O, R>(stream: &Stream<I, O>, reader: &mut WavReader<R>) -> Result<(), Error>
+    where I: SampleType, O: SampleType + Copy + Default, R: Read + Seek
+{
+    let mut buffer = vec![O::default(); CHUNK_SAMPLES * reader.spec().channels as usize];
+    loop
+    {
+        match reader.read_samples(&mut buffer)?
+        {
+            0 => return Ok(()),
+            n => stream.write(&buffer[.. n])?,
+        }
+    }
+}
+
+fn parse_fmt(body: &[u8]) -> io::Result<WavSpec>
+{
+    if body.len() < 16 { return Err(invalid_data("fmt chunk too short")) }
+
+    let mut tag = le_u16(&body[0 .. 2]);
+    let channels = le_u16(&body[2 .. 4]);
+    let sample_rate = le_u32(&body[4 .. 8]);
+    let bits = le_u16(&body[14 .. 16]);
+    if tag == FORMAT_EXTENSIBLE
+    {
+        if body.len() < 40 { return Err(invalid_data("fmt chunk too short")) }
+        tag = le_u16(&body[24 .. 26]);
+    }
+
+    let format = match (tag, bits)
+    {
+        (FORMAT_PCM, 8) => SampleFormat::UINT8,
+        (FORMAT_PCM, 16) => SampleFormat::INT16,
+        (FORMAT_PCM, 24) => SampleFormat::INT24,
+        (FORMAT_PCM, 32) => SampleFormat::INT32,
+        (FORMAT_FLOAT, 32) => SampleFormat::FLOAT32,
+        _ => return Err(invalid_data("unsupported WAV sample format")),
+    };
+    if channels == 0 { return Err(invalid_data("WAV file without channels")) }
+
+    Ok(WavSpec { channels, sample_rate, format })
+}
+
+// WAV files are little endian, the conversions work in native endianness
+#[cfg(target_endian = "little")]
+fn swap_little_endian(_bytes: &mut [u8], _size: usize) {}
+
+#[cfg(target_endian = "big")]
+fn swap_little_endian(bytes: &mut [u8], size: usize)
+{
+    for sample in bytes.chunks_mut(size) { sample.reverse(); }
+}
+
+fn le_u16(b: &[u8]) -> u16 { u16::from_le_bytes([b[0], b[1]]) }
+fn le_u32(b: &[u8]) -> u32 { u32::from_le_bytes([b[0], b[1], b[2], b[3]]) }
+fn le_u64(b: &[u8]) -> u64 { u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) }
+
+fn invalid_data(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::{WavReader, WavSpec, WavWriter};
+    use stream::{SampleFormat, SampleType};
+    use std::io::Cursor;
+
+    fn roundtrip<T: SampleType + Copy + Default>(spec: WavSpec, samples: &[T]) -> (Vec<u8>, Vec<T>)
+    {
+        let mut file = Cursor::new(Vec::new());
+        {
+            let mut writer = WavWriter::new(&mut file, spec).unwrap();
+            writer.write_samples(samples).unwrap();
+            assert_eq!(writer.frames(), (samples.len() / spec.channels as usize) as u64);
+            writer.finalize().unwrap();
+        }
+
+        file.set_position(0);
+        let mut reader = WavReader::new(&mut file).unwrap();
+        assert_eq!(reader.spec(), spec);
+        assert_eq!(reader.frames(), (samples.len() / spec.channels as usize) as u64);
+
+        let mut output = vec![T::default(); samples.len() + 4];
+        let read = reader.read_samples(&mut output).unwrap();
+        assert_eq!(read, samples.len());
+        assert_eq!(reader.read_samples(&mut output).unwrap(), 0);
+        output.truncate(read);
+        (file.into_inner(), output)
+    }
+
+    #[test]
+    fn pcm_roundtrip()
+    {
+        let spec = WavSpec { channels: 2, sample_rate: 44_100, format: SampleFormat::INT16 };
+        let samples = [0i16, 16_384, -16_384, i16::MAX, i16::MIN, 1];
+        let (bytes, output) = roundtrip(spec, &samples);
+        assert_eq!(&bytes[0 .. 4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize, bytes.len() - 8);
+        assert_eq!(output, samples);
+    }
+
+    #[test]
+    fn converted_roundtrip()
+    {
+        let samples = [0.0f32, 0.5, -0.5, -1.0, 0.25, 0.125];
+        for &format in [SampleFormat::FLOAT32, SampleFormat::INT24, SampleFormat::INT32].iter()
+        {
+            let spec = WavSpec { channels: 3, sample_rate: 48_000, format };
+            let (_, output) = roundtrip(spec, &samples);
+            for (a, b) in samples.iter().zip(output.iter())
+            {
+                assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn skips_other_chunks()
+    {
+        let spec = WavSpec { channels: 1, sample_rate: 8000, format: SampleFormat::INT16 };
+        let mut file = Cursor::new(Vec::new());
+        {
+            let mut writer = WavWriter::new(&mut file, spec).unwrap();
+            writer.write_samples::<f32>(&[]).unwrap();
+            writer.write_samples(&[16_384i16, -16_384]).unwrap();
+            writer.finalize().unwrap();
+        }
+
+        // Insert a LIST chunk of odd size, padded to an even size, before the data chunk
+        let mut bytes = file.into_inner();
+        let data = bytes.len() - 12;
+        let list = b"LIST\x03\0\0\0abc\0";
+        bytes.splice(data .. data, list.iter().cloned());
+
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        let mut output = [0i16; 4];
+        assert_eq!(reader.read_samples(&mut output).unwrap(), 2);
+        assert_eq!(reader.read_samples(&mut output).unwrap(), 0);
+        assert_eq!(&output[.. 2], &[16_384, -16_384]);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn recorder()
+    {
+        use super::Recorder;
+        use mock;
+        use stream::{Stream, StreamFlags, StreamParameters};
+        use std::io::{self, Seek, SeekFrom, Write};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // A file the test can read back after the writer thread finalized it
+        #[derive(Clone, Default)]
+        struct SharedFile(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+        impl Write for SharedFile
+        {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().write(buf) }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        impl Seek for SharedFile
+        {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> { self.0.lock().unwrap().seek(pos) }
+        }
+
+        ::initialize().unwrap();
+        mock::push_input(0, &[0.5; 2 * 768]);
+
+        let file = SharedFile::default();
+        let spec = WavSpec { channels: 2, sample_rate: 48_000, format: SampleFormat::INT16 };
+        let recorder = Recorder::new(WavWriter::new(file.clone(), spec).unwrap());
+        let parameters = StreamParameters { device: 0, channel_count: 2, suggested_latency: Duration::from_millis(10), data: 0i16 };
+        let mut stream: Stream<i16, i16> = Stream::open(Some(parameters), None, 48_000.0, 256, StreamFlags::empty(),
+                                                        Some(recorder.callback())).unwrap();
+        stream.set_finished_callback(recorder.finished_callback()).unwrap();
+        stream.start().unwrap();
+        mock::advance(0.016);
+        stream.stop().unwrap();
+        recorder.finish().unwrap();
+        assert_eq!(recorder.dropped_frames(), 0);
+
+        let bytes = file.0.lock().unwrap().get_ref().clone();
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.frames(), 768);
+        let mut output = vec![0i16; 2 * 768];
+        assert_eq!(reader.read_samples(&mut output).unwrap(), 2 * 768);
+        assert!(output.iter().all(|&v| v == 16_384));
+
+        drop(stream);
+        ::terminate().unwrap();
+    }
+
+    #[test]
+    fn rejects_buffers_smaller_than_a_frame()
+    {
+        use super::play_callback;
+        use stream::{StreamCallbackFlags, StreamCallbackResult, StreamTimeInfo};
+        use std::time::Duration;
+
+        let spec = WavSpec { channels: 2, sample_rate: 8000, format: SampleFormat::INT16 };
+        let mut file = Cursor::new(Vec::new());
+        {
+            let mut writer = WavWriter::new(&mut file, spec).unwrap();
+            writer.write_samples(&[1i16, 2, 3, 4]).unwrap();
+            writer.finalize().unwrap();
+        }
+
+        file.set_position(0);
+        let mut reader = WavReader::new(&mut file).unwrap();
+        assert_eq!(reader.read_samples(&mut [0i16; 1]).unwrap_err().kind(), ::std::io::ErrorKind::InvalidInput);
+        assert_eq!(reader.read_samples(&mut [0i16; 3]).unwrap(), 2);
+
+        // A mono stream playing a stereo file fails instead of completing as if at the end
+        file.set_position(0);
+        let time = StreamTimeInfo { input_adc_time: Duration::from_secs(0), current_time: Duration::from_secs(0),
+                                    output_dac_time: Duration::from_secs(0) };
+        let mut play = play_callback::<_, i16, i16>(WavReader::new(file).unwrap());
+        assert!(play(&[], &mut [0i16; 1], time, StreamCallbackFlags::empty()) == StreamCallbackResult::Abort);
+    }
+
+    #[test]
+    fn callbacks_output_digital_silence()
+    {
+        use super::{play_callback, Recorder};
+        use stream::{StreamCallbackFlags, StreamTimeInfo};
+        use std::time::Duration;
+
+        fn check<O: SampleType + Copy + Default + PartialEq + ::std::fmt::Debug + 'static>(zero: O)
+        {
+            let time = StreamTimeInfo { input_adc_time: Duration::from_secs(0), current_time: Duration::from_secs(0),
+                                        output_dac_time: Duration::from_secs(0) };
+            let spec = WavSpec { channels: 1, sample_rate: 8000, format: SampleFormat::INT16 };
+            let mut file = Cursor::new(Vec::new());
+            WavWriter::new(&mut file, spec).unwrap().finalize().unwrap();
+            file.set_position(0);
+
+            let mut output = [O::default(); 64];
+            let mut play = play_callback::<_, i16, O>(WavReader::new(file).unwrap());
+            play(&[], &mut output, time, StreamCallbackFlags::empty());
+            assert!(output.iter().all(|&v| v == zero), "{:?}", &output[..]);
+
+            let mut output = [O::default(); 64];
+            let recorder = Recorder::new(WavWriter::new(Cursor::new(Vec::new()), spec).unwrap());
+            recorder.callback::<i16, O>()(&[0; 64], &mut output, time, StreamCallbackFlags::empty());
+            assert!(output.iter().all(|&v| v == zero), "{:?}", &output[..]);
+            recorder.finish().unwrap();
+        }
+        check(0i16);
+        check(128u8);
+    }
+
+    #[test]
+    fn rejects_other_files()
+    {
+        assert!(WavReader::new(Cursor::new(b"RIFF\0\0\0\0AVI LIST".to_vec())).is_err());
+        let spec = WavSpec { channels: 1, sample_rate: 8000, format: SampleFormat::INT8 };
+        assert!(WavWriter::new(Cursor::new(Vec::new()), spec).is_err());
+    }
+}